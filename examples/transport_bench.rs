@@ -0,0 +1,104 @@
+//! Compare request latency with and without connection reuse.
+//!
+//! Runs a tiny keep-alive HTTP server on 127.0.0.1 and times a batch of GETs
+//! through a fresh `CurlTransport` per request (a new connection every time)
+//! and through a single shared `CurlTransport`.
+//!
+//!   $ cargo run --release --example transport_bench [requests]
+
+extern crate connectr;
+use connectr::http::{AccessToken, CurlTransport, HttpMethod, HttpRequest, HttpTransport};
+
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn serve(stream: TcpStream) {
+    let mut writer = match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    loop {
+        // Read one request's headers; no bodies are sent by the benchmark.
+        let mut got_request = false;
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {},
+            }
+            if line == "\r\n" || line == "\n" {
+                break;
+            }
+            got_request = true;
+        }
+        if !got_request {
+            continue;
+        }
+        let body = "{}";
+        let reply = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                             Content-Length: {}\r\nConnection: keep-alive\r\n\r\n{}",
+                            body.len(), body);
+        if writer.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn start_server(connections: Arc<AtomicUsize>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                connections.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || serve(stream));
+            }
+        }
+    });
+    port
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
+
+fn run<F>(name: &str, count: usize, connections: &Arc<AtomicUsize>, mut request: F)
+    where F: FnMut() {
+    let before = connections.load(Ordering::SeqCst);
+    let start = Instant::now();
+    for _ in 0..count {
+        request();
+    }
+    let elapsed = millis(start.elapsed());
+    // Give the server a moment to count the last accept.
+    thread::sleep(Duration::from_millis(50));
+    let opened = connections.load(Ordering::SeqCst) - before;
+    println!("{:<12} {:>5} requests  {:>9.2} ms total  {:>7.3} ms/request  {:>5} connections",
+             name, count, elapsed, elapsed / count as f64, opened);
+}
+
+fn main() {
+    let count = env::args().nth(1).and_then(|n| n.parse().ok()).unwrap_or(500);
+    let connections = Arc::new(AtomicUsize::new(0));
+    let port = start_server(connections.clone());
+    let url = format!("http://127.0.0.1:{}/v1/me/player/volume", port);
+    let request = HttpRequest::new(&url, "volume_percent=50", "", HttpMethod::GET,
+                                   AccessToken::Bearer("benchmark"));
+
+    run("fresh", count, &connections, || {
+        let response = CurlTransport::new().request(&request);
+        assert_eq!(response.code, Some(200));
+    });
+
+    let shared = CurlTransport::new();
+    run("reused", count, &connections, || {
+        let response = shared.request(&request);
+        assert_eq!(response.code, Some(200));
+    });
+}
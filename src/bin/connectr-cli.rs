@@ -0,0 +1,456 @@
+//! Control Spotify Connect devices from the command line.
+//!
+//!   $ connectr-cli [--json] [--account NAME] [--device NAME] <command> [args]
+//!
+//! Uses the same configuration and credentials as the connectr menu bar app.
+//! If the app is running with `ipc` enabled, commands are sent to it over its
+//! control socket instead, sharing its login and what it knows.
+//! Run without arguments for a list of commands and exit codes.
+
+extern crate connectr;
+use connectr::{ConnectrError, ConnectDevice, PlayerState, SpotifyAccounts, SpotifyRepeat, SpotifyUri};
+use connectr::control::{self, Command, StateCache};
+use connectr::settings;
+#[cfg(unix)]
+use connectr::ipc::{self, IpcClient};
+
+#[macro_use]
+extern crate serde_json;
+use serde_json::Value;
+
+use std::env;
+use std::fmt;
+use std::process;
+
+const USAGE: &'static str = "Usage: connectr-cli [options] <command> [args]
+
+Options:
+  --json             Print results as JSON
+  --account <name>   Use this account from [accounts] instead of the first
+  --device <name>    Send the command to this device instead of the active one
+  --help             Show this help
+
+Commands:
+  devices            List available devices
+  status             Show what is playing
+  play [uri]         Resume, or play a track, album, artist, playlist or show
+  pause              Pause playback
+  next               Skip to the next track
+  prev               Skip to the previous track
+  seek <time>        Seek to a position: 90, 1:30, +10 or -10 (seconds)
+  volume <percent>   Set the volume: 0-100, +10 or -10
+  shuffle <on|off>   Turn shuffle on or off
+  repeat <mode>      Set repeat mode: off, track or context
+  transfer <device>  Move playback to another device
+  preset <name>      Play a preset from connectr.ini
+
+Devices are given by ID or by name, which can be abbreviated.
+
+Exit codes:
+  0  Success
+  1  Spotify rejected the request
+  2  Invalid arguments
+  3  Configuration error
+  4  Not authorized
+  5  Rate limited
+  6  Network error
+  7  No such device, or no active device
+  8  Local file error
+";
+
+enum CliError {
+    Usage(String),
+    Api(ConnectrError),
+}
+
+impl From<ConnectrError> for CliError {
+    fn from(err: ConnectrError) -> CliError {
+        CliError::Api(err)
+    }
+}
+
+impl From<serde_json::Error> for CliError {
+    fn from(err: serde_json::Error) -> CliError {
+        CliError::Api(ConnectrError::from(err))
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CliError::Usage(ref s) => write!(f, "{}", s),
+            CliError::Api(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match *self {
+            CliError::Usage(_) => 2,
+            CliError::Api(ref e) => match *e {
+                ConnectrError::Config(_) => 3,
+                ConnectrError::InvalidUri(_) => 2,
+                ConnectrError::AuthExpired => 4,
+                ConnectrError::Authorization { .. } => 4,
+                ConnectrError::RateLimited(_) => 5,
+                ConnectrError::Transport(_) => 6,
+                ConnectrError::NoDevice(_) => 7,
+                // Spotify says 404 when there's no active device.
+                ConnectrError::Http { code: 404, .. } => 7,
+                ConnectrError::Io(_) => 8,
+                _ => 1,
+            },
+        }
+    }
+}
+
+/// Where commands run: in a running connectr, through its control socket,
+/// or here with our own login.
+enum Session<'a> {
+    #[cfg(unix)]
+    Socket(IpcClient),
+    Direct(SpotifyAccounts<'a>, StateCache),
+}
+
+impl<'a> Session<'a> {
+    fn open(account: Option<&String>) -> Result<Session<'a>, ConnectrError> {
+        let settings = settings::read_settings()?;
+        // The app only serves its active account.
+        #[cfg(unix)]
+        {
+            if settings.ipc && account.is_none() {
+                if let Ok(client) = IpcClient::connect(&ipc::socket_path(&settings)) {
+                    return Ok(Session::Socket(client));
+                }
+            }
+        }
+        let mut accounts = SpotifyAccounts::from_settings(settings)?;
+        match account {
+            Some(name) => accounts.switch(name)?,
+            None => accounts.connect()?,
+        }
+        Ok(Session::Direct(accounts, StateCache::default()))
+    }
+    fn call(&mut self, command: &Command, device: Option<&str>) -> Result<Value, ConnectrError> {
+        match *self {
+            #[cfg(unix)]
+            Session::Socket(ref mut client) => client.call(command, device),
+            Session::Direct(ref mut accounts, ref mut cache) => control::execute(accounts, cache, command, device),
+        }
+    }
+    /// Whether queries can be answered from the app's cache, which it keeps
+    /// up to date on its own.
+    fn cached(&self) -> bool {
+        match *self {
+            #[cfg(unix)]
+            Session::Socket(_) => true,
+            Session::Direct(..) => false,
+        }
+    }
+    fn player_state(&mut self, device: Option<&str>) -> Result<Option<PlayerState>, ConnectrError> {
+        let state = self.call(&Command::PlayerState { refresh: true }, device)?;
+        Ok(serde_json::from_value(state)?)
+    }
+}
+
+struct Options {
+    json: bool,
+    account: Option<String>,
+    device: Option<String>,
+    command: String,
+    args: Vec<String>,
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, CliError> {
+    let mut opts = Options { json: false, account: None, device: None, command: String::new(), args: Vec::new() };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => opts.json = true,
+            "--account" | "--device" => {
+                let value = match args.next() {
+                    Some(value) => value,
+                    None => return Err(CliError::Usage(format!("{} needs a value", arg))),
+                };
+                match arg.as_str() {
+                    "--account" => opts.account = Some(value),
+                    _ => opts.device = Some(value),
+                }
+            },
+            "-h" | "--help" => opts.command = "help".to_string(),
+            // Negative numbers are arguments (seek -10), not options.
+            _ if arg.starts_with("--") => return Err(CliError::Usage(format!("Unknown option {}", arg))),
+            _ if opts.command.is_empty() => opts.command = arg,
+            _ => opts.args.push(arg),
+        }
+    }
+    if opts.command.is_empty() {
+        return Err(CliError::Usage(String::new()));
+    }
+    Ok(opts)
+}
+
+/// The command's only argument, or a usage error.
+fn one_arg<'a>(opts: &'a Options, what: &str) -> Result<&'a str, CliError> {
+    match opts.args.len() {
+        1 => Ok(&opts.args[0]),
+        _ => Err(CliError::Usage(format!("{} needs {}", opts.command, what))),
+    }
+}
+
+fn no_args(opts: &Options) -> Result<(), CliError> {
+    match opts.args.len() {
+        0 => Ok(()),
+        _ => Err(CliError::Usage(format!("{} takes no arguments", opts.command))),
+    }
+}
+
+/// Parse `90`, `1:30` or `1:02:03` as seconds.
+fn parse_time(time: &str) -> Option<u32> {
+    let mut total = 0u32;
+    for part in time.split(':') {
+        total = total.checked_mul(60)?.checked_add(part.parse::<u32>().ok()?)?;
+    }
+    Some(total)
+}
+
+/// Split a leading `+` or `-` off a relative value.
+fn relative(value: &str) -> (Option<i64>, &str) {
+    match value.chars().next() {
+        Some('+') => (Some(1), &value[1..]),
+        Some('-') => (Some(-1), &value[1..]),
+        _ => (None, value),
+    }
+}
+
+fn print_devices(devices: &[ConnectDevice]) {
+    for dev in devices {
+        let volume = match dev.volume_percent {
+            Some(v) => format!("{}%", v),
+            None => "-".to_string(),
+        };
+        println!("{} {:<30} {:<12} {:>4}  {}", match dev.is_active { true => "*", false => " " },
+                 dev.name, dev.device_type, volume, dev.id);
+    }
+}
+
+/// What to do: a command, or a change relative to the current state.
+enum Action {
+    Run(Command),
+    SeekBy(i64),
+    VolumeBy(i64),
+}
+
+/// Parse the command and its arguments.  Catches typos before logging in.
+fn parse_command(opts: &Options) -> Result<Action, CliError> {
+    Ok(Action::Run(match opts.command.as_str() {
+        "devices" => { no_args(opts)?; Command::Devices { refresh: true } },
+        "status" => { no_args(opts)?; Command::PlayerState { refresh: true } },
+        "play" => match opts.args.len() {
+            0 => Command::Play(None),
+            1 => Command::Play(Some(opts.args[0].parse::<SpotifyUri>()?)),
+            _ => return Err(CliError::Usage("play takes at most one URI".to_string())),
+        },
+        "pause" => { no_args(opts)?; Command::Pause },
+        "next" => { no_args(opts)?; Command::SkipNext },
+        "prev" => { no_args(opts)?; Command::SkipPrev },
+        "seek" => {
+            let (sign, time) = relative(one_arg(opts, "a time")?);
+            let secs = match parse_time(time) {
+                Some(secs) => secs as i64,
+                None => return Err(CliError::Usage(format!("Invalid time '{}'", time))),
+            };
+            match sign {
+                Some(sign) => return Ok(Action::SeekBy(sign * secs * 1000)),
+                None => Command::Seek((secs * 1000) as u32),
+            }
+        },
+        "volume" => {
+            let (sign, volume) = relative(one_arg(opts, "a volume")?);
+            let volume = match volume.trim_right_matches('%').parse::<i64>() {
+                Ok(volume) => volume,
+                Err(_) => return Err(CliError::Usage(format!("Invalid volume '{}'", volume))),
+            };
+            match sign {
+                Some(sign) => return Ok(Action::VolumeBy(sign * volume)),
+                None => Command::Volume(std::cmp::min(std::cmp::max(volume, 0), 100) as u32),
+            }
+        },
+        "shuffle" => match one_arg(opts, "on or off")? {
+            "on" | "true" => Command::Shuffle(true),
+            "off" | "false" => Command::Shuffle(false),
+            other => return Err(CliError::Usage(format!("Invalid shuffle state '{}'", other))),
+        },
+        "repeat" => match one_arg(opts, "off, track or context")? {
+            "off" => Command::Repeat(SpotifyRepeat::Off),
+            "track" => Command::Repeat(SpotifyRepeat::Track),
+            "context" => Command::Repeat(SpotifyRepeat::Context),
+            other => return Err(CliError::Usage(format!("Invalid repeat mode '{}'", other))),
+        },
+        "transfer" => Command::SelectDevice(one_arg(opts, "a device")?.to_string()),
+        "preset" => Command::Preset(one_arg(opts, "a preset name")?.to_string()),
+        cmd => return Err(CliError::Usage(format!("Unknown command {}", cmd))),
+    }))
+}
+
+fn run(opts: &Options) -> Result<(), CliError> {
+    if opts.command == "help" {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    let action = parse_command(opts)?;
+    let mut session = Session::open(opts.account.as_ref())?;
+    let device = opts.device.as_ref().map(|d| d.as_str());
+    let command = match action {
+        Action::Run(Command::Devices { .. }) => Command::Devices { refresh: !session.cached() },
+        Action::Run(Command::PlayerState { .. }) => Command::PlayerState { refresh: !session.cached() },
+        Action::Run(command) => command,
+        Action::SeekBy(offset_ms) => {
+            let progress = match session.player_state(device)? {
+                Some(state) => state.progress_ms.unwrap_or(0) as i64,
+                None => return Err(ConnectrError::NoDevice("Nothing is playing.".to_string()).into()),
+            };
+            Command::Seek(std::cmp::max(progress + offset_ms, 0) as u32)
+        },
+        Action::VolumeBy(offset) => {
+            let current = match session.player_state(device)? {
+                Some(state) => state.device.volume_percent.unwrap_or(0) as i64,
+                None => return Err(ConnectrError::NoDevice("No active device.".to_string()).into()),
+            };
+            Command::Volume(std::cmp::min(std::cmp::max(current + offset, 0), 100) as u32)
+        },
+    };
+    let result = session.call(&command, device)?;
+    match (command, opts.json) {
+        (Command::Devices { .. }, true) | (Command::PlayerState { .. }, true) => {
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        },
+        (Command::Devices { .. }, false) => print_devices(&serde_json::from_value::<Vec<ConnectDevice>>(result)?),
+        (Command::PlayerState { .. }, false) => match serde_json::from_value::<Option<PlayerState>>(result)? {
+            Some(state) => print!("{}", state),
+            None => println!("Nothing is playing."),
+        },
+        (_, true) => println!("{}", json!({"ok": true})),
+        (_, false) => {},
+    }
+    Ok(())
+}
+
+fn main() {
+    let opts = match parse_args(env::args().skip(1).collect()) {
+        Ok(opts) => opts,
+        Err(e) => {
+            let msg = e.to_string();
+            if msg.len() > 0 {
+                eprintln!("{}\n", msg);
+            }
+            eprint!("{}", USAGE);
+            process::exit(e.exit_code());
+        },
+    };
+    if let Err(e) = run(&opts) {
+        match opts.json {
+            true => println!("{}", json!({"ok": false, "error": e.to_string(), "exit_code": e.exit_code()})),
+            false => eprintln!("{}", e),
+        }
+        process::exit(e.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(args: &[&str]) -> Result<Options, CliError> {
+        parse_args(args.iter().map(|a| a.to_string()).collect())
+    }
+
+    fn action(args: &[&str]) -> Result<Action, CliError> {
+        parse_command(&opts(args)?)
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = opts(&["--json", "--device", "Kitchen", "seek", "-10"]).ok().unwrap();
+        assert!(parsed.json);
+        assert_eq!(parsed.device, Some("Kitchen".to_string()));
+        assert_eq!(parsed.account, None);
+        assert_eq!(parsed.command, "seek");
+        assert_eq!(parsed.args, vec!["-10".to_string()]);
+        assert_eq!(opts(&["--help"]).ok().unwrap().command, "help");
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        match opts(&["--verbose", "status"]) {
+            Err(CliError::Usage(ref s)) => assert_eq!(s, "Unknown option --verbose"),
+            _ => panic!("expected a usage error"),
+        }
+        match opts(&["status", "--account"]) {
+            Err(CliError::Usage(ref s)) => assert_eq!(s, "--account needs a value"),
+            _ => panic!("expected a usage error"),
+        }
+        assert!(opts(&[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("90"), Some(90));
+        assert_eq!(parse_time("1:30"), Some(90));
+        assert_eq!(parse_time("1:02:03"), Some(3723));
+        assert_eq!(parse_time("1:xx"), None);
+        assert_eq!(parse_time(""), None);
+        assert_eq!(parse_time("99999999999"), None);
+    }
+
+    #[test]
+    fn test_relative() {
+        assert_eq!(relative("+10"), (Some(1), "10"));
+        assert_eq!(relative("-10"), (Some(-1), "10"));
+        assert_eq!(relative("10"), (None, "10"));
+        assert_eq!(relative(""), (None, ""));
+    }
+
+    #[test]
+    fn test_parse_command() {
+        match action(&["seek", "-10"]) {
+            Ok(Action::SeekBy(-10000)) => {},
+            _ => panic!("expected a relative seek"),
+        }
+        match action(&["seek", "1:02:03"]) {
+            Ok(Action::Run(Command::Seek(3723000))) => {},
+            _ => panic!("expected an absolute seek"),
+        }
+        match action(&["volume", "110%"]) {
+            Ok(Action::Run(Command::Volume(100))) => {},
+            _ => panic!("expected the volume to be clamped"),
+        }
+        match action(&["volume", "+5"]) {
+            Ok(Action::VolumeBy(5)) => {},
+            _ => panic!("expected a relative volume"),
+        }
+        match action(&["volume", "loud"]) {
+            Err(CliError::Usage(_)) => {},
+            _ => panic!("expected a usage error"),
+        }
+        match action(&["play", "not a uri"]) {
+            Err(ref e) => assert_eq!(e.exit_code(), 2),
+            _ => panic!("expected an invalid URI"),
+        }
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let code = |e: ConnectrError| CliError::Api(e).exit_code();
+        assert_eq!(CliError::Usage(String::new()).exit_code(), 2);
+        assert_eq!(code(ConnectrError::Http { code: 404, error: None }), 7);
+        assert_eq!(code(ConnectrError::Http { code: 403, error: None }), 1);
+        assert_eq!(code(ConnectrError::NoDevice("none".to_string())), 7);
+        assert_eq!(code(ConnectrError::AuthExpired), 4);
+        assert_eq!(code(ConnectrError::RateLimited(None)), 5);
+        assert_eq!(code(ConnectrError::Transport("down".to_string())), 6);
+        assert_eq!(code(ConnectrError::Config("bad".to_string())), 3);
+        assert_eq!(code(ConnectrError::Io("disk".to_string())), 8);
+    }
+}
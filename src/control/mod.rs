@@ -0,0 +1,489 @@
+#[cfg(test)]
+mod test;
+
+extern crate serde_json;
+use self::serde_json::Value;
+
+use super::{SpotifyAccounts, SpotifyConnectr, ConnectDeviceList, PlayerState, PlaybackItem};
+use super::{PlayContext, SpotifyRepeat, SpotifyUri};
+use super::error::{ConnectrError, SpotifyError};
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// How long other threads wait for the main thread to run their command.
+const COMMAND_TIMEOUT_SECS: u64 = 30;
+
+/// Last known state of the active account, so that queries can be answered
+/// without asking Spotify every time.
+#[derive(Clone, Debug, Default)]
+pub struct StateCache {
+    pub devices: Option<ConnectDeviceList>,
+    /// None if nothing is playing, or if it isn't known yet.
+    pub player_state: Option<PlayerState>,
+}
+
+/// Something to do with the active Spotify account: one of the actions of
+/// the menu, or a query.
+///
+/// Commands are named and take parameters like JSON-RPC methods, e.g.
+/// `volume` with `{"percent": 50}`.  See `parse()` for all of them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// List devices.  Answered from the cache unless `refresh`.
+    Devices { refresh: bool },
+    /// What is playing.  Answered from the cache unless `refresh`.
+    PlayerState { refresh: bool },
+    /// Transfer playback to a device, by ID or name.
+    SelectDevice(String),
+    PlayPause,
+    /// Resume, or play a track, album, artist, playlist or show.
+    Play(Option<SpotifyUri>),
+    Pause,
+    SkipNext,
+    SkipPrev,
+    /// Seek to a position, in milliseconds.
+    Seek(u32),
+    /// Set the volume, in percent.
+    Volume(u32),
+    Shuffle(bool),
+    Repeat(SpotifyRepeat),
+    /// Play a preset from connectr.ini, by name.
+    Preset(String),
+    /// Switch to an account from `[accounts]`.
+    SelectAccount(String),
+    /// Save the current track to the library, or remove it.
+    ToggleLike,
+    AddToPlaylist { playlist: String, uri: SpotifyUri },
+}
+
+/// Names of all commands, as used by `parse()` and `Command::method()`.
+pub const METHODS: &'static [&'static str] = &[
+    "devices", "player_state", "select_device", "play_pause", "play", "pause", "skip_next",
+    "skip_prev", "seek", "volume", "shuffle", "repeat", "preset", "select_account",
+    "toggle_like", "add_to_playlist",
+];
+
+/// A JSON object with the given members.
+pub fn object(members: Vec<(&str, Value)>) -> Value {
+    Value::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn param_str(params: &Value, name: &str) -> Result<String, String> {
+    match params.get(name).and_then(|v| v.as_str()) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(format!("'{}' must be a string", name)),
+    }
+}
+
+fn param_u32(params: &Value, name: &str) -> Result<u32, String> {
+    match params.get(name).and_then(|v| v.as_u64()) {
+        Some(value) if value <= u32::max_value() as u64 => Ok(value as u32),
+        _ => Err(format!("'{}' must be a positive integer", name)),
+    }
+}
+
+fn param_bool(params: &Value, name: &str) -> Result<bool, String> {
+    match params.get(name).and_then(|v| v.as_bool()) {
+        Some(value) => Ok(value),
+        None => Err(format!("'{}' must be true or false", name)),
+    }
+}
+
+impl Command {
+    /// Parse a command from its name and parameters.  Unknown parameters are
+    /// ignored.  The error describes what is wrong with the parameters.
+    pub fn parse(method: &str, params: &Value) -> Result<Command, String> {
+        let refresh = params.get("refresh").and_then(|v| v.as_bool()).unwrap_or(false);
+        Ok(match method {
+            "devices" => Command::Devices { refresh: refresh },
+            "player_state" => Command::PlayerState { refresh: refresh },
+            "select_device" => Command::SelectDevice(param_str(params, "device")?),
+            "play_pause" => Command::PlayPause,
+            "play" => match params.get("uri") {
+                None | Some(&Value::Null) => Command::Play(None),
+                Some(_) => {
+                    let uri = param_str(params, "uri")?;
+                    Command::Play(Some(uri.parse().map_err(|e: ConnectrError| e.to_string())?))
+                },
+            },
+            "pause" => Command::Pause,
+            "skip_next" => Command::SkipNext,
+            "skip_prev" => Command::SkipPrev,
+            "seek" => Command::Seek(param_u32(params, "position_ms")?),
+            "volume" => match param_u32(params, "percent")? {
+                percent @ 0...100 => Command::Volume(percent),
+                _ => return Err("'percent' must be between 0 and 100".to_string()),
+            },
+            "shuffle" => Command::Shuffle(param_bool(params, "state")?),
+            "repeat" => match param_str(params, "state")?.as_str() {
+                "off" => Command::Repeat(SpotifyRepeat::Off),
+                "track" => Command::Repeat(SpotifyRepeat::Track),
+                "context" => Command::Repeat(SpotifyRepeat::Context),
+                _ => return Err("'state' must be off, track or context".to_string()),
+            },
+            "preset" => Command::Preset(param_str(params, "name")?),
+            "select_account" => Command::SelectAccount(param_str(params, "name")?),
+            "toggle_like" => Command::ToggleLike,
+            "add_to_playlist" => {
+                let uri = param_str(params, "uri")?;
+                Command::AddToPlaylist {
+                    playlist: param_str(params, "playlist")?,
+                    uri: uri.parse().map_err(|e: ConnectrError| e.to_string())?,
+                }
+            },
+            _ => return Err(format!("unknown command '{}'", method)),
+        })
+    }
+    pub fn method(&self) -> &'static str {
+        match *self {
+            Command::Devices { .. } => "devices",
+            Command::PlayerState { .. } => "player_state",
+            Command::SelectDevice(_) => "select_device",
+            Command::PlayPause => "play_pause",
+            Command::Play(_) => "play",
+            Command::Pause => "pause",
+            Command::SkipNext => "skip_next",
+            Command::SkipPrev => "skip_prev",
+            Command::Seek(_) => "seek",
+            Command::Volume(_) => "volume",
+            Command::Shuffle(_) => "shuffle",
+            Command::Repeat(_) => "repeat",
+            Command::Preset(_) => "preset",
+            Command::SelectAccount(_) => "select_account",
+            Command::ToggleLike => "toggle_like",
+            Command::AddToPlaylist { .. } => "add_to_playlist",
+        }
+    }
+    /// Parameters of the command, as accepted by `parse()`.
+    pub fn params(&self) -> Value {
+        match *self {
+            Command::Devices { refresh } | Command::PlayerState { refresh } => object(vec![("refresh", refresh.into())]),
+            Command::SelectDevice(ref device) => object(vec![("device", device.as_str().into())]),
+            Command::Play(Some(ref uri)) => object(vec![("uri", uri.to_string().into())]),
+            Command::Seek(position) => object(vec![("position_ms", position.into())]),
+            Command::Volume(percent) => object(vec![("percent", percent.into())]),
+            Command::Shuffle(state) => object(vec![("state", state.into())]),
+            Command::Repeat(state) => object(vec![("state", state.to_string().into())]),
+            Command::Preset(ref name) | Command::SelectAccount(ref name) => object(vec![("name", name.as_str().into())]),
+            Command::AddToPlaylist { ref playlist, ref uri } => {
+                object(vec![("playlist", playlist.as_str().into()), ("uri", uri.to_string().into())])
+            },
+            _ => object(vec![]),
+        }
+    }
+    /// Whether this only asks about state, rather than changing it.
+    pub fn is_query(&self) -> bool {
+        match *self {
+            Command::Devices { .. } | Command::PlayerState { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Play a URI, as a context if it is one.
+pub fn play_uri(spotify: &mut SpotifyConnectr, uri: &SpotifyUri) -> Result<(), ConnectrError> {
+    if !uri.is_playable() {
+        return Err(ConnectrError::InvalidUri(format!("{} can't be played", uri)));
+    }
+    let ctx = match uri.is_context() {
+        true => PlayContext::new().context_uri(uri).offset_position(0).build(),
+        // Single tracks and episodes aren't contexts.
+        false => PlayContext::new().uri(uri).build(),
+    };
+    spotify.play(Some(&ctx))
+}
+
+/// ID of the device matching `name`, refreshing the cached devices if it
+/// isn't found among them.
+fn find_device(spotify: &mut SpotifyConnectr, cache: &mut StateCache, name: &str) -> Result<String, ConnectrError> {
+    if let Some(dev) = cache.devices.as_ref().and_then(|devices| devices.find(name)) {
+        return Ok(dev.id.clone());
+    }
+    let devices = spotify.request_device_list()?;
+    let result = match devices.find(name) {
+        Some(dev) => Ok(dev.id.clone()),
+        None => {
+            let names: Vec<&str> = devices.devices.iter().map(|d| d.name.as_str()).collect();
+            Err(ConnectrError::NoDevice(format!("No single device matches '{}'.  Devices: {}",
+                                                name, names.join(", "))))
+        },
+    };
+    cache.devices = Some(devices);
+    result
+}
+
+/// Run a command against the active account, and return its result as JSON.
+///
+/// Queries return the device list or player state.  `toggle_like` returns
+/// `{"saved": bool}`, and other commands return null.  With `device`, the
+/// command is sent to that device instead of the usual one.
+pub fn execute(accounts: &mut SpotifyAccounts, cache: &mut StateCache, command: &Command,
+               device: Option<&str>) -> Result<Value, ConnectrError> {
+    if let Command::SelectAccount(ref name) = *command {
+        accounts.switch(name)?;
+        // Nothing known about the old account applies to the new one.
+        *cache = StateCache::default();
+        return Ok(Value::Null);
+    }
+    let spotify = accounts.active_mut();
+    let previous = spotify.target_device().map(|d| d.to_string());
+    if let Some(name) = device {
+        let id = find_device(spotify, cache, name)?;
+        spotify.set_target_device(Some(id));
+    }
+    let result = execute_on(spotify, cache, command);
+    // Selecting a device changes the target for good.
+    match *command {
+        Command::SelectDevice(_) => {},
+        _ if device.is_some() => spotify.set_target_device(previous),
+        _ => {},
+    }
+    result
+}
+
+fn execute_on(spotify: &mut SpotifyConnectr, cache: &mut StateCache, command: &Command) -> Result<Value, ConnectrError> {
+    match *command {
+        Command::Devices { refresh } => {
+            if refresh || cache.devices.is_none() {
+                cache.devices = Some(spotify.request_device_list()?);
+            }
+            let devices = cache.devices.as_ref().map(|d| &d.devices);
+            return Ok(serde_json::to_value(&devices)?);
+        },
+        Command::PlayerState { refresh } => {
+            if refresh {
+                cache.player_state = spotify.request_player_state()?;
+            }
+            return Ok(serde_json::to_value(&cache.player_state)?);
+        },
+        Command::SelectDevice(ref name) => {
+            let id = find_device(spotify, cache, name)?;
+            // Spotify is broken.  Must be 'true', always starts playing.
+            spotify.transfer(id, true)?;
+        },
+        Command::PlayPause => {
+            // The fresh state is kept, so callers can tell which way it went.
+            if let Ok(state) = spotify.request_player_state() {
+                cache.player_state = state;
+            }
+            let is_playing = cache.player_state.as_ref().map(|s| s.is_playing).unwrap_or(false);
+            match is_playing {
+                true => spotify.pause()?,
+                false => spotify.play(None)?,
+            }
+        },
+        Command::Play(None) => spotify.play(None)?,
+        Command::Play(Some(ref uri)) => play_uri(spotify, uri)?,
+        Command::Pause => spotify.pause()?,
+        Command::SkipNext => spotify.next()?,
+        Command::SkipPrev => spotify.previous()?,
+        Command::Seek(position) => spotify.seek(position)?,
+        Command::Volume(percent) => spotify.volume(percent)?,
+        Command::Shuffle(state) => spotify.shuffle(state)?,
+        Command::Repeat(state) => spotify.repeat(state)?,
+        Command::Preset(ref name) => {
+            let uri = spotify.get_presets().iter()
+                .find(|&&(ref preset, _)| preset.to_lowercase() == name.to_lowercase())
+                .map(|&(_, ref uri)| uri.clone());
+            match uri {
+                Some(uri) => play_uri(spotify, &uri)?,
+                None => return Err(ConnectrError::Config(format!("no preset named '{}'", name))),
+            }
+        },
+        Command::ToggleLike => {
+            let state = spotify.request_player_state()?;
+            let id = match state.as_ref().and_then(|s| s.item.as_ref()) {
+                Some(&PlaybackItem::Track(ref track)) => match track.id {
+                    Some(ref id) => id.clone(),
+                    None => return Err(ConnectrError::InvalidUri("local tracks can't be liked".to_string())),
+                },
+                Some(_) => return Err(ConnectrError::InvalidUri("only tracks can be liked".to_string())),
+                None => return Err(ConnectrError::NoDevice("Nothing is playing.".to_string())),
+            };
+            cache.player_state = state;
+            let saved = spotify.check_saved_tracks(&[&id])?.first().cloned().unwrap_or(false);
+            match saved {
+                true => spotify.remove_saved_tracks(&[&id])?,
+                false => spotify.save_tracks(&[&id])?,
+            }
+            return Ok(object(vec![("saved", (!saved).into())]));
+        },
+        Command::AddToPlaylist { ref playlist, ref uri } => {
+            spotify.add_to_playlist(playlist, &[uri.clone()], None)?;
+        },
+        Command::SelectAccount(_) => unreachable!(),
+    }
+    Ok(Value::Null)
+}
+
+/// Describe an error so that `decode_error()` can rebuild it on the other side.
+pub fn encode_error(err: &ConnectrError) -> Value {
+    match *err {
+        ConnectrError::Transport(ref s) => object(vec![("kind", "transport".into()), ("message", s.as_str().into())]),
+        ConnectrError::Http { code, ref error } => {
+            let mut members = vec![("kind", "http".into()), ("status", code.into())];
+            if let Some(ref error) = *error {
+                members.push(("message", error.message.as_str().into()));
+                members.push(("reason", error.reason.clone().into()));
+            }
+            object(members)
+        },
+        ConnectrError::AuthExpired => object(vec![("kind", "auth_expired".into())]),
+        ConnectrError::RateLimited(secs) => object(vec![("kind", "rate_limited".into()), ("retry_after", secs.into())]),
+        ConnectrError::Deserialize(ref s) => object(vec![("kind", "deserialize".into()), ("message", s.as_str().into())]),
+        ConnectrError::Config(ref s) => object(vec![("kind", "config".into()), ("message", s.as_str().into())]),
+        ConnectrError::Io(ref s) => object(vec![("kind", "io".into()), ("message", s.as_str().into())]),
+        ConnectrError::InvalidUri(ref s) => object(vec![("kind", "invalid_uri".into()), ("message", s.as_str().into())]),
+        ConnectrError::NoDevice(ref s) => object(vec![("kind", "no_device".into()), ("message", s.as_str().into())]),
+        ConnectrError::Authorization { ref error, ref description } => {
+            object(vec![("kind", "authorization".into()), ("error", error.as_str().into()),
+                        ("description", description.clone().into())])
+        },
+    }
+}
+
+/// Rebuild an error from `encode_error()`.  None if `data` isn't one.
+pub fn decode_error(data: &Value) -> Option<ConnectrError> {
+    let string = |name: &str| data.get(name).and_then(|v| v.as_str()).map(|s| s.to_string());
+    let message = string("message").unwrap_or(String::new());
+    Some(match data.get("kind").and_then(|k| k.as_str())? {
+        "transport" => ConnectrError::Transport(message),
+        "http" => {
+            let code = data.get("status").and_then(|v| v.as_u64())? as u32;
+            let error = match data.get("message") {
+                Some(_) => Some(SpotifyError { status: code, message: message, reason: string("reason") }),
+                None => None,
+            };
+            ConnectrError::Http { code: code, error: error }
+        },
+        "auth_expired" => ConnectrError::AuthExpired,
+        "rate_limited" => ConnectrError::RateLimited(data.get("retry_after").and_then(|v| v.as_u64())),
+        "deserialize" => ConnectrError::Deserialize(message),
+        "config" => ConnectrError::Config(message),
+        "io" => ConnectrError::Io(message),
+        "invalid_uri" => ConnectrError::InvalidUri(message),
+        "no_device" => ConnectrError::NoDevice(message),
+        "authorization" => ConnectrError::Authorization {
+            error: string("error").unwrap_or(String::new()),
+            description: string("description"),
+        },
+        _ => return None,
+    })
+}
+
+/// A command waiting for the main thread, which has the Spotify session.
+struct PendingCommand {
+    command: Command,
+    device: Option<String>,
+    reply: mpsc::Sender<Result<Value, ConnectrError>>,
+    /// When the sender stops waiting.  Later, the command is dropped unrun.
+    deadline: Instant,
+}
+
+struct SharedState {
+    /// Bumped on every update, so waiters can tell whether they missed one.
+    state: Mutex<(u64, StateCache)>,
+    updated: Condvar,
+}
+
+/// Commands from other threads, such as the control socket's clients, run
+/// on the main thread by `process()`.
+pub struct CommandQueue {
+    shared: Arc<SharedState>,
+    sender: mpsc::Sender<PendingCommand>,
+    commands: mpsc::Receiver<PendingCommand>,
+}
+
+impl CommandQueue {
+    pub fn new() -> CommandQueue {
+        let (tx, rx) = mpsc::channel();
+        let shared = SharedState { state: Mutex::new((0, StateCache::default())), updated: Condvar::new() };
+        CommandQueue { shared: Arc::new(shared), sender: tx, commands: rx }
+    }
+    /// Handle for another thread to send commands with.
+    pub fn handle(&self) -> CommandHandle {
+        CommandHandle { shared: self.shared.clone(), sender: self.sender.clone() }
+    }
+    /// Replace the state used to answer queries.
+    pub fn update(&self, cache: StateCache) {
+        let mut state = self.shared.state.lock().unwrap();
+        *state = (state.0 + 1, cache);
+        self.shared.updated.notify_all();
+    }
+    /// Run queued commands against the active account.  Returns true if any
+    /// of them may have changed what is playing.
+    pub fn process(&self, accounts: &mut SpotifyAccounts) -> bool {
+        let mut changed = false;
+        while let Ok(pending) = self.commands.try_recv() {
+            if Instant::now() >= pending.deadline {
+                warn!("Dropping {} command: its client stopped waiting", pending.command.method());
+                continue;
+            }
+            // Don't hold the lock while talking to Spotify.
+            let mut cache = self.shared.state.lock().unwrap().1.clone();
+            let result = execute(accounts, &mut cache, &pending.command,
+                                 pending.device.as_ref().map(|d| d.as_str()));
+            self.update(cache);
+            changed |= result.is_ok() && !pending.command.is_query();
+            let _ = pending.reply.send(result);
+        }
+        changed
+    }
+}
+
+/// Sends commands to a `CommandQueue` from another thread.
+#[derive(Clone)]
+pub struct CommandHandle {
+    shared: Arc<SharedState>,
+    sender: mpsc::Sender<PendingCommand>,
+}
+
+impl CommandHandle {
+    /// Run a command on the main thread, and wait for the result.  Queries
+    /// are answered from the cache when possible.
+    pub fn run(&self, command: &Command, device: Option<String>) -> Result<Value, ConnectrError> {
+        if device.is_none() {
+            if let Some(result) = self.cached(command) {
+                return Ok(result);
+            }
+        }
+        let (reply, result) = mpsc::channel();
+        let timeout = Duration::from_secs(COMMAND_TIMEOUT_SECS);
+        let pending = PendingCommand {
+            command: command.clone(),
+            device: device,
+            reply: reply,
+            deadline: Instant::now() + timeout,
+        };
+        match self.sender.send(pending) {
+            Ok(_) => result.recv_timeout(timeout)
+                .unwrap_or(Err(ConnectrError::Transport("connectr did not answer in time".to_string()))),
+            Err(_) => Err(ConnectrError::Transport("connectr is shutting down".to_string())),
+        }
+    }
+    fn cached(&self, command: &Command) -> Option<Value> {
+        let state = self.shared.state.lock().unwrap();
+        let result = match *command {
+            Command::Devices { refresh: false } => serde_json::to_value(&state.1.devices.as_ref()?.devices),
+            Command::PlayerState { refresh: false } => serde_json::to_value(&state.1.player_state),
+            _ => return None,
+        };
+        result.ok()
+    }
+    /// Current version and state.
+    pub fn state(&self) -> (u64, StateCache) {
+        let state = self.shared.state.lock().unwrap();
+        (state.0, state.1.clone())
+    }
+    /// Wait up to `timeout` for an update after `version`.  Returns the
+    /// current version and state, whether or not it changed.
+    pub fn wait_for_update(&self, version: u64, timeout: Duration) -> (u64, StateCache) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.0 == version {
+            state = self.shared.updated.wait_timeout(state, timeout).unwrap().0;
+        }
+        (state.0, state.1.clone())
+    }
+}
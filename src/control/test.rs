@@ -0,0 +1,182 @@
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+    use super::super::*;
+    use ::http::{ScriptedTransport, HttpMethod};
+    use ::settings::Settings;
+    use ::webapi::fixtures::{self, DEVICES, PLAYER_STATE};
+
+    fn scripted_accounts<'a>() -> (SpotifyAccounts<'a>, ScriptedTransport) {
+        let mut settings = Settings::for_test();
+        settings.presets = vec![("Bakesale".to_string(), "spotify:album:1pzvBxYgT6OVwJLtHkrdQK".parse().unwrap())];
+        fixtures::scripted_accounts(settings)
+    }
+
+    #[test]
+    fn test_command_json() {
+        let commands = vec![
+            Command::Devices { refresh: true },
+            Command::PlayerState { refresh: false },
+            Command::SelectDevice("Kitchen".to_string()),
+            Command::PlayPause,
+            Command::Play(None),
+            Command::Play(Some("spotify:album:1pzvBxYgT6OVwJLtHkrdQK".parse().unwrap())),
+            Command::Pause,
+            Command::SkipNext,
+            Command::SkipPrev,
+            Command::Seek(90000),
+            Command::Volume(55),
+            Command::Shuffle(true),
+            Command::Repeat(SpotifyRepeat::Track),
+            Command::Preset("Bakesale".to_string()),
+            Command::SelectAccount("work".to_string()),
+            Command::ToggleLike,
+            Command::AddToPlaylist { playlist: "p1".to_string(), uri: "spotify:track:6rqhFgbbKwnb9MLmUQDhG6".parse().unwrap() },
+        ];
+        for command in commands {
+            assert!(METHODS.contains(&command.method()));
+            assert_eq!(Command::parse(command.method(), &command.params()), Ok(command.clone()));
+        }
+        let params = |s: &str| serde_json::from_str::<Value>(s).unwrap();
+        assert_eq!(Command::parse("devices", &params("{}")), Ok(Command::Devices { refresh: false }));
+        assert!(Command::parse("volume", &params(r#"{"percent": 101}"#)).is_err());
+        assert!(Command::parse("volume", &params(r#"{"percent": "loud"}"#)).is_err());
+        assert!(Command::parse("seek", &params("{}")).is_err());
+        assert!(Command::parse("repeat", &params(r#"{"state": "forever"}"#)).is_err());
+        assert!(Command::parse("play", &params(r#"{"uri": "not a uri"}"#)).is_err());
+        assert!(Command::parse("rewind", &params("{}")).is_err());
+        assert!(Command::Devices { refresh: false }.is_query());
+        assert!(!Command::Pause.is_query());
+    }
+
+    #[test]
+    fn test_execute_queries() {
+        let (mut accounts, transport) = scripted_accounts();
+        let mut cache = StateCache::default();
+
+        // Devices are fetched once, then answered from the cache.
+        transport.push_response(200, DEVICES);
+        let devices = execute(&mut accounts, &mut cache, &Command::Devices { refresh: false }, None).unwrap();
+        assert_eq!(devices[1]["name"], "Office Computer");
+        let again = execute(&mut accounts, &mut cache, &Command::Devices { refresh: false }, None).unwrap();
+        assert_eq!(again, devices);
+        assert_eq!(transport.requests().len(), 1);
+
+        // Player state is whatever was cached, unless refreshed.
+        let state = execute(&mut accounts, &mut cache, &Command::PlayerState { refresh: false }, None).unwrap();
+        assert_eq!(state, Value::Null);
+        transport.push_response(200, PLAYER_STATE);
+        let state = execute(&mut accounts, &mut cache, &Command::PlayerState { refresh: true }, None).unwrap();
+        assert_eq!(state["item"]["name"], "Magnet's Coil");
+        assert!(cache.player_state.is_some());
+    }
+
+    #[test]
+    fn test_execute_on_device() {
+        let (mut accounts, transport) = scripted_accounts();
+        let mut cache = StateCache::default();
+
+        // A device for one command doesn't change the target.
+        transport.push_response(200, DEVICES);
+        transport.push_response(204, "");
+        assert_eq!(execute(&mut accounts, &mut cache, &Command::Volume(30), Some("office")).unwrap(), Value::Null);
+        let requests = transport.requests();
+        assert!(requests[1].query.contains("volume_percent=30"));
+        assert!(requests[1].query.contains("device_id=def456"));
+        assert_eq!(accounts.active().target_device(), None);
+
+        // Selecting a device does, using the cached list.
+        transport.push_response(204, "");
+        assert!(execute(&mut accounts, &mut cache, &Command::SelectDevice("Kitchen".to_string()), None).is_ok());
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].method, HttpMethod::PUT);
+        assert!(requests[2].body.contains("abc123"));
+        assert_eq!(accounts.active().target_device(), Some("abc123"));
+
+        // Unknown devices are looked up again before giving up.
+        transport.push_response(200, DEVICES);
+        match execute(&mut accounts, &mut cache, &Command::Pause, Some("Garage")) {
+            Err(ConnectrError::NoDevice(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(transport.requests().len(), 4);
+        assert_eq!(accounts.active().target_device(), Some("abc123"));
+    }
+
+    #[test]
+    fn test_execute_commands() {
+        let (mut accounts, transport) = scripted_accounts();
+        let mut cache = StateCache::default();
+
+        transport.push_response(200, PLAYER_STATE);
+        transport.push_response(200, "[false]");
+        transport.push_response(200, "");
+        let saved = execute(&mut accounts, &mut cache, &Command::ToggleLike, None).unwrap();
+        assert_eq!(saved["saved"], true);
+        let requests = transport.requests();
+        assert_eq!(requests[2].method, HttpMethod::PUT);
+        assert!(requests[2].query.contains("6rqhFgbbKwnb9MLmUQDhG6"));
+
+        // Presets are played as contexts.
+        transport.push_response(204, "");
+        assert!(execute(&mut accounts, &mut cache, &Command::Preset("bakesale".to_string()), None).is_ok());
+        assert!(transport.requests()[3].body.contains("\"context_uri\":\"spotify:album:1pzvBxYgT6OVwJLtHkrdQK\""));
+        match execute(&mut accounts, &mut cache, &Command::Preset("Pinkerton".to_string()), None) {
+            Err(ConnectrError::Config(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Play/pause asks what is playing first.
+        transport.push_response(200, PLAYER_STATE);
+        transport.push_response(204, "");
+        assert!(execute(&mut accounts, &mut cache, &Command::PlayPause, None).is_ok());
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 6);
+        assert!(requests[5].url.ends_with("/pause"));
+
+        assert!(execute(&mut accounts, &mut cache, &Command::SelectAccount("work".to_string()), None).is_err());
+
+        // Profiles aren't sent to Spotify to play.
+        let user = Command::Play(Some("spotify:user:sebadoh".parse().unwrap()));
+        match execute(&mut accounts, &mut cache, &user, None) {
+            Err(ConnectrError::InvalidUri(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(transport.requests().len(), 6);
+    }
+
+    #[test]
+    fn test_expired_commands() {
+        let (mut accounts, transport) = scripted_accounts();
+        let queue = CommandQueue::new();
+        let (reply, result) = mpsc::channel();
+        let pending = PendingCommand { command: Command::SkipNext, device: None, reply: reply, deadline: Instant::now() };
+        queue.sender.send(pending).unwrap();
+        // Nobody is waiting for it any more, so it isn't run.
+        assert!(!queue.process(&mut accounts));
+        assert!(transport.requests().is_empty());
+        assert!(result.recv().is_err());
+    }
+
+    #[test]
+    fn test_error_encoding() {
+        let errors = vec![
+            ConnectrError::Transport("refused".to_string()),
+            ConnectrError::Http { code: 500, error: None },
+            ConnectrError::AuthExpired,
+            ConnectrError::RateLimited(None),
+            ConnectrError::Deserialize("eof".to_string()),
+            ConnectrError::Config("no client_id".to_string()),
+            ConnectrError::Io("disk full".to_string()),
+            ConnectrError::InvalidUri("spotify:bogus".to_string()),
+            ConnectrError::NoDevice("No active device.".to_string()),
+            ConnectrError::Authorization { error: "access_denied".to_string(), description: None },
+        ];
+        for err in errors {
+            let decoded = decode_error(&encode_error(&err)).unwrap();
+            assert_eq!(decoded.to_string(), err.to_string());
+        }
+        assert!(decode_error(&Value::Null).is_none());
+    }
+}
@@ -0,0 +1,159 @@
+#[cfg(test)]
+mod test;
+
+use std::fmt;
+use std::error::Error;
+
+extern crate serde_json;
+use self::serde_json::Value;
+
+use super::http::HttpResponse;
+
+/// Error body returned by Spotify alongside a non-2xx status.
+///
+/// The Web API wraps errors as `{"error": {"status": 404, "message": "..."}}`,
+/// with player endpoints sometimes adding a `reason`.  The accounts service
+/// uses the OAuth style `{"error": "invalid_grant", "error_description": "..."}`
+/// instead.  Both are parsed into this struct.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpotifyError {
+    pub status: u32,
+    pub message: String,
+    pub reason: Option<String>,
+}
+
+impl SpotifyError {
+    pub fn parse(code: u32, body: &str) -> Option<SpotifyError> {
+        let json: Value = match serde_json::from_str(body) {
+            Ok(j) => j,
+            Err(_) => return None,
+        };
+        match json.get("error") {
+            Some(&Value::Object(ref obj)) => {
+                Some(SpotifyError {
+                    status: obj.get("status").and_then(|s| s.as_u64()).map(|s| s as u32).unwrap_or(code),
+                    message: obj.get("message").and_then(|s| s.as_str()).unwrap_or("").to_string(),
+                    reason: obj.get("reason").and_then(|s| s.as_str()).map(|s| s.to_string()),
+                })
+            },
+            Some(&Value::String(ref error)) => {
+                let message = match json.get("error_description").and_then(|s| s.as_str()) {
+                    Some(desc) => desc.to_string(),
+                    None => error.clone(),
+                };
+                Some(SpotifyError {
+                    status: code,
+                    message: message,
+                    reason: Some(error.clone()),
+                })
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SpotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            Some(ref reason) => write!(f, "{} ({})", self.message, reason),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectrError {
+    /// Request never completed: connection refused, DNS failure, TLS error, etc.
+    Transport(String),
+    /// Spotify answered with a non-2xx status not covered by a more specific variant.
+    Http { code: u32, error: Option<SpotifyError> },
+    /// Access token rejected (401).  Credentials must be refreshed.
+    AuthExpired,
+    /// Too many requests (429).  Contains the server's Retry-After, in seconds, if known.
+    RateLimited(Option<u64>),
+    /// Response body was not the JSON we expected.
+    Deserialize(String),
+    /// Missing or invalid configuration.
+    Config(String),
+    /// Reading or writing a local file failed, e.g. saved tokens or the
+    /// album art cache.
+    Io(String),
+    /// Not a Spotify URI, link or ID of the expected kind.
+    InvalidUri(String),
+    /// No device matches the requested name, or nothing is playing.
+    NoDevice(String),
+    /// OAuth login did not complete: the user declined (`access_denied`),
+    /// the browser never came back, etc.
+    Authorization { error: String, description: Option<String> },
+}
+
+impl ConnectrError {
+    /// Classify a failed HTTP response.  Should not be called with 2xx responses.
+    pub fn from_response(response: &HttpResponse) -> ConnectrError {
+        let code = match response.code {
+            Some(code) => code,
+            None => {
+                return match response.data {
+                    Err(ref s) => ConnectrError::Transport(s.clone()),
+                    Ok(_) => ConnectrError::Transport("no response code".to_string()),
+                }
+            }
+        };
+        match code {
+            401 => ConnectrError::AuthExpired,
+            429 => ConnectrError::RateLimited(response.retry_after()),
+            _ => {
+                let error = match response.data {
+                    Ok(ref body) => SpotifyError::parse(code, body),
+                    Err(_) => None,
+                };
+                ConnectrError::Http { code: code, error: error }
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConnectrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectrError::Transport(ref s) => write!(f, "Transport error: {}", s),
+            ConnectrError::Http { code, error: Some(ref e) } => write!(f, "HTTP {}: {}", code, e),
+            ConnectrError::Http { code, error: None } => write!(f, "HTTP {}", code),
+            ConnectrError::AuthExpired => write!(f, "Access token expired or invalid"),
+            ConnectrError::RateLimited(Some(secs)) => write!(f, "Rate limited, retry after {}s", secs),
+            ConnectrError::RateLimited(None) => write!(f, "Rate limited"),
+            ConnectrError::Deserialize(ref s) => write!(f, "Invalid response: {}", s),
+            ConnectrError::Config(ref s) => write!(f, "Configuration error: {}", s),
+            ConnectrError::Io(ref s) => write!(f, "I/O error: {}", s),
+            ConnectrError::InvalidUri(ref s) => write!(f, "Invalid Spotify URI: {}", s),
+            ConnectrError::NoDevice(ref s) => write!(f, "{}", s),
+            ConnectrError::Authorization { ref error, description: Some(ref d) } =>
+                write!(f, "Authorization failed: {} ({})", error, d),
+            ConnectrError::Authorization { ref error, description: None } =>
+                write!(f, "Authorization failed: {}", error),
+        }
+    }
+}
+
+impl Error for ConnectrError {
+    fn description(&self) -> &str {
+        match *self {
+            ConnectrError::Transport(_) => "transport error",
+            ConnectrError::Http { .. } => "HTTP error",
+            ConnectrError::AuthExpired => "access token expired",
+            ConnectrError::RateLimited(_) => "rate limited",
+            ConnectrError::Deserialize(_) => "invalid response",
+            ConnectrError::Config(_) => "configuration error",
+            ConnectrError::Io(_) => "I/O error",
+            ConnectrError::InvalidUri(_) => "invalid Spotify URI",
+            ConnectrError::NoDevice(_) => "no such device",
+            ConnectrError::Authorization { .. } => "authorization failed",
+        }
+    }
+}
+
+impl From<serde_json::Error> for ConnectrError {
+    fn from(err: serde_json::Error) -> ConnectrError {
+        ConnectrError::Deserialize(err.to_string())
+    }
+}
@@ -0,0 +1,86 @@
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use std::collections::BTreeMap;
+
+    fn response(code: Option<u32>, headers: &[(&str, &str)], body: &str) -> HttpResponse {
+        let mut map = BTreeMap::new();
+        for &(name, value) in headers {
+            map.insert(name.to_lowercase(), value.to_string());
+        }
+        HttpResponse {
+            code: code,
+            data: Ok(body.to_string()),
+            headers: map,
+            bytes: body.as_bytes().to_vec(),
+            unsent: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_web_api_error() {
+        let body = r#"{"error": {"status": 404, "message": "Player command failed", "reason": "NO_ACTIVE_DEVICE"}}"#;
+        assert_eq!(SpotifyError::parse(400, body), Some(SpotifyError {
+            status: 404,
+            message: "Player command failed".to_string(),
+            reason: Some("NO_ACTIVE_DEVICE".to_string()),
+        }));
+        // Status falls back to the HTTP code.
+        let error = SpotifyError::parse(403, r#"{"error": {"message": "Forbidden"}}"#).unwrap();
+        assert_eq!(error.status, 403);
+        assert_eq!(error.reason, None);
+        assert_eq!(error.to_string(), "Forbidden");
+    }
+
+    #[test]
+    fn test_parse_oauth_error() {
+        let body = r#"{"error": "invalid_grant", "error_description": "Refresh token revoked"}"#;
+        let error = SpotifyError::parse(400, body).unwrap();
+        assert_eq!(error, SpotifyError {
+            status: 400,
+            message: "Refresh token revoked".to_string(),
+            reason: Some("invalid_grant".to_string()),
+        });
+        assert_eq!(error.to_string(), "Refresh token revoked (invalid_grant)");
+        assert_eq!(SpotifyError::parse(400, r#"{"error": "invalid_client"}"#).unwrap().message,
+                   "invalid_client");
+    }
+
+    #[test]
+    fn test_parse_not_an_error() {
+        assert_eq!(SpotifyError::parse(502, "<html>Bad Gateway</html>"), None);
+        assert_eq!(SpotifyError::parse(500, ""), None);
+        assert_eq!(SpotifyError::parse(500, r#"{"message": "no error key"}"#), None);
+    }
+
+    #[test]
+    fn test_from_response() {
+        match ConnectrError::from_response(&response(Some(401), &[], "")) {
+            ConnectrError::AuthExpired => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        match ConnectrError::from_response(&response(Some(429), &[("Retry-After", "7")], "")) {
+            ConnectrError::RateLimited(Some(7)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        match ConnectrError::from_response(&response(Some(429), &[], "")) {
+            ConnectrError::RateLimited(None) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        let body = r#"{"error": {"status": 404, "message": "Device not found"}}"#;
+        match ConnectrError::from_response(&response(Some(404), &[], body)) {
+            ConnectrError::Http { code: 404, error: Some(ref e) } => assert_eq!(e.message, "Device not found"),
+            other => panic!("unexpected {:?}", other),
+        }
+        match ConnectrError::from_response(&response(Some(502), &[], "<html></html>")) {
+            ConnectrError::Http { code: 502, error: None } => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut failed = response(None, &[], "");
+        failed.data = Err("Couldn't connect to server".to_string());
+        match ConnectrError::from_response(&failed) {
+            ConnectrError::Transport(ref s) => assert_eq!(s, "Couldn't connect to server"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Files connectr keeps for itself: saved tokens and the album art cache.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::error::ConnectrError;
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().write(true).create(true).truncate(true).open(path)
+}
+
+pub fn io_error(path: &Path, action: &str, err: io::Error) -> ConnectrError {
+    ConnectrError::Io(format!("cannot {} {}: {}", action, path.display(), err))
+}
+
+/// `path` with `.tmp` appended, where `write_atomic()` writes first.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Write `data` to a temporary file alongside `path` and rename it into
+/// place, so a crash never leaves behind a half-written file.  The file is
+/// created readable only by the current user.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), ConnectrError> {
+    let temp = temp_path(path);
+    {
+        // Created 0600 up front; the file is never readable by others.
+        let mut file = match create_private(&temp) {
+            Ok(f) => f,
+            Err(e) => return Err(io_error(&temp, "create", e)),
+        };
+        if let Err(e) = file.write_all(data).and_then(|_| file.sync_all()) {
+            let _ = fs::remove_file(&temp);
+            return Err(io_error(&temp, "write", e));
+        }
+    }
+    if let Err(e) = fs::rename(&temp, path) {
+        let _ = fs::remove_file(&temp);
+        return Err(io_error(path, "replace", e));
+    }
+    Ok(())
+}
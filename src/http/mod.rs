@@ -1,36 +1,79 @@
+#[cfg(test)]
+mod test;
+
+mod pkce;
+pub use self::pkce::{PkceChallenge, random_token};
+
 use std::fmt;
 use std::error::Error;
 use std::str;
-use std::io::{Read, Write, BufReader, BufRead};
-use std::net::{TcpListener};
-
-extern crate regex;
-use self::regex::Regex;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+use std::thread;
+use std::io::{self, Read, Write, BufReader, BufRead};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
 
 extern crate curl;
 use self::curl::easy::{Easy, List};
 
 extern crate open;
+extern crate time;
 extern crate url;
 use self::url::percent_encoding;
+use self::url::form_urlencoded;
 
 use super::settings;
+use super::error::ConnectrError;
 
-#[derive(PartialEq)]
+// How long to wait for the browser to come back from the Spotify login page.
+const OAUTH_TIMEOUT_SECS: u64 = 300;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum HttpMethod {
     GET,
     POST,
     PUT,
+    DELETE,
+    PATCH,
+}
+
+impl HttpMethod {
+    /// Whether sending the request twice has the same effect as once.
+    pub fn is_idempotent(&self) -> bool {
+        match *self {
+            HttpMethod::POST | HttpMethod::PATCH => false,
+            _ => true,
+        }
+    }
 }
 
 pub type HttpErrorString = String;
 pub struct HttpResponse {
     pub code: Option<u32>,
     pub data: Result<String, HttpErrorString>,
+    /// Response headers, keyed by lowercase header name.
+    pub headers: BTreeMap<String, String>,
+    /// Undecoded response body.  `data` is this as UTF-8, so binary bodies
+    /// (images) are only found here.
+    pub bytes: Vec<u8>,
+    /// The request failed before anything was sent, e.g. the host couldn't
+    /// be resolved or connected to.
+    pub unsent: bool,
 }
 
 impl HttpResponse {
     pub fn unwrap(self) -> String { self.data.unwrap() }
+    /// Value of a response header.  Names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|v| v.as_str())
+    }
+    /// Value of the Retry-After header, in seconds, if present and numeric.
+    pub fn retry_after(&self) -> Option<u64> {
+        self.header("retry-after").and_then(|v| v.trim().parse().ok())
+    }
     pub fn print(&self) {
         let code: i32 = match self.code {
             Some(x) => { x as i32 }
@@ -64,135 +107,630 @@ pub enum AccessToken<'a> {
     None,
 }
 
-pub fn http(url: &str, query: &str, body: &str,
-            method: HttpMethod, access_token: AccessToken) -> HttpResponse {
-    let enc_query = percent_encoding::utf8_percent_encode(&query, percent_encoding::QUERY_ENCODE_SET).collect::<String>();
-    let mut data = match method {
-        HttpMethod::POST => { enc_query.as_bytes() },
-        _ => { body.as_bytes() },
-        //_ => { query.as_bytes() }
-    };
-    let query_url = &format!("{}?{}", url, query);
-    let url = match method {
-        HttpMethod::GET | HttpMethod::PUT => match query.len() {
-            0 => url,
-            _ => query_url,
-        },
-        _ => url
+/// A single HTTP request, as handed to an `HttpTransport`.
+///
+/// `query` is a `key=value&...` string, with any `&`, `=` or `%` inside the
+/// values already percent-encoded.  It is appended to the URL, unless `form`
+/// is set, in which case it is sent as the form-encoded body.  `new` sets
+/// `form` for a POST with an empty `body`.
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub url: String,
+    pub query: String,
+    pub body: String,
+    pub method: HttpMethod,
+    pub authorization: Option<String>,
+    /// Extra request headers, sent in order after Authorization.
+    pub headers: Vec<(String, String)>,
+    pub form: bool,
+}
 
-    };
-    let mut response = None;
-    let mut json_bytes = Vec::<u8>::new();
-    {
-        let mut easy = Easy::new();
-        easy.url(url).unwrap();
-        match method {
-            HttpMethod::POST => {
-                easy.post(true).unwrap();
-                easy.post_field_size(data.len() as u64).unwrap();
-            }
-            HttpMethod::PUT => {
-                easy.put(true).unwrap();
-                easy.post_field_size(data.len() as u64).unwrap();
-            }
-            _ => {}
+impl HttpRequest {
+    pub fn new(url: &str, query: &str, body: &str,
+               method: HttpMethod, access_token: AccessToken) -> HttpRequest {
+        let authorization = match access_token {
+            AccessToken::Bearer(token) => Some(format!("Bearer {}", token)),
+            AccessToken::Basic(token) => Some(format!("Basic {}", token)),
+            AccessToken::None => None,
+        };
+        HttpRequest {
+            url: url.to_string(),
+            query: query.to_string(),
+            body: body.to_string(),
+            method: method,
+            authorization: authorization,
+            headers: Vec::new(),
+            form: method == HttpMethod::POST && body.len() == 0,
         }
+    }
+    /// Send `query` in the URL even if this is a POST without a body.
+    pub fn query_in_url(mut self) -> HttpRequest {
+        self.form = false;
+        self
+    }
+    /// Add an arbitrary request header.
+    pub fn header(mut self, name: &str, value: &str) -> HttpRequest {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
 
-        match access_token {
-            AccessToken::None => {},
-            access_token => {
-                let request = match access_token {
-                    AccessToken::Bearer(token) => ("Bearer", token),
-                    AccessToken::Basic(token) => ("Basic", token),
-                    _ => ("",""),
-                };
+/// Something that can execute HTTP requests.
+///
+/// `SpotifyConnectr` sends all of its traffic through one of these.  The
+/// default is `CurlTransport`.  Applications that already own an HTTP client
+/// can implement this to route connectr's requests through it, and tests can
+/// use `ScriptedTransport` to avoid the network entirely.
+pub trait HttpTransport {
+    fn request(&self, request: &HttpRequest) -> HttpResponse;
+}
+
+/// Default transport, backed by libcurl.
+///
+/// Holds a single curl handle for its whole lifetime.  curl keeps finished
+/// connections open in the handle's connection cache, so consecutive requests
+/// to api.spotify.com skip the TCP and TLS handshakes.
+pub struct CurlTransport {
+    easy: RefCell<Easy>,
+}
+
+impl CurlTransport {
+    pub fn new() -> CurlTransport { CurlTransport { easy: RefCell::new(Easy::new()) } }
+}
+
+impl HttpTransport for CurlTransport {
+    fn request(&self, request: &HttpRequest) -> HttpResponse {
+        let method = request.method;
+        let query = &request.query;
+        let enc_query = percent_encoding::utf8_percent_encode(&query, percent_encoding::QUERY_ENCODE_SET).collect::<String>();
+        let form_post = request.form;
+        let mut data = match form_post {
+            true => { enc_query.as_bytes() },
+            false => { request.body.as_bytes() },
+        };
+        let query_url = &format!("{}?{}", request.url, query);
+        let url = match form_post {
+            true => &request.url,
+            false => match query.len() {
+                0 => &request.url,
+                _ => query_url,
+            },
+        };
+        let mut response = None;
+        let mut json_bytes = Vec::<u8>::new();
+        let mut headers = BTreeMap::<String, String>::new();
+        {
+            let mut easy = self.easy.borrow_mut();
+            // Clears options from the previous request, but keeps the
+            // connection cache.
+            easy.reset();
+            easy.url(url).unwrap();
+            match method {
+                HttpMethod::POST => {
+                    easy.post(true).unwrap();
+                    easy.post_field_size(data.len() as u64).unwrap();
+                }
+                HttpMethod::PUT => {
+                    // Newer libcurls turn a PUT with a post field size into
+                    // a POST, so give the upload size instead.
+                    easy.upload(true).unwrap();
+                    easy.in_filesize(data.len() as u64).unwrap();
+                }
+                HttpMethod::DELETE | HttpMethod::PATCH => {
+                    let verb = match method {
+                        HttpMethod::DELETE => "DELETE",
+                        _ => "PATCH",
+                    };
+                    easy.custom_request(verb).unwrap();
+                    if data.len() > 0 {
+                        easy.post_fields_copy(data).unwrap();
+                    }
+                }
+                HttpMethod::GET => {}
+            }
+
+            if request.authorization.is_some() || request.headers.len() > 0 || method == HttpMethod::PUT {
                 let mut list = List::new();
-                let header = format!("Authorization: {} {}", request.0, request.1);
-                list.append(&header).unwrap();
+                if let Some(ref authorization) = request.authorization {
+                    list.append(&format!("Authorization: {}", authorization)).unwrap();
+                }
+                // Uploads would otherwise wait for a 100 Continue first.
+                if method == HttpMethod::PUT {
+                    list.append("Expect:").unwrap();
+                }
+                for &(ref name, ref value) in &request.headers {
+                    list.append(&format!("{}: {}", name, value)).unwrap();
+                }
                 easy.http_headers(list).unwrap();
             }
-        }
 
-        {
-            let mut transfer = easy.transfer();
-            if method == HttpMethod::POST || method == HttpMethod::PUT {
-                transfer.read_function(|buf| {
-                    Ok(data.read(buf).unwrap_or(0))
+            {
+                let mut transfer = easy.transfer();
+                if method == HttpMethod::POST || method == HttpMethod::PUT {
+                    transfer.read_function(|buf| {
+                        Ok(data.read(buf).unwrap_or(0))
+                    }).unwrap();
+                }
+                transfer.write_function(|x| {
+                    json_bytes.extend(x);
+                    Ok(x.len())
+                }).unwrap();
+                transfer.header_function(|x| {
+                    let line = String::from_utf8_lossy(x);
+                    // A new status line means a new response (redirect or
+                    // 100-continue).  Only keep headers from the last one.
+                    if line.starts_with("HTTP/") {
+                        headers.clear();
+                    }
+                    else if let Some(idx) = line.find(':') {
+                        let (name, value) = line.split_at(idx);
+                        headers.insert(name.trim().to_lowercase(), value[1..].trim().to_string());
+                    }
+                    true
                 }).unwrap();
+                match transfer.perform() {
+                    Err(x) => {
+                        let result: Result<String,String> = Err(x.description().to_string());
+                        #[cfg(feature = "verbose_http")]
+                        println!("HTTP response: err: {}", x.description().to_string());
+                        let unsent = x.is_couldnt_resolve_proxy() || x.is_couldnt_resolve_host() ||
+                            x.is_couldnt_connect();
+                        return HttpResponse {code: response, data: result, headers: BTreeMap::new(),
+                                             bytes: Vec::new(), unsent: unsent }
+                    }
+                    _ => {}
+                };
             }
-            transfer.write_function(|x| {
-                json_bytes.extend(x);
-                Ok(x.len())
-            }).unwrap();
-            match transfer.perform() {
-                Err(x) => {
-                    let result: Result<String,String> = Err(x.description().to_string());
-                    #[cfg(feature = "verbose_http")]
-                    println!("HTTP response: err: {}", x.description().to_string());
-                    return HttpResponse {code: response, data: result }
-                }
-                _ => {}
+            response = match easy.response_code() {
+                Ok(code) => { Some(code) }
+                _ => { None }
             };
         }
-        response = match easy.response_code() {
-            Ok(code) => { Some(code) }
-            _ => { None }
+        let result: Result<String,String> = match str::from_utf8(&json_bytes) {
+            Ok(x) => { Ok(x.to_string()) }
+            Err(x) => { Err(x.description().to_string()) }
         };
+        #[cfg(feature = "verbose_http")]
+        match result {
+            Ok(ref body) => println!("HTTP response: {}", body),
+            // Images and other binary bodies aren't worth printing.
+            Err(_) => println!("HTTP response: <{} bytes>", json_bytes.len()),
+        }
+        HttpResponse {code: response, data: result, headers: headers, bytes: json_bytes, unsent: false }
+    }
+}
+
+struct Script {
+    responses: VecDeque<HttpResponse>,
+    requests: Vec<HttpRequest>,
+}
+
+/// In-memory transport that replays queued responses in order.
+///
+/// Every request received is recorded, and can be inspected with
+/// `requests()`.  Clones share the same queue and log, so keep a clone around
+/// after handing one to `SpotifyConnectr::with_transport()`.
+#[derive(Clone)]
+pub struct ScriptedTransport {
+    script: Rc<RefCell<Script>>,
+}
+
+impl ScriptedTransport {
+    pub fn new() -> ScriptedTransport {
+        ScriptedTransport {
+            script: Rc::new(RefCell::new(Script {
+                responses: VecDeque::new(),
+                requests: Vec::new(),
+            })),
+        }
+    }
+    /// Queue a response with the given status code and body.
+    pub fn push_response(&self, code: u32, body: &str) -> &ScriptedTransport {
+        self.push_response_with_headers(code, &[], body)
+    }
+    /// Queue a response with the given status code, headers and body.
+    pub fn push_response_with_headers(&self, code: u32, headers: &[(&str, &str)],
+                                      body: &str) -> &ScriptedTransport {
+        let mut map = BTreeMap::new();
+        for &(name, value) in headers {
+            map.insert(name.to_lowercase(), value.to_string());
+        }
+        self.script.borrow_mut().responses.push_back(HttpResponse {
+            code: Some(code),
+            data: Ok(body.to_string()),
+            headers: map,
+            bytes: body.as_bytes().to_vec(),
+            unsent: false,
+        });
+        self
+    }
+    /// Queue a response with a binary body, such as an image.
+    pub fn push_bytes(&self, code: u32, content_type: &str, body: &[u8]) -> &ScriptedTransport {
+        let mut map = BTreeMap::new();
+        map.insert("content-type".to_string(), content_type.to_string());
+        self.script.borrow_mut().responses.push_back(HttpResponse {
+            code: Some(code),
+            data: String::from_utf8(body.to_vec()).map_err(|e| e.to_string()),
+            headers: map,
+            bytes: body.to_vec(),
+            unsent: false,
+        });
+        self
+    }
+    /// Queue a transport-level failure (no status code), after the request
+    /// may have reached the server.
+    pub fn push_error(&self, error: &str) -> &ScriptedTransport {
+        self.script.borrow_mut().responses.push_back(HttpResponse {
+            code: None,
+            data: Err(error.to_string()),
+            headers: BTreeMap::new(),
+            bytes: Vec::new(),
+            unsent: false,
+        });
+        self
+    }
+    /// Queue a failure to connect, before anything was sent.
+    pub fn push_connect_error(&self, error: &str) -> &ScriptedTransport {
+        self.push_error(error);
+        self.script.borrow_mut().responses.back_mut().unwrap().unsent = true;
+        self
+    }
+    /// All requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.script.borrow().requests.clone()
+    }
+}
+
+impl HttpTransport for ScriptedTransport {
+    fn request(&self, request: &HttpRequest) -> HttpResponse {
+        let mut script = self.script.borrow_mut();
+        script.requests.push(request.clone());
+        match script.responses.pop_front() {
+            Some(response) => response,
+            None => HttpResponse {
+                code: None,
+                data: Err(format!("no scripted response for {}", request.url)),
+                headers: BTreeMap::new(),
+                bytes: Vec::new(),
+                unsent: false,
+            },
+        }
+    }
+}
+
+/// When and how often to retry failed requests.
+///
+/// Transport failures, 429 (rate limited) and 500/502/503/504 are retried
+/// for idempotent requests.  POST and PATCH, which may skip two tracks or add
+/// a song twice, are only retried on 429 or if they never reached the
+/// server.  Either way, retries use exponential backoff: `base_delay_ms`, doubled for every attempt and
+/// capped at `max_delay_ms`.  With `jitter`, each delay is randomized between
+/// half and all of that value so that several clients don't retry in lockstep.
+///
+/// With `honour_retry_after`, a 429 waits for as long as Spotify's
+/// Retry-After header asks.  If that is longer than `max_delay_ms`, the
+/// request is not retried at all and the caller gets the rate limit error.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first.  1 disables retries.
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub jitter: bool,
+    pub honour_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay_ms: 250,
+            max_delay_ms: 8000,
+            jitter: true,
+            honour_retry_after: true,
+        }
     }
-    let result: Result<String,String> = match String::from_utf8(json_bytes) {
-        Ok(x) => { Ok(x) }
-        Err(x) => { Err(x.utf8_error().description().to_string()) }
-    };
-    #[cfg(feature = "verbose_http")]
-    println!("HTTP response: {}", result.clone().unwrap());
-    HttpResponse {code: response, data: result }
 }
 
-pub fn authenticate(scopes: &str, url: &str, settings: &settings::Settings) -> String {
-    let host = format!("http://127.0.0.1:{}", settings.port);
-    let url = format!("{}?client_id={}&response_type=code&scope={}&redirect_uri={}",
-                      url,settings.client_id, scopes, host);
+impl RetryPolicy {
+    /// Policy that never retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy { max_attempts: 1, .. RetryPolicy::default() }
+    }
+    fn is_retryable(method: HttpMethod, response: &HttpResponse) -> bool {
+        if response.unsent || response.code == Some(429) {
+            return true;
+        }
+        if !method.is_idempotent() {
+            return false;
+        }
+        match response.code {
+            None => true,
+            Some(500) | Some(502) | Some(503) | Some(504) => true,
+            _ => false,
+        }
+    }
+    /// How long to wait before retrying, after attempt number `attempt`
+    /// (starting at 1) of a `method` request returned `response`.  None means
+    /// give up.
+    pub fn retry_delay(&self, attempt: u32, method: HttpMethod, response: &HttpResponse) -> Option<Duration> {
+        if attempt >= self.max_attempts || !RetryPolicy::is_retryable(method, response) {
+            return None;
+        }
+        if self.honour_retry_after && response.code == Some(429) {
+            if let Some(secs) = response.retry_after() {
+                let delay_ms = secs.saturating_mul(1000);
+                if delay_ms > self.max_delay_ms {
+                    return None;
+                }
+                return Some(Duration::from_millis(delay_ms));
+            }
+        }
+        let shift = ::std::cmp::min(attempt - 1, 32);
+        let backoff = ::std::cmp::min(self.base_delay_ms.saturating_mul(1 << shift), self.max_delay_ms);
+        let delay_ms = match self.jitter && backoff > 1 {
+            true => {
+                // Cheap randomness is plenty here; this isn't security sensitive.
+                let half = backoff / 2;
+                half + time::precise_time_ns() % (backoff - half + 1)
+            },
+            false => backoff,
+        };
+        Some(Duration::from_millis(delay_ms))
+    }
+}
+
+/// One-off request with a fresh `CurlTransport`.  Connections are not reused
+/// between calls; hold on to a transport for that.
+pub fn http(url: &str, query: &str, body: &str,
+            method: HttpMethod, access_token: AccessToken) -> HttpResponse {
+    CurlTransport::new().request(&HttpRequest::new(url, query, body, method, access_token))
+}
+
+/// Run the OAuth authorization code flow, returning the authorization code.
+///
+/// Opens the Spotify login page in a browser and waits for it to redirect
+/// back to a temporary web server on `settings.port`.  A random `state`
+/// parameter is sent with the request and must come back unchanged.
+///
+/// In headless mode, or if no browser can be opened, the login URL is
+/// printed instead.  The user can open it on any machine and paste the URL
+/// they are redirected to on stdin.  It must be the full URL: a bare code
+/// can't be matched against `state`.  The callback server
+/// keeps running meanwhile, so with `bind` set to a public interface the
+/// redirect can also complete remotely.
+pub fn authenticate(scopes: &str, url: &str, settings: &settings::Settings,
+                    pkce: Option<&PkceChallenge>) -> Result<String, ConnectrError> {
+    let state = random_token(24);
+    let mut url = format!("{}?client_id={}&response_type=code&scope={}&redirect_uri={}&state={}",
+                          url,settings.client_id, scopes, settings.redirect_uri(), state);
+    if let Some(pkce) = pkce {
+        url = format!("{}&code_challenge_method={}&code_challenge={}",
+                      url, pkce.method(), pkce.challenge);
+    }
     let query = percent_encoding::utf8_percent_encode(&url, percent_encoding::QUERY_ENCODE_SET).collect::<String>();
-    let response = "HTTP/1.1 200 OK\r\n\r\n<html><body>
-Authenticated with Spotify.<br/><br/>
-You can close this window.<br/><br/>
-<button type=\"button\" onclick=\"window.open('', '_self', ''); window.close();\">Close</button><br/>
-</body></html>";
-    let auth_lines = oauth_request_with_local_webserver(settings.port, &query, response);
-    let auth_code = spotify_auth_code(auth_lines);
-    auth_code
-}
-
-fn oauth_request_with_local_webserver(port: u32, url: &str, reply: &str) -> Vec<String> {
-    if !open::that(url).is_ok() {
-        return Vec::<String>::new()
-    }
-    let host = format!("127.0.0.1:{}", port);
-    let listener = TcpListener::bind(host).unwrap();
-    let stream = listener.accept().unwrap().0;
+    // Listen before opening the browser, so a fast redirect can't beat us.
+    let host = format!("{}:{}", settings.bind, settings.port);
+    let listener = match TcpListener::bind(&host) {
+        Ok(l) => l,
+        Err(e) => return Err(ConnectrError::Config(format!("cannot listen on {}: {}", host, e))),
+    };
+    let pasted = match settings.headless || !open::that(&query).is_ok() {
+        true => {
+            if !settings.headless {
+                warn!("Failed to open a web browser.  Falling back to headless login.");
+            }
+            Some(headless_prompt(&query))
+        },
+        false => None,
+    };
+    oauth_request_with_local_webserver(&listener, &state, Duration::from_secs(OAUTH_TIMEOUT_SECS),
+                                       pasted)
+}
+
+/// Print login instructions, and start reading pasted redirects from stdin.
+fn headless_prompt(url: &str) -> Receiver<String> {
+    println!("");
+    println!("To authorize connectr, open this URL in a web browser on any machine:");
+    println!("");
+    println!("  {}", url);
+    println!("");
+    println!("After logging in, your browser is redirected to a page that may fail to load.");
+    println!("Paste the full address of that page, including the state, here:");
+    let (tx, rx) = channel::<String>();
+    // This thread stays blocked on stdin after login completes.  It's only
+    // started when there's nobody at a browser, so that's harmless.
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => if tx.send(line).is_err() { break; },
+                Err(_) => break,
+            }
+        }
+    });
+    rx
+}
+
+/// Interpret a redirect URL or query string pasted by the user.  Like a
+/// redirect to the callback server, it must carry the expected `state`.
+fn parse_pasted_redirect(input: &str, expected_state: &str) -> OAuthCallback {
+    let input = input.trim();
+    if input.len() == 0 || !input.contains('=') {
+        return OAuthCallback::Unrelated;
+    }
+    let target = match input.find("://") {
+        Some(idx) => {
+            let rest = &input[idx+3..];
+            match rest.find(|c| c == '/' || c == '?') {
+                Some(path) if rest[path..].starts_with('?') => format!("/{}", &rest[path..]),
+                Some(path) => rest[path..].to_string(),
+                None => "/".to_string(),
+            }
+        },
+        None if input.starts_with('/') => input.to_string(),
+        None if input.starts_with('?') => format!("/{}", input),
+        None => format!("/?{}", input),
+    };
+    parse_oauth_callback(&target, expected_state)
+}
+
+/// Result of a request received by the OAuth callback server.
+#[derive(Debug, PartialEq)]
+enum OAuthCallback {
+    /// Not a redirect from Spotify: favicon, port scanner, etc.
+    Unrelated,
+    /// Redirect with a `state` we didn't send.
+    BadState,
+    /// User approved.  Contains the authorization code.
+    Code(String),
+    /// User declined, or Spotify refused the request.
+    Denied(String, Option<String>),
+}
+
+/// Interpret the path and query (`/?code=...&state=...`) of a callback request.
+fn parse_oauth_callback(target: &str, expected_state: &str) -> OAuthCallback {
+    let (path, query) = match target.find('?') {
+        Some(idx) => (&target[..idx], &target[idx+1..]),
+        None => (target, ""),
+    };
+    if path != "/" {
+        return OAuthCallback::Unrelated;
+    }
+    let mut code = None;
+    let mut error = None;
+    let mut description = None;
+    let mut state = None;
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "code" => code = Some(value.into_owned()),
+            "error" => error = Some(value.into_owned()),
+            "error_description" => description = Some(value.into_owned()),
+            "state" => state = Some(value.into_owned()),
+            _ => {},
+        }
+    }
+    if code.is_none() && error.is_none() {
+        return OAuthCallback::Unrelated;
+    }
+    if state.as_ref().map(|s| s.as_str()) != Some(expected_state) {
+        return OAuthCallback::BadState;
+    }
+    match (code, error) {
+        (_, Some(error)) => OAuthCallback::Denied(error, description),
+        (Some(code), None) => OAuthCallback::Code(code),
+        (None, None) => OAuthCallback::Unrelated,
+    }
+}
+
+fn oauth_page(status: &str, title: &str, message: &str) -> String {
+    let body = format!("<!DOCTYPE html>
+<html><head><meta charset=\"utf-8\"><title>connectr - {title}</title>
+<style>
+body {{ font-family: -apple-system, 'Segoe UI', Helvetica, Arial, sans-serif;
+       background: #181818; color: #fff; text-align: center; padding-top: 15%; }}
+h1 {{ font-weight: 400; }}
+p {{ color: #b3b3b3; }}
+button {{ background: #1db954; color: #fff; border: 0; border-radius: 500px;
+         padding: 10px 32px; font-size: 14px; cursor: pointer; }}
+</style></head><body>
+<h1>{title}</h1>
+<p>{message}</p>
+<button type=\"button\" onclick=\"window.open('', '_self', ''); window.close();\">Close</button>
+</body></html>", title = title, message = message);
+    format!("HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, body.len(), body)
+}
+
+/// Read the request line of one HTTP request, and return its target.
+fn read_request_target(stream: &TcpStream) -> Option<String> {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
     let mut reader = BufReader::new(stream);
-    let mut response = Vec::<String>::new();
-    for line in reader.by_ref().lines() {
-        let line_str = line.unwrap();
-        response.push(line_str.clone());
-        if line_str == "" {
-            break;
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return None;
+    }
+    // Drain the headers; the browser may not like us closing on it early.
+    for line in reader.lines() {
+        match line {
+            Ok(ref l) if l.len() > 0 => continue,
+            _ => break,
         }
     }
-    let _ = reader.into_inner().write(reply.as_bytes());
-    response
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => Some(target.to_string()),
+        _ => None,
+    }
 }
 
-fn spotify_auth_code(lines: Vec<String>) -> String {
-    let mut auth_code = String::new();
-    for line in lines {
-        let line_str = line;
-        let re = Regex::new(r"code=([^?& ]+)").unwrap();
-        let ismatch = re.is_match(line_str.as_str());
-        if ismatch {
-            let cap = re.captures(line_str.as_str()).unwrap();
-            auth_code = auth_code + &cap[1];
+fn oauth_request_with_local_webserver(listener: &TcpListener, state: &str, timeout: Duration,
+                                      pasted: Option<Receiver<String>>) -> Result<String, ConnectrError> {
+    if let Err(e) = listener.set_nonblocking(true) {
+        return Err(ConnectrError::Transport(e.to_string()));
+    }
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(ref rx) = pasted {
+            let input = match rx.try_recv() {
+                Ok(input) => Some(input),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+            };
+            if let Some(input) = input {
+                match parse_pasted_redirect(&input, state) {
+                    OAuthCallback::Code(code) => return Ok(code),
+                    OAuthCallback::Denied(error, description) =>
+                        return Err(ConnectrError::Authorization { error: error, description: description }),
+                    OAuthCallback::BadState => {
+                        println!("That address is missing this login's state, or is from an old login.  Please use the URL printed above.");
+                    },
+                    OAuthCallback::Unrelated => {
+                        if input.trim().len() > 0 {
+                            println!("That doesn't look like a Spotify redirect.  Please paste the full address:");
+                        }
+                    },
+                }
+            }
+        }
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            },
+            Err(e) => return Err(ConnectrError::Transport(e.to_string())),
+        };
+        let target = match read_request_target(&stream) {
+            Some(t) => t,
+            None => continue,
+        };
+        match parse_oauth_callback(&target, state) {
+            OAuthCallback::Unrelated => {
+                let _ = stream.write_all(oauth_page("404 Not Found", "Not Found",
+                                                    "Nothing to see here.").as_bytes());
+            },
+            OAuthCallback::BadState => {
+                // Could be a forged request, or an old browser tab.  Either
+                // way, keep waiting for the real one.
+                warn!("Ignoring OAuth callback with unexpected state.");
+                let _ = stream.write_all(oauth_page("400 Bad Request", "Login Failed",
+                                                    "This login link has expired.  Please try again from connectr.").as_bytes());
+            },
+            OAuthCallback::Denied(error, description) => {
+                let _ = stream.write_all(oauth_page("200 OK", "Login Failed",
+                                                    "connectr was not authorized to use your Spotify account.").as_bytes());
+                return Err(ConnectrError::Authorization { error: error, description: description });
+            },
+            OAuthCallback::Code(code) => {
+                let _ = stream.write_all(oauth_page("200 OK", "Authenticated with Spotify",
+                                                    "You can close this window.").as_bytes());
+                return Ok(code);
+            },
         }
     }
-    auth_code
+    Err(ConnectrError::Authorization {
+        error: "timeout".to_string(),
+        description: Some(format!("no response from browser within {} seconds", timeout.as_secs())),
+    })
 }
@@ -0,0 +1,200 @@
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    #[test]
+    fn test_pkce_rfc7636_example() {
+        // Example from RFC 7636, Appendix B
+        let pkce = PkceChallenge::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+        assert_eq!(pkce.challenge, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+        assert_eq!(pkce.method(), "S256");
+    }
+
+    #[test]
+    fn test_pkce_random_verifier() {
+        let a = PkceChallenge::new();
+        let b = PkceChallenge::new();
+        assert_eq!(a.verifier.len(), 64);
+        assert!(a.verifier != b.verifier);
+        assert!(a.verifier.chars().all(|c| c.is_alphanumeric() || "-._~".contains(c)));
+    }
+
+    #[test]
+    fn test_oauth_callback_code() {
+        assert_eq!(parse_oauth_callback("/?code=abc-123&state=xyz", "xyz"),
+                   OAuthCallback::Code("abc-123".to_string()));
+    }
+
+    #[test]
+    fn test_oauth_callback_denied() {
+        assert_eq!(parse_oauth_callback("/?error=access_denied&state=xyz", "xyz"),
+                   OAuthCallback::Denied("access_denied".to_string(), None));
+    }
+
+    #[test]
+    fn test_oauth_callback_bad_state() {
+        assert_eq!(parse_oauth_callback("/?code=abc&state=evil", "xyz"), OAuthCallback::BadState);
+        assert_eq!(parse_oauth_callback("/?code=abc", "xyz"), OAuthCallback::BadState);
+    }
+
+    #[test]
+    fn test_oauth_callback_unrelated() {
+        assert_eq!(parse_oauth_callback("/favicon.ico", "xyz"), OAuthCallback::Unrelated);
+        assert_eq!(parse_oauth_callback("/", "xyz"), OAuthCallback::Unrelated);
+        assert_eq!(parse_oauth_callback("/other?code=abc&state=xyz", "xyz"), OAuthCallback::Unrelated);
+    }
+
+    #[test]
+    fn test_oauth_webserver_ignores_strays() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::thread;
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut replies = Vec::new();
+            for target in &["/favicon.ico", "/?code=abc&state=wrong", "/?code=abc&state=xyz"] {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                write!(stream, "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", target).unwrap();
+                let mut reply = String::new();
+                let _ = stream.read_to_string(&mut reply);
+                replies.push(reply.lines().next().unwrap_or("").to_string());
+            }
+            replies
+        });
+        let code = oauth_request_with_local_webserver(&listener, "xyz", Duration::from_secs(10), None);
+        assert_eq!(code.unwrap(), "abc");
+        assert_eq!(client.join().unwrap(), vec!["HTTP/1.1 404 Not Found",
+                                                "HTTP/1.1 400 Bad Request",
+                                                "HTTP/1.1 200 OK"]);
+    }
+
+    #[test]
+    fn test_pasted_redirect() {
+        let code = OAuthCallback::Code("abc".to_string());
+        assert_eq!(parse_pasted_redirect("http://127.0.0.1:5432/?code=abc&state=xyz", "xyz"), code);
+        assert_eq!(parse_pasted_redirect("  http://127.0.0.1:5432?code=abc&state=xyz\n", "xyz"), code);
+        assert_eq!(parse_pasted_redirect("?code=abc&state=xyz", "xyz"), code);
+        assert_eq!(parse_pasted_redirect("code=abc&state=xyz", "xyz"), code);
+        // A bare code, or one without its state, could have come from anywhere.
+        assert_eq!(parse_pasted_redirect("abc", "xyz"), OAuthCallback::Unrelated);
+        assert_eq!(parse_pasted_redirect("http://127.0.0.1:5432/?code=abc", "xyz"), OAuthCallback::BadState);
+        assert_eq!(parse_pasted_redirect("http://127.0.0.1:5432/?code=abc&state=old", "xyz"),
+                   OAuthCallback::BadState);
+        assert_eq!(parse_pasted_redirect("", "xyz"), OAuthCallback::Unrelated);
+    }
+
+    #[test]
+    fn test_oauth_webserver_pasted_code() {
+        use std::net::TcpListener;
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (tx, rx) = channel();
+        tx.send("http://127.0.0.1:5432/?error=access_denied&state=xyz".to_string()).unwrap();
+        match oauth_request_with_local_webserver(&listener, "xyz", Duration::from_secs(10), Some(rx)) {
+            Err(ConnectrError::Authorization { error, .. }) => assert_eq!(error, "access_denied"),
+            _ => assert!(false),
+        }
+    }
+
+    /// Answer each connection with an empty JSON object, passing on the raw
+    /// request line and headers, and the body, as received.
+    fn wire_server() -> (String, ::std::sync::mpsc::Receiver<(String, String)>) {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+        use std::sync::mpsc::channel;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/me/player", listener.local_addr().unwrap());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let mut head = String::new();
+                let mut body = Vec::new();
+                {
+                    let mut reader = BufReader::new(&mut stream);
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                            break;
+                        }
+                        if line.to_lowercase().starts_with("content-length:") {
+                            length = line[15..].trim().parse().unwrap_or(0);
+                        }
+                        head.push_str(&line);
+                    }
+                    let _ = reader.take(length).read_to_end(&mut body);
+                }
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}");
+                if tx.send((head, String::from_utf8_lossy(&body).into_owned())).is_err() {
+                    return;
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_curl_transport_on_the_wire() {
+        let (url, rx) = wire_server();
+        let transport = CurlTransport::new();
+
+        let request = HttpRequest::new(&url, "ids=1,2", "", HttpMethod::DELETE, AccessToken::Bearer("token"))
+            .header("If-None-Match", "\"xyz\"");
+        let response = transport.request(&request);
+        assert_eq!(response.code, Some(200));
+        assert_eq!(response.data, Ok("{}".to_string()));
+        let (head, body) = rx.recv().unwrap();
+        assert!(head.starts_with("DELETE /v1/me/player?ids=1,2 HTTP/1.1\r\n"));
+        assert!(head.contains("Authorization: Bearer token\r\n"));
+        assert!(head.contains("If-None-Match: \"xyz\"\r\n"));
+        assert_eq!(body, "");
+
+        // DELETE and PATCH bodies are sent with post_fields_copy.
+        let request = HttpRequest::new(&url, "", r#"{"ids":["1"]}"#, HttpMethod::DELETE, AccessToken::None)
+            .header("Content-Type", "application/json");
+        transport.request(&request);
+        let (head, body) = rx.recv().unwrap();
+        assert!(head.starts_with("DELETE /v1/me/player HTTP/1.1\r\n"));
+        assert!(head.contains("Content-Type: application/json\r\n"));
+        assert!(!head.contains("Authorization"));
+        assert_eq!(body, r#"{"ids":["1"]}"#);
+
+        let request = HttpRequest::new(&url, "", r#"{"name":"x"}"#, HttpMethod::PATCH, AccessToken::Bearer("token"));
+        transport.request(&request);
+        let (head, body) = rx.recv().unwrap();
+        assert!(head.starts_with("PATCH /v1/me/player HTTP/1.1\r\n"));
+        assert_eq!(body, r#"{"name":"x"}"#);
+
+        // A body-less POST sends its query as a form.
+        let request = HttpRequest::new(&url, "grant_type=refresh_token", "", HttpMethod::POST, AccessToken::None);
+        transport.request(&request);
+        let (head, body) = rx.recv().unwrap();
+        assert!(head.starts_with("POST /v1/me/player HTTP/1.1\r\n"));
+        assert_eq!(body, "grant_type=refresh_token");
+
+        let request = HttpRequest::new(&url, "uri=spotify%3Atrack%3A1", "", HttpMethod::POST, AccessToken::Bearer("token"))
+            .query_in_url();
+        transport.request(&request);
+        let (head, body) = rx.recv().unwrap();
+        assert!(head.starts_with("POST /v1/me/player?uri=spotify%3Atrack%3A1 HTTP/1.1\r\n"));
+        assert_eq!(body, "");
+
+        let request = HttpRequest::new(&url, "device_id=abc", r#"{"play":true}"#, HttpMethod::PUT, AccessToken::None);
+        transport.request(&request);
+        let (head, body) = rx.recv().unwrap();
+        assert!(head.starts_with("PUT /v1/me/player?device_id=abc HTTP/1.1\r\n"));
+        assert!(!head.contains("Expect:"));
+        assert_eq!(body, r#"{"play":true}"#);
+    }
+}
@@ -0,0 +1,246 @@
+//! Control socket for scripts, hotkey daemons and connectr-cli.
+//!
+//! The menu bar app listens on a Unix domain socket, and answers one JSON-RPC
+//! 2.0 request per line.  Methods and parameters are those of
+//! `control::Command`, plus an optional `device` to send the command to:
+//!
+//!   --> {"jsonrpc": "2.0", "id": 1, "method": "volume", "params": {"percent": 40}}
+//!   <-- {"jsonrpc": "2.0", "id": 1, "result": null}
+//!
+//! `devices` and `player_state` are answered from what the app last fetched,
+//! unless called with `"refresh": true`.  Errors from Spotify have code
+//! -32000, with the kind of error in `data`.
+
+#[cfg(test)]
+mod test;
+
+extern crate libc;
+extern crate serde_json;
+use self::serde_json::Value;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use super::control::{self, Command, CommandHandle, object, encode_error, decode_error};
+use super::error::ConnectrError;
+use super::settings::Settings;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// Spotify, or the app, failed to carry out the command.
+pub const COMMAND_FAILED: i64 = -32000;
+
+/// How long a client waits for the app to run its command.  A little longer
+/// than the app waits for it to run.
+const CLIENT_TIMEOUT_SECS: u64 = 35;
+
+/// `$XDG_RUNTIME_DIR/connectr.sock`, or `~/.connectr.sock` without one.
+pub fn default_socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(ref dir) if dir.len() > 0 => Path::new(dir).join("connectr.sock"),
+        _ => env::home_dir().unwrap_or(PathBuf::from(".")).join(".connectr.sock"),
+    }
+}
+
+/// Socket configured in connectr.ini, or the default one.
+pub fn socket_path(settings: &Settings) -> PathBuf {
+    match settings.ipc_socket {
+        Some(ref path) => PathBuf::from(path),
+        None => default_socket_path(),
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str, data: Option<Value>) -> Value {
+    let mut error = vec![("code", code.into()), ("message", message.into())];
+    if let Some(data) = data {
+        error.push(("data", data));
+    }
+    object(vec![("jsonrpc", "2.0".into()), ("id", id), ("error", object(error))])
+}
+
+/// Fail unless `dir` belongs to this user and nobody else can add or
+/// replace files in it, so nobody can connect to the socket or swap it out.
+fn check_private_dir(dir: &Path) -> Result<(), ConnectrError> {
+    let meta = fs::metadata(dir)
+        .map_err(|e| ConnectrError::Config(format!("can't check {}: {}", dir.display(), e)))?;
+    let uid = unsafe { libc::geteuid() };
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o022 != 0 {
+        return Err(ConnectrError::Config(format!(
+            "{} must be a directory owned by and only writable by this user", dir.display())));
+    }
+    Ok(())
+}
+
+/// Handle one line from a client.  Returns the response, if one is due.
+fn handle_request(line: &str, commands: &CommandHandle) -> Option<Value> {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return Some(error_response(Value::Null, PARSE_ERROR, &e.to_string(), None)),
+    };
+    // Requests without an ID are notifications, and get no response.
+    let id = request.get("id").cloned();
+    let respond = |response: Value| id.as_ref().map(|_| response);
+    let reply_id = id.clone().unwrap_or(Value::Null);
+    let method = match (request.get("jsonrpc"), request.get("method").and_then(|m| m.as_str())) {
+        (Some(&Value::String(ref version)), Some(method)) if version == "2.0" => method,
+        _ => return Some(error_response(reply_id, INVALID_REQUEST, "not a JSON-RPC 2.0 request", None)),
+    };
+    let params = match request.get("params") {
+        None => object(vec![]),
+        Some(&Value::Object(ref params)) => Value::Object(params.clone()),
+        Some(_) => return respond(error_response(reply_id, INVALID_PARAMS, "params must be an object", None)),
+    };
+    if !control::METHODS.contains(&method) {
+        let message = format!("unknown method '{}'", method);
+        return respond(error_response(reply_id, METHOD_NOT_FOUND, &message, None));
+    }
+    let command = match Command::parse(method, &params) {
+        Ok(command) => command,
+        Err(e) => return respond(error_response(reply_id, INVALID_PARAMS, &e, None)),
+    };
+    // select_device's device is where to go, not where to send it.
+    let device = match command {
+        Command::SelectDevice(_) => None,
+        _ => params.get("device").and_then(|d| d.as_str()).map(|d| d.to_string()),
+    };
+    respond(match commands.run(&command, device) {
+        Ok(result) => object(vec![("jsonrpc", "2.0".into()), ("id", reply_id), ("result", result)]),
+        Err(e) => error_response(reply_id, COMMAND_FAILED, &e.to_string(), Some(encode_error(&e))),
+    })
+}
+
+fn serve_client(stream: UnixStream, commands: CommandHandle) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if line.trim().len() == 0 {
+            continue;
+        }
+        if let Some(response) = handle_request(&line, &commands) {
+            if writeln!(writer, "{}", response).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Listens on the control socket.  Clients are served on their own threads,
+/// and their commands sent to the main thread's `CommandQueue`.
+pub struct IpcServer {
+    path: PathBuf,
+}
+
+impl IpcServer {
+    /// Listen on `path`, replacing a stale socket left by a crash.  Fails if
+    /// another connectr is already listening there.
+    pub fn bind(path: &Path, commands: CommandHandle) -> Result<IpcServer, ConnectrError> {
+        let dir = match path.parent() {
+            Some(dir) if dir.as_os_str().len() > 0 => dir,
+            _ => Path::new("."),
+        };
+        check_private_dir(dir)?;
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            // Never unlink something that isn't a socket, e.g. a mistyped path.
+            if !meta.file_type().is_socket() {
+                return Err(ConnectrError::Config(format!("{} exists and is not a socket", path.display())));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(ConnectrError::Config(format!("{} is already in use", path.display())));
+            }
+            let _ = fs::remove_file(path);
+        }
+        // Anyone who can connect can control the account, so the socket must
+        // never exist with looser permissions, even briefly.
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask); }
+        let listener = listener
+            .map_err(|e| ConnectrError::Config(format!("can't listen on {}: {}", path.display(), e)))?;
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+            let _ = fs::remove_file(path);
+            return Err(ConnectrError::Config(format!("can't restrict {} to this user: {}", path.display(), e)));
+        }
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let commands = commands.clone();
+                thread::spawn(move || serve_client(stream, commands));
+            }
+        });
+        info!("Control socket at {}", path.display());
+        Ok(IpcServer { path: path.to_path_buf() })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for IpcServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Client side of the control socket.
+pub struct IpcClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+impl IpcClient {
+    pub fn connect(path: &Path) -> Result<IpcClient, ConnectrError> {
+        let transport = |e: ::std::io::Error| ConnectrError::Transport(format!("{}: {}", path.display(), e));
+        let stream = UnixStream::connect(path).map_err(&transport)?;
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(CLIENT_TIMEOUT_SECS)));
+        let writer = stream.try_clone().map_err(&transport)?;
+        Ok(IpcClient { reader: BufReader::new(stream), writer: writer, next_id: 1 })
+    }
+    /// Run a command in the app, optionally on another device.
+    pub fn call(&mut self, command: &Command, device: Option<&str>) -> Result<Value, ConnectrError> {
+        let mut params = command.params();
+        if let (Some(device), Some(params)) = (device, params.as_object_mut()) {
+            params.insert("device".to_string(), device.into());
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = object(vec![("jsonrpc", "2.0".into()), ("id", id.into()),
+                                  ("method", command.method().into()), ("params", params)]);
+        let io_error = |e: ::std::io::Error| ConnectrError::Transport(format!("control socket: {}", e));
+        writeln!(self.writer, "{}", request).map_err(&io_error)?;
+        let mut line = String::new();
+        match self.reader.read_line(&mut line).map_err(&io_error)? {
+            0 => return Err(ConnectrError::Transport("control socket closed".to_string())),
+            _ => {},
+        }
+        let mut response: Value = serde_json::from_str(&line)?;
+        if let Some(error) = response.get("error") {
+            let message = error.get("message").and_then(|m| m.as_str()).unwrap_or("").to_string();
+            return Err(match error.get("data").and_then(decode_error) {
+                Some(err) => err,
+                None => ConnectrError::Transport(format!("control socket: {}", message)),
+            });
+        }
+        match response.get_mut("result") {
+            Some(result) => Ok(result.take()),
+            None => Err(ConnectrError::Deserialize("response has no result".to_string())),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+    use super::super::*;
+    use ::ConnectDeviceList;
+    use ::control::{Command, CommandQueue, StateCache};
+    use ::settings::Settings;
+    use ::webapi::fixtures::{DEVICES, scripted_accounts, serve_commands};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static SOCKETS: AtomicUsize = AtomicUsize::new(0);
+
+    /// A socket in a fresh directory only this user can write to.
+    fn socket_path() -> PathBuf {
+        let dir = env::temp_dir().join(format!("connectr-test-{}-{}", ::std::process::id(),
+                                               SOCKETS.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        dir.join("connectr.sock")
+    }
+
+    /// Send raw lines, and read one line back for each.
+    fn raw(path: &Path, lines: &[&str]) -> Vec<Value> {
+        let mut stream = UnixStream::connect(path).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        lines.iter().map(|line| {
+            writeln!(stream, "{}", line).unwrap();
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            serde_json::from_str(&response).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn test_ipc_bind() {
+        let path = socket_path();
+        let queue = CommandQueue::new();
+        // Files other than sockets are left alone.
+        fs::File::create(&path).unwrap();
+        assert!(IpcServer::bind(&path, queue.handle()).is_err());
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+
+        // Stale sockets are replaced.
+        drop(UnixListener::bind(&path).unwrap());
+        let server = IpcServer::bind(&path, queue.handle()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Only one app per socket.
+        assert!(IpcServer::bind(&path, queue.handle()).is_err());
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_ipc_bind_shared_dir() {
+        let path = socket_path();
+        let dir = path.parent().unwrap().to_path_buf();
+        let queue = CommandQueue::new();
+        // Others could connect to, or replace, a socket here.
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(IpcServer::bind(&path, queue.handle()).is_err());
+        assert!(!path.exists());
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        assert!(IpcServer::bind(&path, queue.handle()).is_ok());
+    }
+
+    #[test]
+    fn test_ipc_cached_queries() {
+        let path = socket_path();
+        let queue = CommandQueue::new();
+        let _server = IpcServer::bind(&path, queue.handle()).unwrap();
+        let devices: ConnectDeviceList = serde_json::from_str(DEVICES).unwrap();
+        queue.update(StateCache { devices: Some(devices), player_state: None });
+
+        // Answered without the main thread.
+        let mut client = IpcClient::connect(&path).unwrap();
+        let devices = client.call(&Command::Devices { refresh: false }, None).unwrap();
+        assert_eq!(devices[0]["name"], "Kitchen");
+        let state = client.call(&Command::PlayerState { refresh: false }, None).unwrap();
+        assert_eq!(state, Value::Null);
+    }
+
+    #[test]
+    fn test_ipc_commands() {
+        let path = socket_path();
+        let queue = CommandQueue::new();
+        let _server = IpcServer::bind(&path, queue.handle()).unwrap();
+        let (mut accounts, transport) = scripted_accounts(Settings::for_test());
+
+        transport.push_response(200, DEVICES);
+        transport.push_response(204, "");
+        transport.push_response_with_headers(429, &[("Retry-After", "7")], "");
+        transport.push_response(404, r#"{"error": {"status": 404, "message": "Player command failed: No active device found", "reason": "NO_ACTIVE_DEVICE"}}"#);
+        let client_path = path.clone();
+        let (results, changed) = serve_commands(&queue, &mut accounts, move || {
+            let mut client = IpcClient::connect(&client_path).unwrap();
+            vec![client.call(&Command::Volume(25), Some("kitchen")),
+                 client.call(&Command::SkipNext, None),
+                 client.call(&Command::Pause, None)]
+        });
+        assert!(changed);
+        assert_eq!(*results[0].as_ref().unwrap(), Value::Null);
+        match results[1] {
+            Err(ConnectrError::RateLimited(Some(7))) => {},
+            ref other => panic!("unexpected {:?}", other),
+        }
+        match results[2] {
+            Err(ConnectrError::Http { code: 404, error: Some(ref e) }) => {
+                assert_eq!(e.reason, Some("NO_ACTIVE_DEVICE".to_string()));
+            },
+            ref other => panic!("unexpected {:?}", other),
+        }
+        let requests = transport.requests();
+        assert!(requests[1].query.contains("device_id=abc123"));
+        assert!(requests[1].query.contains("volume_percent=25"));
+    }
+
+    #[test]
+    fn test_ipc_protocol_errors() {
+        let path = socket_path();
+        let queue = CommandQueue::new();
+        let _server = IpcServer::bind(&path, queue.handle()).unwrap();
+        let responses = raw(&path, &[
+            "{not json",
+            r#"{"id": 1, "method": "pause"}"#,
+            r#"{"jsonrpc": "2.0", "id": 2, "method": "rewind"}"#,
+            r#"{"jsonrpc": "2.0", "id": 3, "method": "volume", "params": {"percent": 300}}"#,
+            r#"{"jsonrpc": "2.0", "id": 4, "method": "seek", "params": [1000]}"#,
+        ]);
+        let codes: Vec<i64> = responses.iter().map(|r| r["error"]["code"].as_i64().unwrap()).collect();
+        assert_eq!(codes, vec![PARSE_ERROR, INVALID_REQUEST, METHOD_NOT_FOUND, INVALID_PARAMS, INVALID_PARAMS]);
+        assert_eq!(responses[0]["id"], Value::Null);
+        assert_eq!(responses[3]["id"], 3);
+    }
+}
@@ -1,9 +1,18 @@
+pub mod error;
 pub mod http;
 pub mod settings;
+pub mod tokens;
 pub mod webapi;
+pub mod control;
+#[cfg(unix)]
+pub mod ipc;
+pub mod server;
+mod fsutil;
 
 // Re-export webapi interface to connectr root
 pub use webapi::*;
+pub use error::ConnectrError;
+pub use settings::Account;
 
 #[cfg(test)]
 #[macro_use]
@@ -18,6 +27,9 @@ pub mod osx;
 #[cfg(target_os = "windows")]
 pub mod win;
 
+#[cfg(target_os = "linux")]
+pub mod linux;
+
 #[cfg(target_os = "macos")]
 #[macro_use]
 extern crate objc;
@@ -41,10 +53,22 @@ pub struct SpotifyEndpoints<'a> {
     shuffle: &'a str,
     repeat: &'a str,
     player: &'a str,
+    search: &'a str,
+    saved_tracks: &'a str,
+    saved_tracks_contains: &'a str,
+    saved_albums: &'a str,
+    saved_albums_contains: &'a str,
+    me: &'a str,
+    my_playlists: &'a str,
+    playlists: &'a str,
+    users: &'a str,
+    queue: &'a str,
+    recently_played: &'a str,
+    top: &'a str,
 }
 
 pub const SPOTIFY_API: SpotifyEndpoints = SpotifyEndpoints {
-    scopes: "user-read-private streaming user-read-playback-state",
+    scopes: "user-read-private streaming user-read-playback-state user-modify-playback-state user-read-currently-playing user-library-read user-library-modify playlist-read-private playlist-read-collaborative playlist-modify-public playlist-modify-private user-read-recently-played user-top-read",
     authorize: "https://accounts.spotify.com/en/authorize",
     token: "https://accounts.spotify.com/api/token",
     devices: "https://api.spotify.com/v1/me/player/devices",
@@ -58,6 +82,18 @@ pub const SPOTIFY_API: SpotifyEndpoints = SpotifyEndpoints {
     shuffle: "https://api.spotify.com/v1/me/player/shuffle",
     repeat: "https://api.spotify.com/v1/me/player/repeat",
     player: "https://api.spotify.com/v1/me/player",
+    search: "https://api.spotify.com/v1/search",
+    saved_tracks: "https://api.spotify.com/v1/me/tracks",
+    saved_tracks_contains: "https://api.spotify.com/v1/me/tracks/contains",
+    saved_albums: "https://api.spotify.com/v1/me/albums",
+    saved_albums_contains: "https://api.spotify.com/v1/me/albums/contains",
+    me: "https://api.spotify.com/v1/me",
+    my_playlists: "https://api.spotify.com/v1/me/playlists",
+    playlists: "https://api.spotify.com/v1/playlists",
+    users: "https://api.spotify.com/v1/users",
+    queue: "https://api.spotify.com/v1/me/player/queue",
+    recently_played: "https://api.spotify.com/v1/me/player/recently-played",
+    top: "https://api.spotify.com/v1/me/top",
 };
 
 #[cfg(target_os = "linux")]
@@ -68,7 +104,7 @@ pub type Object = u32;
 pub type Object = osx::Object;
 
 #[cfg(target_os = "linux")]
-pub type StatusBar = DummyStatusBar;
+pub type StatusBar = linux::LinuxStatusBar;
 #[cfg(target_os = "macos")]
 pub type StatusBar = osx::OSXStatusBar;
 #[cfg(target_os = "windows")]
@@ -83,6 +119,9 @@ pub trait TStatusBar {
     fn add_separator(&mut self);
     fn add_label(&mut self, label: &str);
     fn add_item(&mut self, item: &str, callback: NSCallback, selected: bool) -> *mut Object;
+    /// Items added until `end_submenu()` go in a submenu titled `label`.
+    fn add_submenu(&mut self, label: &str);
+    fn end_submenu(&mut self);
     fn add_quit(&mut self, label: &str);
     fn update_item(&mut self, item: *mut Object, label: &str);
     fn sel_item(&mut self, sender: u64);
@@ -94,6 +133,10 @@ pub trait TStatusBar {
 use std::sync::mpsc::Sender;
 pub type NSCallback = Box<Fn(u64, &Sender<String>)>;
 
+/// Sent on a status bar's channel when its quit item is clicked, by status
+/// bars that leave exiting to the main loop.
+pub const QUIT_COMMAND: &'static str = "quit";
+
 pub struct DummyStatusBar {}
 impl TStatusBar for DummyStatusBar {
     type S = DummyStatusBar;
@@ -103,6 +146,8 @@ impl TStatusBar for DummyStatusBar {
     fn add_separator(&mut self) {}
     fn add_label(&mut self, _: &str) {}
     fn add_item(&mut self, _: &str, _: NSCallback, _: bool) -> *mut Object { 0 as *mut Object }
+    fn add_submenu(&mut self, _: &str) {}
+    fn end_submenu(&mut self) {}
     fn add_quit(&mut self, _: &str) {}
     fn update_item(&mut self, _: *mut Object, _: &str) {}
     fn sel_item(&mut self, _: u64) {}
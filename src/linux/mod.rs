@@ -0,0 +1,628 @@
+#[cfg(test)]
+mod test;
+
+extern crate dbus;
+
+pub mod mpris;
+pub use self::mpris::MprisServer;
+
+pub use ::TStatusBar;
+pub use ::NSCallback;
+use ::Object;
+
+use self::dbus::{Message, MessageType};
+use self::dbus::channel::{BusType, Channel};
+use self::dbus::arg::{Append, Arg, ArgType, IterAppend, PropMap, RefArg, Variant};
+use self::dbus::strings::Signature;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::sleep;
+use std::time::Duration;
+
+const SNI_PATH: &'static str = "/StatusNotifierItem";
+const SNI_IFACE: &'static str = "org.kde.StatusNotifierItem";
+const MENU_PATH: &'static str = "/MenuBar";
+const MENU_IFACE: &'static str = "com.canonical.dbusmenu";
+const WATCHER_NAME: &'static str = "org.kde.StatusNotifierWatcher";
+const PROPS_IFACE: &'static str = "org.freedesktop.DBus.Properties";
+const INTROSPECT_IFACE: &'static str = "org.freedesktop.DBus.Introspectable";
+const ICON_NAME: &'static str = "connectr_80px_300dpi";
+
+// Distinguishes the bus names of several status bars in one process.
+static INSTANCES: AtomicUsize = AtomicUsize::new(0);
+
+const INTROSPECT_SNI: &'static str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.kde.StatusNotifierItem">
+    <property name="Category" type="s" access="read"/>
+    <property name="Id" type="s" access="read"/>
+    <property name="Title" type="s" access="read"/>
+    <property name="Status" type="s" access="read"/>
+    <property name="WindowId" type="i" access="read"/>
+    <property name="IconName" type="s" access="read"/>
+    <property name="IconThemePath" type="s" access="read"/>
+    <property name="ToolTip" type="(sa(iiay)ss)" access="read"/>
+    <property name="ItemIsMenu" type="b" access="read"/>
+    <property name="Menu" type="o" access="read"/>
+    <method name="ContextMenu"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="Activate"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="SecondaryActivate"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="Scroll"><arg name="delta" type="i" direction="in"/><arg name="orientation" type="s" direction="in"/></method>
+    <signal name="NewTitle"/>
+    <signal name="NewIcon"/>
+    <signal name="NewToolTip"/>
+    <signal name="NewStatus"><arg name="status" type="s"/></signal>
+  </interface>
+</node>"#;
+
+const INTROSPECT_MENU: &'static str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="com.canonical.dbusmenu">
+    <property name="Version" type="u" access="read"/>
+    <property name="TextDirection" type="s" access="read"/>
+    <property name="Status" type="s" access="read"/>
+    <property name="IconThemePath" type="as" access="read"/>
+    <method name="GetLayout">
+      <arg name="parentId" type="i" direction="in"/>
+      <arg name="recursionDepth" type="i" direction="in"/>
+      <arg name="propertyNames" type="as" direction="in"/>
+      <arg name="revision" type="u" direction="out"/>
+      <arg name="layout" type="(ia{sv}av)" direction="out"/>
+    </method>
+    <method name="GetGroupProperties">
+      <arg name="ids" type="ai" direction="in"/>
+      <arg name="propertyNames" type="as" direction="in"/>
+      <arg name="properties" type="a(ia{sv})" direction="out"/>
+    </method>
+    <method name="GetProperty">
+      <arg name="id" type="i" direction="in"/>
+      <arg name="name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="Event">
+      <arg name="id" type="i" direction="in"/>
+      <arg name="eventId" type="s" direction="in"/>
+      <arg name="data" type="v" direction="in"/>
+      <arg name="timestamp" type="u" direction="in"/>
+    </method>
+    <method name="EventGroup">
+      <arg name="events" type="a(isvu)" direction="in"/>
+      <arg name="idErrors" type="ai" direction="out"/>
+    </method>
+    <method name="AboutToShow">
+      <arg name="id" type="i" direction="in"/>
+      <arg name="needUpdate" type="b" direction="out"/>
+    </method>
+    <method name="AboutToShowGroup">
+      <arg name="ids" type="ai" direction="in"/>
+      <arg name="updatesNeeded" type="ai" direction="out"/>
+      <arg name="idErrors" type="ai" direction="out"/>
+    </method>
+    <signal name="ItemsPropertiesUpdated">
+      <arg name="updatedProps" type="a(ia{sv})"/>
+      <arg name="removedProps" type="a(ias)"/>
+    </signal>
+    <signal name="LayoutUpdated">
+      <arg name="revision" type="u"/>
+      <arg name="parent" type="i"/>
+    </signal>
+  </interface>
+</node>"#;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum EntryKind {
+    Standard,
+    Separator,
+    Submenu,
+}
+
+struct MenuEntry {
+    id: i32,
+    /// 0 for top-level entries
+    parent: i32,
+    label: String,
+    kind: EntryKind,
+    enabled: bool,
+    checked: bool,
+    /// Has a checkmark, shown or not.  Hosts lay these out differently from
+    /// plain items, so the property must not come and go with `checked`.
+    toggle: bool,
+}
+
+impl MenuEntry {
+    /// dbusmenu properties, limited to `names` unless it's empty.
+    fn props(&self, names: &[String]) -> PropMap {
+        let mut map = PropMap::new();
+        {
+            let mut add = |name: &str, value: Box<RefArg>| {
+                if names.is_empty() || names.iter().any(|n| n == name) {
+                    map.insert(name.to_string(), Variant(value));
+                }
+            };
+            match self.kind {
+                EntryKind::Separator => add("type", Box::new("separator".to_string())),
+                _ => {
+                    // Underscores mark mnemonics in dbusmenu labels.
+                    add("label", Box::new(self.label.replace("_", "__")));
+                    if !self.enabled {
+                        add("enabled", Box::new(false));
+                    }
+                },
+            }
+            if self.kind == EntryKind::Submenu {
+                add("children-display", Box::new("submenu".to_string()));
+            }
+            if self.toggle {
+                add("toggle-type", Box::new("checkmark".to_string()));
+                add("toggle-state", Box::new(self.checked as i32));
+            }
+        }
+        map
+    }
+}
+
+/// One node of a dbusmenu layout, `(ia{sv}av)` on the wire.
+struct Layout {
+    id: i32,
+    props: PropMap,
+    children: Vec<Layout>,
+}
+
+impl Arg for Layout {
+    const ARG_TYPE: ArgType = ArgType::Struct;
+    fn signature() -> Signature<'static> {
+        Signature::from("(ia{sv}av)")
+    }
+}
+
+impl Append for Layout {
+    fn append_by_ref(&self, ia: &mut IterAppend) {
+        ia.append_struct(|s| {
+            s.append(self.id);
+            s.append(&self.props);
+            s.append_array(&Signature::from("v"), |a| {
+                for child in &self.children {
+                    a.append(Variant(child));
+                }
+            });
+        });
+    }
+}
+
+/// Status bar for Linux desktops, speaking the freedesktop
+/// StatusNotifierItem protocol with a com.canonical.dbusmenu menu.
+///
+/// All D-Bus traffic is handled in `run()`, on the caller's thread.  Without
+/// a session bus (e.g. on a headless server) it quietly does nothing, like
+/// `DummyStatusBar`.
+pub struct LinuxStatusBar {
+    channel: Option<Channel>,
+    bus_name: String,
+    tx: Sender<String>,
+    entries: Vec<MenuEntry>,
+    callbacks: BTreeMap<i32, NSCallback>,
+    next_id: i32,
+    submenu: Option<i32>,
+    tooltip: String,
+    icon_theme_path: String,
+    revision: u32,
+    layout_dirty: bool,
+    /// Entries whose properties changed since the last signal.
+    props_dirty: BTreeSet<i32>,
+}
+
+impl TStatusBar for LinuxStatusBar {
+    type S = LinuxStatusBar;
+    fn new(tx: Sender<String>) -> LinuxStatusBar {
+        let channel = match Channel::get_private(BusType::Session) {
+            Ok(channel) => Some(channel),
+            Err(e) => {
+                warn!("No D-Bus session bus, so no status bar icon: {}", e);
+                None
+            },
+        };
+        LinuxStatusBar::on_bus(tx, channel)
+    }
+    fn can_redraw(&mut self) -> bool {
+        true
+    }
+    fn clear_items(&mut self) {
+        self.entries.clear();
+        self.callbacks.clear();
+        self.submenu = None;
+        self.layout_dirty = true;
+    }
+    fn set_tooltip(&mut self, text: &str) {
+        if self.tooltip != text {
+            self.tooltip = text.to_string();
+            self.signal(SNI_PATH, SNI_IFACE, "NewToolTip");
+        }
+    }
+    fn add_label(&mut self, label: &str) {
+        self.add_entry(label, EntryKind::Standard, false, false);
+    }
+    fn add_quit(&mut self, label: &str) {
+        self.submenu = None;
+        let id = self.add_entry(label, EntryKind::Standard, true, false);
+        // Leave the exiting to the main loop, so it can clean up.
+        self.callbacks.insert(id, Box::new(|_, tx| { let _ = tx.send(::QUIT_COMMAND.to_string()); }));
+    }
+    fn add_separator(&mut self) {
+        self.add_entry("", EntryKind::Separator, true, false);
+    }
+    fn add_item(&mut self, item: &str, callback: NSCallback, selected: bool) -> *mut Object {
+        let id = self.add_entry(item, EntryKind::Standard, true, selected);
+        self.callbacks.insert(id, callback);
+        id as *mut Object
+    }
+    fn add_submenu(&mut self, label: &str) {
+        self.submenu = None;
+        let id = self.add_entry(label, EntryKind::Submenu, true, false);
+        self.submenu = Some(id);
+    }
+    fn end_submenu(&mut self) {
+        self.submenu = None;
+    }
+    fn update_item(&mut self, item: *mut Object, label: &str) {
+        if let Some(entry) = self.entry_mut(item as i32) {
+            entry.label = label.to_string();
+        }
+        self.layout_dirty = true;
+    }
+    fn sel_item(&mut self, sender: u64) {
+        self.set_checked(sender as i32, true);
+    }
+    fn unsel_item(&mut self, sender: u64) {
+        self.set_checked(sender as i32, false);
+    }
+    fn run(&mut self, block: bool) {
+        loop {
+            self.process(match block {
+                true => Duration::from_millis(50),
+                false => Duration::from_millis(0),
+            });
+            if !block { break; }
+            if self.channel.is_none() {
+                sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+impl LinuxStatusBar {
+    fn on_bus(tx: Sender<String>, channel: Option<Channel>) -> LinuxStatusBar {
+        let instance = INSTANCES.fetch_add(1, Ordering::SeqCst) + 1;
+        let icon_theme_path = ::search_paths().into_iter()
+            .find(|dir| Path::new(dir).join(format!("{}.png", ICON_NAME)).is_file())
+            .unwrap_or_default();
+        let mut bar = LinuxStatusBar {
+            channel: channel,
+            bus_name: format!("org.kde.StatusNotifierItem-{}-{}", process::id(), instance),
+            tx: tx,
+            entries: Vec::new(),
+            callbacks: BTreeMap::new(),
+            next_id: 1,
+            submenu: None,
+            tooltip: String::new(),
+            icon_theme_path: icon_theme_path,
+            revision: 1,
+            layout_dirty: false,
+            props_dirty: BTreeSet::new(),
+        };
+        if let Err(e) = bar.register() {
+            warn!("Failed to register status bar icon on D-Bus: {}", e);
+            bar.channel = None;
+        }
+        bar
+    }
+    /// Unique bus name the item is served under.
+    pub fn bus_name(&self) -> &str {
+        &self.bus_name
+    }
+    fn register(&mut self) -> Result<(), String> {
+        let channel = match self.channel {
+            Some(ref channel) => channel,
+            None => return Ok(()),
+        };
+        if !request_name(channel, &self.bus_name)? {
+            return Err(format!("{} is taken", self.bus_name));
+        }
+        // Register again whenever a tray host (re)starts.
+        let rule = format!("type='signal',interface='org.freedesktop.DBus',member='NameOwnerChanged',arg0='{}'",
+                           WATCHER_NAME);
+        let add_match = Message::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                                 "org.freedesktop.DBus", "AddMatch")?
+            .append1(rule);
+        channel.send_with_reply_and_block(add_match, Duration::from_secs(5)).map_err(|e| e.to_string())?;
+        self.register_with_watcher();
+        Ok(())
+    }
+    fn register_with_watcher(&self) {
+        if let Some(ref channel) = self.channel {
+            // No reply needed: if there is no tray host yet, we'll hear
+            // about it from NameOwnerChanged when one starts.
+            if let Ok(mut msg) = Message::new_method_call(WATCHER_NAME, "/StatusNotifierWatcher",
+                                                          WATCHER_NAME, "RegisterStatusNotifierItem") {
+                msg.set_no_reply(true);
+                let _ = channel.send(msg.append1(&self.bus_name));
+            }
+        }
+    }
+    fn add_entry(&mut self, label: &str, kind: EntryKind, enabled: bool, checked: bool) -> i32 {
+        // IDs are never reused, so a click on a stale menu can't hit a new item.
+        let id = self.next_id;
+        self.next_id += 1;
+        let parent = self.submenu.unwrap_or(0);
+        // Join a submenu of choices, like the devices.
+        let toggle = checked || (parent != 0 && self.entries.iter().any(|e| e.parent == parent && e.toggle));
+        self.entries.push(MenuEntry {
+            id: id,
+            parent: parent,
+            label: label.to_string(),
+            kind: kind,
+            enabled: enabled,
+            checked: checked,
+            toggle: toggle,
+        });
+        if checked {
+            self.make_toggle(id);
+        }
+        self.layout_dirty = true;
+        id
+    }
+    fn entry_mut(&mut self, id: i32) -> Option<&mut MenuEntry> {
+        self.entries.iter_mut().find(|e| e.id == id)
+    }
+    /// Give `id`, and any other choices in its submenu, a checkmark.
+    fn make_toggle(&mut self, id: i32) {
+        let parent = match self.entries.iter().find(|e| e.id == id) {
+            Some(entry) => entry.parent,
+            None => return,
+        };
+        for entry in self.entries.iter_mut() {
+            if !entry.toggle && (entry.id == id || (parent != 0 && entry.parent == parent &&
+                                                    entry.kind == EntryKind::Standard)) {
+                entry.toggle = true;
+                self.props_dirty.insert(entry.id);
+            }
+        }
+    }
+    fn set_checked(&mut self, id: i32, checked: bool) {
+        self.make_toggle(id);
+        if let Some(entry) = self.entry_mut(id) {
+            entry.checked = checked;
+        }
+        if self.entries.iter().any(|e| e.id == id) {
+            self.props_dirty.insert(id);
+        }
+    }
+    fn signal(&self, path: &str, iface: &str, name: &str) {
+        if let Some(ref channel) = self.channel {
+            if let Ok(msg) = Message::new_signal(path, iface, name) {
+                let _ = channel.send(msg);
+            }
+        }
+    }
+    /// Handle D-Bus messages for up to `timeout`.
+    fn process(&mut self, timeout: Duration) {
+        self.flush_layout();
+        let messages = match self.channel {
+            Some(ref channel) => {
+                if channel.read_write(Some(timeout)).is_err() {
+                    warn!("Lost connection to D-Bus.");
+                }
+                let mut messages = Vec::new();
+                while let Some(msg) = channel.pop_message() {
+                    messages.push(msg);
+                }
+                messages
+            },
+            None => return,
+        };
+        for msg in messages {
+            self.handle(msg);
+        }
+        // Callbacks may have changed the menu.
+        self.flush_layout();
+        if let Some(ref channel) = self.channel {
+            channel.flush();
+        }
+    }
+    fn flush_layout(&mut self) {
+        if self.layout_dirty {
+            // The new layout carries the changed properties too.
+            self.props_dirty.clear();
+            self.layout_dirty = false;
+            self.revision += 1;
+            if let Some(ref channel) = self.channel {
+                if let Ok(msg) = Message::new_signal(MENU_PATH, MENU_IFACE, "LayoutUpdated") {
+                    let _ = channel.send(msg.append2(self.revision, 0i32));
+                }
+            }
+        }
+        if self.props_dirty.len() > 0 {
+            let updated: Vec<(i32, PropMap)> = self.entries.iter()
+                .filter(|e| self.props_dirty.contains(&e.id))
+                .map(|e| (e.id, e.props(&[])))
+                .collect();
+            self.props_dirty.clear();
+            if let Some(ref channel) = self.channel {
+                if let Ok(msg) = Message::new_signal(MENU_PATH, MENU_IFACE, "ItemsPropertiesUpdated") {
+                    let _ = channel.send(msg.append2(updated, Vec::<(i32, Vec<String>)>::new()));
+                }
+            }
+        }
+    }
+    fn handle(&mut self, msg: Message) {
+        match msg.msg_type() {
+            MessageType::MethodCall => {
+                let reply = self.dispatch(&msg);
+                if !msg.get_no_reply() {
+                    if let Some(ref channel) = self.channel {
+                        let _ = channel.send(reply);
+                    }
+                }
+            },
+            MessageType::Signal => {
+                if msg.member().map(|m| m.to_string()).unwrap_or_default() == "NameOwnerChanged" {
+                    if let Ok((name, _, new_owner)) = msg.read3::<String, String, String>() {
+                        if name == WATCHER_NAME && new_owner.len() > 0 {
+                            self.register_with_watcher();
+                        }
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+    fn dispatch(&mut self, msg: &Message) -> Message {
+        let path = msg.path().map(|p| p.to_string()).unwrap_or_default();
+        let iface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+        let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        match (path.as_str(), iface.as_str(), member.as_str()) {
+            (SNI_PATH, INTROSPECT_IFACE, "Introspect") => msg.method_return().append1(INTROSPECT_SNI),
+            (MENU_PATH, INTROSPECT_IFACE, "Introspect") => msg.method_return().append1(INTROSPECT_MENU),
+            (SNI_PATH, PROPS_IFACE, "Get") | (MENU_PATH, PROPS_IFACE, "Get") => {
+                let name = msg.read2::<String, String>().map(|(_, name)| name).unwrap_or_default();
+                let props = match path.as_str() {
+                    SNI_PATH => self.sni_props(),
+                    _ => self.menu_props(),
+                };
+                match props.get(&name) {
+                    Some(value) => msg.method_return().append1(value),
+                    None => error_reply(msg, "org.freedesktop.DBus.Error.UnknownProperty", &name),
+                }
+            },
+            (SNI_PATH, PROPS_IFACE, "GetAll") => msg.method_return().append1(self.sni_props()),
+            (MENU_PATH, PROPS_IFACE, "GetAll") => msg.method_return().append1(self.menu_props()),
+            // The menu is all there is; clicking the icon just opens it.
+            (SNI_PATH, SNI_IFACE, "Activate") | (SNI_PATH, SNI_IFACE, "SecondaryActivate") |
+            (SNI_PATH, SNI_IFACE, "ContextMenu") | (SNI_PATH, SNI_IFACE, "Scroll") => msg.method_return(),
+            (MENU_PATH, MENU_IFACE, "GetLayout") => {
+                let (parent, depth, names) = match msg.read3::<i32, i32, Vec<String>>() {
+                    Ok(args) => args,
+                    Err(e) => return error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs", &e.to_string()),
+                };
+                msg.method_return().append2(self.revision, self.layout(parent, depth, &names))
+            },
+            (MENU_PATH, MENU_IFACE, "GetGroupProperties") => {
+                let (ids, names) = msg.read2::<Vec<i32>, Vec<String>>().unwrap_or_default();
+                let props: Vec<(i32, PropMap)> = self.entries.iter()
+                    .filter(|e| ids.is_empty() || ids.contains(&e.id))
+                    .map(|e| (e.id, e.props(&names)))
+                    .collect();
+                msg.method_return().append1(props)
+            },
+            (MENU_PATH, MENU_IFACE, "GetProperty") => {
+                let (id, name) = msg.read2::<i32, String>().unwrap_or_default();
+                let props = self.entries.iter().find(|e| e.id == id).map(|e| e.props(&[name.clone()]));
+                match props.as_ref().and_then(|p| p.get(&name)) {
+                    Some(value) => msg.method_return().append1(value),
+                    None => error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs", &name),
+                }
+            },
+            (MENU_PATH, MENU_IFACE, "Event") => {
+                if let Ok((id, event)) = msg.read2::<i32, String>() {
+                    self.event(id, &event);
+                }
+                msg.method_return()
+            },
+            (MENU_PATH, MENU_IFACE, "EventGroup") => {
+                let events = msg.read1::<Vec<(i32, String, Variant<self::dbus::arg::Iter>, u32)>>()
+                    .map(|events| events.into_iter().map(|(id, event, _, _)| (id, event)).collect())
+                    .unwrap_or(Vec::<(i32, String)>::new());
+                let mut errors = Vec::<i32>::new();
+                for (id, event) in events {
+                    if !self.event(id, &event) {
+                        errors.push(id);
+                    }
+                }
+                msg.method_return().append1(errors)
+            },
+            (MENU_PATH, MENU_IFACE, "AboutToShow") => msg.method_return().append1(false),
+            (MENU_PATH, MENU_IFACE, "AboutToShowGroup") =>
+                msg.method_return().append2(Vec::<i32>::new(), Vec::<i32>::new()),
+            _ => error_reply(msg, "org.freedesktop.DBus.Error.UnknownMethod",
+                             &format!("{}.{} not found on {}", iface, member, path)),
+        }
+    }
+    /// Run the callback of a clicked item.  False if there's no such item.
+    fn event(&mut self, id: i32, event: &str) -> bool {
+        if !self.entries.iter().any(|e| e.id == id) {
+            return false;
+        }
+        if event == "clicked" {
+            if let Some(callback) = self.callbacks.get(&id) {
+                callback(id as u64, &self.tx);
+            }
+        }
+        true
+    }
+    fn layout(&self, parent: i32, depth: i32, names: &[String]) -> Layout {
+        let props = match self.entries.iter().find(|e| e.id == parent) {
+            Some(entry) => entry.props(names),
+            None => {
+                let mut props = PropMap::new();
+                props.insert("children-display".to_string(), Variant(Box::new("submenu".to_string())));
+                props
+            },
+        };
+        let children = match depth {
+            0 => Vec::new(),
+            _ => self.entries.iter()
+                .filter(|e| e.parent == parent)
+                .map(|e| self.layout(e.id, depth - 1, names))
+                .collect(),
+        };
+        Layout { id: parent, props: props, children: children }
+    }
+    fn sni_props(&self) -> PropMap {
+        let mut props = PropMap::new();
+        let tooltip = (String::new(), Vec::<(i32, i32, Vec<u8>)>::new(), self.tooltip.clone(), String::new());
+        props.insert("Category".to_string(), Variant(Box::new("ApplicationStatus".to_string())));
+        props.insert("Id".to_string(), Variant(Box::new("connectr".to_string())));
+        props.insert("Title".to_string(), Variant(Box::new("connectr".to_string())));
+        props.insert("Status".to_string(), Variant(Box::new("Active".to_string())));
+        props.insert("WindowId".to_string(), Variant(Box::new(0i32)));
+        props.insert("IconName".to_string(), Variant(Box::new(ICON_NAME.to_string())));
+        props.insert("IconThemePath".to_string(), Variant(Box::new(self.icon_theme_path.clone())));
+        props.insert("ToolTip".to_string(), Variant(Box::new(tooltip)));
+        props.insert("ItemIsMenu".to_string(), Variant(Box::new(true)));
+        props.insert("Menu".to_string(), Variant(Box::new(self::dbus::Path::from(MENU_PATH))));
+        props
+    }
+    fn menu_props(&self) -> PropMap {
+        let mut props = PropMap::new();
+        props.insert("Version".to_string(), Variant(Box::new(3u32)));
+        props.insert("TextDirection".to_string(), Variant(Box::new("ltr".to_string())));
+        props.insert("Status".to_string(), Variant(Box::new("normal".to_string())));
+        props.insert("IconThemePath".to_string(), Variant(Box::new(Vec::<String>::new())));
+        props
+    }
+}
+
+/// Ask for a well-known bus name.  False if someone else already has it.
+fn request_name(channel: &Channel, name: &str) -> Result<bool, String> {
+    let request = Message::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                           "org.freedesktop.DBus", "RequestName")?
+        .append2(name, 4u32); // DO_NOT_QUEUE
+    let reply = channel.send_with_reply_and_block(request, Duration::from_secs(5)).map_err(|e| e.to_string())?;
+    // 1 is PRIMARY_OWNER, 4 is ALREADY_OWNER
+    match reply.read1::<u32>() {
+        Ok(1) | Ok(4) => Ok(true),
+        Ok(_) => Ok(false),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn error_reply(msg: &Message, name: &str, text: &str) -> Message {
+    let name = self::dbus::strings::ErrorName::from(name);
+    let text = ::std::ffi::CString::new(text.replace('\0', "")).unwrap_or_default();
+    msg.error(&name, &text)
+}
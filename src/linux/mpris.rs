@@ -0,0 +1,480 @@
+use super::dbus::{Message, MessageType};
+use super::dbus::channel::{BusType, Channel};
+use super::dbus::arg::{PropMap, RefArg, Variant};
+use super::{error_reply, request_name, PROPS_IFACE, INTROSPECT_IFACE};
+
+extern crate url;
+use self::url::Url;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+
+use ::{ArtCache, SpotifyConnectr, PlayerState, PlaybackItem, SpotifyUri, SpotifyRepeat};
+use ::control;
+use ::ConnectrError;
+
+const MPRIS_PATH: &'static str = "/org/mpris/MediaPlayer2";
+const ROOT_IFACE: &'static str = "org.mpris.MediaPlayer2";
+const PLAYER_IFACE: &'static str = "org.mpris.MediaPlayer2.Player";
+const BUS_NAME: &'static str = "org.mpris.MediaPlayer2.connectr";
+const NO_TRACK: &'static str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+const ART_SIZE: u32 = 300;
+
+const INTROSPECT_MPRIS: &'static str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.mpris.MediaPlayer2">
+    <method name="Raise"/>
+    <method name="Quit"/>
+    <property name="CanQuit" type="b" access="read"/>
+    <property name="CanRaise" type="b" access="read"/>
+    <property name="HasTrackList" type="b" access="read"/>
+    <property name="Identity" type="s" access="read"/>
+    <property name="SupportedUriSchemes" type="as" access="read"/>
+    <property name="SupportedMimeTypes" type="as" access="read"/>
+  </interface>
+  <interface name="org.mpris.MediaPlayer2.Player">
+    <method name="Next"/>
+    <method name="Previous"/>
+    <method name="Pause"/>
+    <method name="PlayPause"/>
+    <method name="Stop"/>
+    <method name="Play"/>
+    <method name="Seek"><arg name="Offset" type="x" direction="in"/></method>
+    <method name="SetPosition">
+      <arg name="TrackId" type="o" direction="in"/>
+      <arg name="Position" type="x" direction="in"/>
+    </method>
+    <method name="OpenUri"><arg name="Uri" type="s" direction="in"/></method>
+    <signal name="Seeked"><arg name="Position" type="x"/></signal>
+    <property name="PlaybackStatus" type="s" access="read"/>
+    <property name="LoopStatus" type="s" access="readwrite"/>
+    <property name="Rate" type="d" access="readwrite"/>
+    <property name="Shuffle" type="b" access="readwrite"/>
+    <property name="Metadata" type="a{sv}" access="read"/>
+    <property name="Volume" type="d" access="readwrite"/>
+    <property name="Position" type="x" access="read"/>
+    <property name="MinimumRate" type="d" access="read"/>
+    <property name="MaximumRate" type="d" access="read"/>
+    <property name="CanGoNext" type="b" access="read"/>
+    <property name="CanGoPrevious" type="b" access="read"/>
+    <property name="CanPlay" type="b" access="read"/>
+    <property name="CanPause" type="b" access="read"/>
+    <property name="CanSeek" type="b" access="read"/>
+    <property name="CanControl" type="b" access="read"/>
+  </interface>
+</node>"#;
+
+/// MPRIS2 media player service for the active Spotify Connect device, so
+/// media keys, desktop media widgets and `playerctl` can control it like a
+/// local player.
+///
+/// Commands are sent to Spotify from `process()`, on the caller's thread.
+/// Player state comes from whatever the caller last passed to `update()`;
+/// changes are announced with `PropertiesChanged`.  Album art is downloaded
+/// into an `ArtCache` and offered as a local file, since many clients can't
+/// fetch remote art themselves.
+pub struct MprisServer {
+    channel: Option<Channel>,
+    bus_name: String,
+    state: Option<PlayerState>,
+    /// When `state` was fetched, to estimate the playback position since.
+    updated: Instant,
+    /// Player properties as last announced, in Debug form for comparison.
+    announced: BTreeMap<String, String>,
+    art: Option<ArtCache>,
+    /// Remote URL and local path of the current item's art, once fetched.
+    art_file: Option<(String, PathBuf)>,
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<RefArg>> {
+    Variant(Box::new(value))
+}
+
+/// D-Bus object path identifying the track, as MPRIS wants.
+fn track_path(item: &PlaybackItem) -> String {
+    match item.id() {
+        Some(id) if id.len() > 0 && id.chars().all(|c| c.is_ascii() && c.is_alphanumeric()) =>
+            format!("/org/connectr/track/{}", id),
+        _ => NO_TRACK.to_string(),
+    }
+}
+
+/// Metadata of `item`, with its art at `art_file` if that has been fetched.
+fn metadata(item: &PlaybackItem, art_file: Option<&(String, PathBuf)>) -> PropMap {
+    let mut map = PropMap::new();
+    map.insert("mpris:trackid".to_string(), variant(super::dbus::Path::from(track_path(item))));
+    map.insert("mpris:length".to_string(), variant(item.duration_ms() as i64 * 1000));
+    map.insert("xesam:title".to_string(), variant(item.name().to_string()));
+    map.insert("xesam:album".to_string(), variant(item.album_or_publisher().to_string()));
+    if let Some(image) = item.image(ART_SIZE) {
+        let local = art_file
+            .filter(|&&(ref url, _)| *url == image.url)
+            .and_then(|&(_, ref path)| Url::from_file_path(path).ok());
+        let art_url = match local {
+            Some(url) => url.to_string(),
+            None => image.url.clone(),
+        };
+        map.insert("mpris:artUrl".to_string(), variant(art_url));
+    }
+    if let Ok(uri) = item.uri().parse::<SpotifyUri>() {
+        map.insert("xesam:url".to_string(), variant(uri.url()));
+    }
+    match *item {
+        PlaybackItem::Track(ref track) => {
+            let artists: Vec<String> = track.artists.iter().map(|a| a.name.clone()).collect();
+            let album_artists: Vec<String> = track.album.artists.iter().map(|a| a.name.clone()).collect();
+            map.insert("xesam:artist".to_string(), variant(artists));
+            map.insert("xesam:albumArtist".to_string(), variant(album_artists));
+            if let Some(n) = track.track_number {
+                map.insert("xesam:trackNumber".to_string(), variant(n as i32));
+            }
+            if let Some(n) = track.disc_number {
+                map.insert("xesam:discNumber".to_string(), variant(n as i32));
+            }
+        },
+        PlaybackItem::Episode(_) => {
+            map.insert("xesam:artist".to_string(), variant(vec![item.artist_or_show()]));
+        },
+    }
+    map
+}
+
+impl MprisServer {
+    /// Serve on the session bus, with art cached in `~/.connectr.art`.
+    /// Without a bus, this does nothing.
+    pub fn new() -> MprisServer {
+        let channel = match Channel::get_private(BusType::Session) {
+            Ok(channel) => Some(channel),
+            Err(e) => {
+                warn!("No D-Bus session bus, so no MPRIS player: {}", e);
+                None
+            },
+        };
+        MprisServer::on_bus(channel).with_art_cache(ArtCache::default())
+    }
+    /// Offer art from `cache`, downloading into it as needed.  Without a
+    /// cache, clients are given Spotify's URLs.
+    pub fn with_art_cache(mut self, cache: ArtCache) -> MprisServer {
+        self.art = Some(cache);
+        self
+    }
+    pub(super) fn on_bus(channel: Option<Channel>) -> MprisServer {
+        let mut server = MprisServer {
+            channel: channel,
+            bus_name: BUS_NAME.to_string(),
+            state: None,
+            updated: Instant::now(),
+            announced: BTreeMap::new(),
+            art: None,
+            art_file: None,
+        };
+        if let Err(e) = server.register() {
+            warn!("Failed to register MPRIS player on D-Bus: {}", e);
+            server.channel = None;
+        }
+        server
+    }
+    /// Bus name the player is served under.
+    pub fn bus_name(&self) -> &str {
+        &self.bus_name
+    }
+    fn register(&mut self) -> Result<(), String> {
+        let channel = match self.channel {
+            Some(ref channel) => channel,
+            None => return Ok(()),
+        };
+        if request_name(channel, BUS_NAME)? {
+            return Ok(());
+        }
+        // Another connectr is running.  MPRIS allows one name per instance.
+        self.bus_name = format!("{}.instance{}", BUS_NAME, process::id());
+        match request_name(channel, &self.bus_name)? {
+            true => Ok(()),
+            false => Err(format!("{} is taken", self.bus_name)),
+        }
+    }
+    /// Replace the player state, and announce whatever changed.
+    /// Art for the new item is downloaded with `spotify` first.
+    pub fn update(&mut self, state: Option<&PlayerState>, spotify: &SpotifyConnectr) {
+        let expected = self.position();
+        let same_item = match (self.state.as_ref(), state) {
+            (Some(old), Some(new)) => old.item.as_ref().map(|i| i.uri()) == new.item.as_ref().map(|i| i.uri()),
+            _ => false,
+        };
+        self.state = state.cloned();
+        self.updated = Instant::now();
+        self.fetch_art(spotify);
+
+        let mut changed = PropMap::new();
+        for (name, value) in self.player_props() {
+            if name == "Position" {
+                continue;
+            }
+            let debug = format!("{:?}", value);
+            if self.announced.get(&name) != Some(&debug) {
+                self.announced.insert(name.clone(), debug);
+                changed.insert(name, value);
+            }
+        }
+        if let Some(ref channel) = self.channel {
+            if changed.len() > 0 {
+                if let Ok(msg) = Message::new_signal(MPRIS_PATH, PROPS_IFACE, "PropertiesChanged") {
+                    let _ = channel.send(msg.append3(PLAYER_IFACE, changed, Vec::<String>::new()));
+                }
+            }
+            // Position isn't announced, so tell clients when it jumps.
+            let position = self.position();
+            if same_item && (position - expected).abs() > 2_000_000 {
+                if let Ok(msg) = Message::new_signal(MPRIS_PATH, PLAYER_IFACE, "Seeked") {
+                    let _ = channel.send(msg.append1(position));
+                }
+            }
+            channel.flush();
+        }
+    }
+    fn fetch_art(&mut self, spotify: &SpotifyConnectr) {
+        let url = match self.state.as_ref().and_then(|s| s.item.as_ref()).and_then(|i| i.image(ART_SIZE)) {
+            Some(image) => image.url.clone(),
+            None => return,
+        };
+        if self.art_file.as_ref().map(|&(ref u, _)| *u == url).unwrap_or(false) {
+            return;
+        }
+        if let Some(ref mut cache) = self.art {
+            match spotify.fetch_image(cache, &url) {
+                Ok(path) => self.art_file = Some((url, path)),
+                Err(e) => warn!("Failed to fetch album art: {}", e),
+            }
+        }
+    }
+    /// Handle D-Bus messages for up to `timeout`, sending any commands to
+    /// `spotify`.  Returns true if a command was sent, so the caller should
+    /// refresh the player state soon.
+    pub fn process(&mut self, spotify: &mut SpotifyConnectr, timeout: Duration) -> bool {
+        let messages = match self.channel {
+            Some(ref channel) => {
+                if channel.read_write(Some(timeout)).is_err() {
+                    warn!("Lost connection to D-Bus.");
+                }
+                let mut messages = Vec::new();
+                while let Some(msg) = channel.pop_message() {
+                    messages.push(msg);
+                }
+                messages
+            },
+            None => return false,
+        };
+        let mut commanded = false;
+        for msg in messages {
+            if msg.msg_type() != MessageType::MethodCall {
+                continue;
+            }
+            let (reply, sent) = self.dispatch(&msg, spotify);
+            commanded = commanded || sent;
+            if !msg.get_no_reply() {
+                if let Some(ref channel) = self.channel {
+                    let _ = channel.send(reply);
+                }
+            }
+        }
+        if let Some(ref channel) = self.channel {
+            channel.flush();
+        }
+        commanded
+    }
+    /// Reply to a method call, and whether a command was sent to Spotify.
+    fn dispatch(&mut self, msg: &Message, spotify: &mut SpotifyConnectr) -> (Message, bool) {
+        let path = msg.path().map(|p| p.to_string()).unwrap_or_default();
+        let iface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+        let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        if path != MPRIS_PATH {
+            return (error_reply(msg, "org.freedesktop.DBus.Error.UnknownObject", &path), false);
+        }
+        let result = match (iface.as_str(), member.as_str()) {
+            (INTROSPECT_IFACE, "Introspect") => return (msg.method_return().append1(INTROSPECT_MPRIS), false),
+            (PROPS_IFACE, "Get") => {
+                let (iface, name) = msg.read2::<String, String>().unwrap_or_default();
+                let props = match iface.as_str() {
+                    ROOT_IFACE => self.root_props(),
+                    _ => self.player_props(),
+                };
+                return match props.get(&name) {
+                    Some(value) => (msg.method_return().append1(value), false),
+                    None => (error_reply(msg, "org.freedesktop.DBus.Error.UnknownProperty", &name), false),
+                };
+            },
+            (PROPS_IFACE, "GetAll") => {
+                let iface = msg.read1::<String>().unwrap_or_default();
+                let props = match iface.as_str() {
+                    ROOT_IFACE => self.root_props(),
+                    PLAYER_IFACE => self.player_props(),
+                    _ => PropMap::new(),
+                };
+                return (msg.method_return().append1(props), false);
+            },
+            (PROPS_IFACE, "Set") => self.set_property(msg, spotify),
+            // There's no window to raise, and quitting is the menu's job.
+            (ROOT_IFACE, "Raise") | (ROOT_IFACE, "Quit") => return (msg.method_return(), false),
+            (PLAYER_IFACE, "Next") => spotify.next(),
+            (PLAYER_IFACE, "Previous") => spotify.previous(),
+            (PLAYER_IFACE, "Pause") | (PLAYER_IFACE, "Stop") => spotify.pause(),
+            (PLAYER_IFACE, "Play") => spotify.play(None),
+            (PLAYER_IFACE, "PlayPause") => match self.state.as_ref().map(|s| s.is_playing).unwrap_or(false) {
+                true => spotify.pause(),
+                false => spotify.play(None),
+            },
+            (PLAYER_IFACE, "Seek") => {
+                let offset = msg.read1::<i64>().unwrap_or(0);
+                let position = self.position() + offset;
+                match self.length() {
+                    Some(length) if position > length => spotify.next(),
+                    _ => spotify.seek((::std::cmp::max(position, 0) / 1000) as u32),
+                }
+            },
+            (PLAYER_IFACE, "SetPosition") => {
+                let (track, position) = match msg.read2::<super::dbus::Path, i64>() {
+                    Ok(args) => args,
+                    Err(e) => return (error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs", &e.to_string()), false),
+                };
+                let current = self.state.as_ref().and_then(|s| s.item.as_ref()).map(track_path);
+                match (current, self.length()) {
+                    // Ignored unless it's for the current track, as MPRIS says.
+                    (Some(ref current), Some(length)) if current.as_str() == &*track &&
+                        position >= 0 && position <= length => spotify.seek((position / 1000) as u32),
+                    _ => return (msg.method_return(), false),
+                }
+            },
+            (PLAYER_IFACE, "OpenUri") => {
+                let uri = msg.read1::<String>().map_err(|e| e.to_string())
+                    .and_then(|uri| uri.parse::<SpotifyUri>().map_err(|e| e.to_string()));
+                let result = match uri {
+                    Ok(uri) => control::play_uri(spotify, &uri),
+                    Err(e) => return (error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs", &e), false),
+                };
+                match result {
+                    // e.g. user URIs, which can't be played.
+                    Err(ConnectrError::InvalidUri(e)) =>
+                        return (error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs", &e), false),
+                    result => result,
+                }
+            },
+            _ => return (error_reply(msg, "org.freedesktop.DBus.Error.UnknownMethod",
+                                     &format!("{}.{} not found", iface, member)), false),
+        };
+        match result {
+            Ok(_) => (msg.method_return(), true),
+            Err(e) => {
+                warn!("MPRIS {} failed: {}", member, e);
+                (error_reply(msg, "org.freedesktop.DBus.Error.Failed", &e.to_string()), false)
+            },
+        }
+    }
+    fn set_property(&mut self, msg: &Message, spotify: &mut SpotifyConnectr) -> Result<(), ConnectrError> {
+        let mut args = msg.iter_init();
+        let _iface: String = args.read().unwrap_or_default();
+        let name: String = args.read().unwrap_or_default();
+        let value = args.read::<Variant<super::dbus::arg::Iter>>().ok().and_then(|mut v| v.0.get_refarg());
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        match name.as_str() {
+            "Volume" => match value.as_f64() {
+                Some(volume) => {
+                    let volume = (volume.max(0.0).min(1.0) * 100.0).round() as u32;
+                    spotify.volume(volume)
+                },
+                None => Ok(()),
+            },
+            "Shuffle" => match value.as_u64() {
+                Some(shuffle) => spotify.shuffle(shuffle != 0),
+                None => Ok(()),
+            },
+            "LoopStatus" => match value.as_str() {
+                Some("None") => spotify.repeat(SpotifyRepeat::Off),
+                Some("Track") => spotify.repeat(SpotifyRepeat::Track),
+                Some("Playlist") => spotify.repeat(SpotifyRepeat::Context),
+                _ => Ok(()),
+            },
+            // Rate can only be 1.0
+            _ => Ok(()),
+        }
+    }
+    /// Estimated playback position, in microseconds.
+    fn position(&self) -> i64 {
+        let state = match self.state {
+            Some(ref state) => state,
+            None => return 0,
+        };
+        let mut position = state.progress_ms.unwrap_or(0) as i64 * 1000;
+        if state.is_playing {
+            let elapsed = self.updated.elapsed();
+            position += elapsed.as_secs() as i64 * 1_000_000 + elapsed.subsec_nanos() as i64 / 1000;
+        }
+        match self.length() {
+            Some(length) if position > length => length,
+            _ => position,
+        }
+    }
+    /// Length of the current item, in microseconds.
+    fn length(&self) -> Option<i64> {
+        self.state.as_ref()
+            .and_then(|s| s.item.as_ref())
+            .map(|item| item.duration_ms() as i64 * 1000)
+    }
+    fn root_props(&self) -> PropMap {
+        let mut props = PropMap::new();
+        props.insert("CanQuit".to_string(), variant(false));
+        props.insert("CanRaise".to_string(), variant(false));
+        props.insert("HasTrackList".to_string(), variant(false));
+        props.insert("Identity".to_string(), variant("connectr".to_string()));
+        props.insert("SupportedUriSchemes".to_string(), variant(vec!["spotify".to_string()]));
+        props.insert("SupportedMimeTypes".to_string(), variant(Vec::<String>::new()));
+        props
+    }
+    fn player_props(&self) -> PropMap {
+        let state = self.state.as_ref();
+        let allowed = |action: &str| state.map(|s| !s.is_disallowed(action)).unwrap_or(false);
+        let has_item = state.map(|s| s.item.is_some()).unwrap_or(false);
+        let status = match state {
+            Some(s) if s.is_playing => "Playing",
+            Some(_) => "Paused",
+            None => "Stopped",
+        };
+        let loop_status = match state.map(|s| s.repeat_state.as_str()) {
+            Some("track") => "Track",
+            Some("context") => "Playlist",
+            _ => "None",
+        };
+        let volume = state.and_then(|s| s.device.volume_percent).unwrap_or(0) as f64 / 100.0;
+        let metadata = match state.and_then(|s| s.item.as_ref()) {
+            Some(item) => metadata(item, self.art_file.as_ref()),
+            None => {
+                let mut map = PropMap::new();
+                map.insert("mpris:trackid".to_string(), variant(super::dbus::Path::from(NO_TRACK)));
+                map
+            },
+        };
+
+        let mut props = PropMap::new();
+        props.insert("PlaybackStatus".to_string(), variant(status.to_string()));
+        props.insert("LoopStatus".to_string(), variant(loop_status.to_string()));
+        props.insert("Rate".to_string(), variant(1.0f64));
+        props.insert("MinimumRate".to_string(), variant(1.0f64));
+        props.insert("MaximumRate".to_string(), variant(1.0f64));
+        props.insert("Shuffle".to_string(), variant(state.map(|s| s.shuffle_state).unwrap_or(false)));
+        props.insert("Metadata".to_string(), variant(metadata));
+        props.insert("Volume".to_string(), variant(volume));
+        props.insert("Position".to_string(), variant(self.position()));
+        props.insert("CanGoNext".to_string(), variant(allowed("skipping_next")));
+        props.insert("CanGoPrevious".to_string(), variant(allowed("skipping_prev")));
+        props.insert("CanPlay".to_string(), variant(state.is_some()));
+        props.insert("CanPause".to_string(), variant(allowed("pausing")));
+        props.insert("CanSeek".to_string(), variant(has_item && allowed("seeking")));
+        props.insert("CanControl".to_string(), variant(true));
+        props
+    }
+}
@@ -0,0 +1,418 @@
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::super::*;
+    use ::webapi::fixtures::scripted_spotify;
+    use super::super::dbus::Message;
+    use super::super::dbus::channel::Channel;
+    use super::super::dbus::arg::{RefArg, Variant};
+
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A private session bus, killed when dropped.
+    struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Start a private session bus.  Tests that need one are `#[ignore]`d,
+    /// since CI machines rarely have dbus-daemon; run them with
+    /// `cargo test -- --ignored`.
+    fn test_bus() -> TestBus {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(&["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is needed for the D-Bus tests");
+        let mut address = String::new();
+        if let Some(stdout) = daemon.stdout.take() {
+            let _ = BufReader::new(stdout).read_line(&mut address);
+        }
+        let address = address.trim().to_string();
+        if address.len() == 0 {
+            let _ = daemon.kill();
+            panic!("dbus-daemon didn't print its address");
+        }
+        TestBus { daemon: daemon, address: address }
+    }
+
+    fn connect(bus: &TestBus) -> Channel {
+        let mut channel = Channel::open_private(&bus.address).unwrap();
+        channel.register().unwrap();
+        channel
+    }
+
+    /// Run `client` on another thread, calling `process` until it's done.
+    fn serve<T, F>(process: &mut FnMut(), client: F) -> T
+        where T: Send + 'static, F: FnOnce() -> T + Send + 'static {
+        let (tx, rx) = channel();
+        thread::spawn(move || { let _ = tx.send(client()); });
+        let start = Instant::now();
+        loop {
+            process();
+            if let Ok(result) = rx.try_recv() {
+                return result;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "never answered");
+        }
+    }
+
+    fn call(address: &str, dest: &str, path: &str, iface: &str, member: &str,
+            build: fn(Message) -> Message) -> Message {
+        try_call(address, dest, path, iface, member, build).unwrap()
+    }
+
+    /// Like `call()`, but error replies are returned instead of failing.
+    fn try_call(address: &str, dest: &str, path: &str, iface: &str, member: &str,
+                build: fn(Message) -> Message) -> Result<Message, super::super::dbus::Error> {
+        let mut client = Channel::open_private(address).unwrap();
+        client.register().unwrap();
+        let msg = build(Message::new_method_call(dest, path, iface, member).unwrap());
+        client.send_with_reply_and_block(msg, Duration::from_secs(5))
+    }
+
+    /// Every string in a reply, depth first.
+    fn strings(arg: &RefArg, out: &mut Vec<String>) {
+        if let Some(s) = arg.as_str() {
+            out.push(s.to_string());
+        }
+        else if let Some(iter) = arg.as_iter() {
+            for a in iter {
+                strings(a, out);
+            }
+        }
+    }
+
+    fn reply_strings(msg: &Message) -> Vec<String> {
+        let mut out = Vec::new();
+        for arg in msg.iter_init() {
+            strings(&arg, &mut out);
+        }
+        out
+    }
+
+    fn test_menu(bar: &mut LinuxStatusBar) -> (*mut Object, *mut Object) {
+        bar.add_label("Now Playing:");
+        bar.add_separator();
+        let play = bar.add_item("Play", Box::new(|sender, tx: &Sender<String>| {
+            let _ = tx.send(format!("clicked {}", sender));
+        }), false);
+        bar.add_submenu("Devices");
+        let device = bar.add_item("Living_Room", Box::new(|_, _| {}), true);
+        bar.end_submenu();
+        bar.add_quit("Exit");
+        (play, device)
+    }
+
+    fn layout(bar: &mut LinuxStatusBar, address: &str) -> Vec<String> {
+        let address = address.to_string();
+        let name = bar.bus_name().to_string();
+        let reply = serve(&mut || bar.process(Duration::from_millis(10)), move || {
+            call(&address, &name, "/MenuBar", "com.canonical.dbusmenu", "GetLayout",
+                 |m| m.append3(0i32, -1i32, Vec::<String>::new()))
+        });
+        reply_strings(&reply)
+    }
+
+    #[test]
+    #[ignore]
+    fn test_dbusmenu_layout() {
+        let bus = test_bus();
+        let (tx, _rx): (Sender<String>, Receiver<String>) = channel();
+        let mut bar = LinuxStatusBar::on_bus(tx, Some(connect(&bus)));
+        let (play, device) = test_menu(&mut bar);
+
+        let strings = layout(&mut bar, &bus.address);
+        let labels: Vec<&String> = strings.iter().enumerate()
+            .filter(|&(i, _)| i > 0 && strings[i - 1] == "label")
+            .map(|(_, s)| s)
+            .collect();
+        assert_eq!(labels, vec!["Now Playing:", "Play", "Devices", "Living__Room", "Exit"]);
+        assert!(strings.contains(&"separator".to_string()));
+        assert!(strings.contains(&"checkmark".to_string()));
+        assert!(strings.contains(&"children-display".to_string()));
+
+        bar.update_item(play, "Pause");
+        bar.unsel_item(device as u64);
+        let strings = layout(&mut bar, &bus.address);
+        assert!(strings.contains(&"Pause".to_string()));
+        assert!(!strings.contains(&"Play".to_string()));
+        // Unchecked, but still a checkmark item.
+        assert!(strings.contains(&"checkmark".to_string()));
+
+        bar.clear_items();
+        bar.add_label("Nothing");
+        let strings = layout(&mut bar, &bus.address);
+        assert!(strings.contains(&"Nothing".to_string()));
+        assert!(!strings.contains(&"Pause".to_string()));
+    }
+
+    fn toggle_state(bar: &LinuxStatusBar, item: *mut Object) -> Option<i64> {
+        let entry = bar.entries.iter().find(|e| e.id == item as i32).unwrap();
+        let props = entry.props(&[]);
+        match props.get("toggle-type") {
+            Some(_) => props.get("toggle-state").and_then(|v| v.0.as_i64()),
+            None => None,
+        }
+    }
+
+    #[test]
+    fn test_dbusmenu_toggle() {
+        let (tx, _rx) = channel();
+        let mut bar = LinuxStatusBar::on_bus(tx, None);
+        let (play, device) = test_menu(&mut bar);
+        bar.add_submenu("Volume");
+        let low = bar.add_item("10%", Box::new(|_, _| {}), false);
+        let high = bar.add_item("20%", Box::new(|_, _| {}), false);
+        bar.end_submenu();
+        bar.process(Duration::from_millis(0));
+        assert_eq!(toggle_state(&bar, play), None);
+        assert_eq!(toggle_state(&bar, device), Some(1));
+        assert_eq!(toggle_state(&bar, low), None);
+
+        // Selecting one choice makes its siblings checkmark items too, and
+        // only their properties are sent again.
+        bar.sel_item(high as u64);
+        bar.unsel_item(device as u64);
+        assert!(!bar.layout_dirty);
+        assert_eq!(bar.props_dirty.iter().cloned().collect::<Vec<i32>>(),
+                   vec![device as i32, low as i32, high as i32]);
+        assert_eq!(toggle_state(&bar, device), Some(0));
+        assert_eq!(toggle_state(&bar, low), Some(0));
+        assert_eq!(toggle_state(&bar, high), Some(1));
+        bar.process(Duration::from_millis(0));
+        assert!(bar.props_dirty.is_empty());
+    }
+
+    #[test]
+    fn test_dbusmenu_quit() {
+        let (tx, rx) = channel();
+        let mut bar = LinuxStatusBar::on_bus(tx, None);
+        test_menu(&mut bar);
+        let quit = bar.entries.iter().find(|e| e.label == "Exit").unwrap().id;
+        assert!(bar.event(quit, "clicked"));
+        assert_eq!(rx.try_recv().unwrap(), ::QUIT_COMMAND);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_dbusmenu_click() {
+        let bus = test_bus();
+        let (tx, rx) = channel();
+        let mut bar = LinuxStatusBar::on_bus(tx, Some(connect(&bus)));
+        let (play, _) = test_menu(&mut bar);
+        let id = play as i32;
+
+        let address = bus.address.clone();
+        let name = bar.bus_name().to_string();
+        serve(&mut || bar.process(Duration::from_millis(10)), move || {
+            let mut client = Channel::open_private(&address).unwrap();
+            client.register().unwrap();
+            let msg = Message::new_method_call(&name, "/MenuBar", "com.canonical.dbusmenu", "Event").unwrap()
+                .append3(id, "clicked", Variant(0i32))
+                .append1(0u32);
+            client.send_with_reply_and_block(msg, Duration::from_secs(5)).unwrap();
+        });
+        assert_eq!(rx.try_recv().unwrap(), format!("clicked {}", id));
+
+        // Hovering doesn't trigger anything.
+        let address = bus.address.clone();
+        let name = bar.bus_name().to_string();
+        serve(&mut || bar.process(Duration::from_millis(10)), move || {
+            let mut client = Channel::open_private(&address).unwrap();
+            client.register().unwrap();
+            let msg = Message::new_method_call(&name, "/MenuBar", "com.canonical.dbusmenu", "Event").unwrap()
+                .append3(id, "hovered", Variant(0i32))
+                .append1(0u32);
+            client.send_with_reply_and_block(msg, Duration::from_secs(5)).unwrap();
+        });
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_status_notifier_item() {
+        let bus = test_bus();
+        let (tx, _rx): (Sender<String>, Receiver<String>) = channel();
+        let mut bar = LinuxStatusBar::on_bus(tx, Some(connect(&bus)));
+        bar.set_tooltip("Song - Artist");
+
+        let address = bus.address.clone();
+        let name = bar.bus_name().to_string();
+        let (menu, tooltip) = serve(&mut || bar.process(Duration::from_millis(10)), move || {
+            let menu = call(&address, &name, "/StatusNotifierItem", "org.freedesktop.DBus.Properties", "Get",
+                            |m| m.append2("org.kde.StatusNotifierItem", "Menu"));
+            let tooltip = call(&address, &name, "/StatusNotifierItem", "org.freedesktop.DBus.Properties", "Get",
+                               |m| m.append2("org.kde.StatusNotifierItem", "ToolTip"));
+            (reply_strings(&menu), reply_strings(&tooltip))
+        });
+        assert_eq!(menu, vec!["/MenuBar"]);
+        assert!(tooltip.contains(&"Song - Artist".to_string()));
+    }
+
+    fn player_state(is_playing: bool, name: &str) -> ::PlayerState {
+        serde_json::from_str(&format!(r#"{{"timestamp": 1, "progress_ms": 1000, "is_playing": {},
+            "shuffle_state": true, "repeat_state": "context", "context": null,
+            "currently_playing_type": "track",
+            "actions": {{"disallows": {{"skipping_prev": true}}}},
+            "device": {{"id": "abc123", "is_active": true, "is_restricted": false, "name": "Kitchen",
+                "type": "Speaker", "volume_percent": 40}},
+            "item": {{"type": "track", "id": "6rqhFgbbKwnb9MLmUQDhG6", "name": "{}",
+                "uri": "spotify:track:6rqhFgbbKwnb9MLmUQDhG6", "duration_ms": 60000,
+                "artists": [{{"name": "Sebadoh", "uri": "spotify:artist:d"}}],
+                "album": {{"name": "Bakesale", "uri": "spotify:album:b",
+                    "images": [{{"url": "https://i.scdn.co/image/b", "height": 300, "width": 300}}]}}}}}}"#,
+            is_playing, name)).unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    fn test_mpris_properties() {
+        let bus = test_bus();
+        let (mut spotify, _transport) = scripted_spotify();
+        let mut mpris = mpris::MprisServer::on_bus(Some(connect(&bus)));
+        assert_eq!(mpris.bus_name(), "org.mpris.MediaPlayer2.connectr");
+        mpris.update(Some(&player_state(true, "Skull")), &spotify);
+
+        let address = bus.address.clone();
+        let props = serve(&mut || { mpris.process(&mut spotify, Duration::from_millis(10)); }, move || {
+            let reply = call(&address, "org.mpris.MediaPlayer2.connectr", "/org/mpris/MediaPlayer2",
+                             "org.freedesktop.DBus.Properties", "GetAll",
+                             |m| m.append1("org.mpris.MediaPlayer2.Player"));
+            reply_strings(&reply)
+        });
+        for expected in &["Playing", "Playlist", "Skull", "Sebadoh", "Bakesale", "https://i.scdn.co/image/b",
+                          "https://open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6",
+                          "/org/connectr/track/6rqhFgbbKwnb9MLmUQDhG6"] {
+            assert!(props.contains(&expected.to_string()), "missing {}", expected);
+        }
+
+        // A second player gets its own name.
+        let other = mpris::MprisServer::on_bus(Some(connect(&bus)));
+        assert!(other.bus_name().starts_with("org.mpris.MediaPlayer2.connectr.instance"));
+    }
+
+    #[test]
+    #[ignore]
+    fn test_mpris_local_art() {
+        let bus = test_bus();
+        let dir = ::std::env::temp_dir().join(format!("connectr-mpris-art-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_dir_all(&dir);
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_bytes(200, "image/jpeg", &[0xff, 0xd8, 0xff, 0x00]);
+        let mut mpris = mpris::MprisServer::on_bus(Some(connect(&bus)))
+            .with_art_cache(::ArtCache::new(&dir, 1024));
+        mpris.update(Some(&player_state(true, "Skull")), &spotify);
+        // Same art, so not fetched again.
+        mpris.update(Some(&player_state(false, "Skull")), &spotify);
+        assert_eq!(transport.requests().len(), 1);
+        assert_eq!(transport.requests()[0].url, "https://i.scdn.co/image/b");
+
+        let address = bus.address.clone();
+        let props = serve(&mut || { mpris.process(&mut spotify, Duration::from_millis(10)); }, move || {
+            let reply = call(&address, "org.mpris.MediaPlayer2.connectr", "/org/mpris/MediaPlayer2",
+                             "org.freedesktop.DBus.Properties", "GetAll",
+                             |m| m.append1("org.mpris.MediaPlayer2.Player"));
+            reply_strings(&reply)
+        });
+        let prefix = format!("file://{}/", dir.display());
+        assert!(props.iter().any(|p| p.starts_with(&prefix) && p.ends_with(".jpg")), "no local art in {:?}", props);
+        let _ = ::std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_mpris_commands() {
+        let bus = test_bus();
+        let (mut spotify, transport) = scripted_spotify();
+        let mut mpris = mpris::MprisServer::on_bus(Some(connect(&bus)));
+        mpris.update(Some(&player_state(true, "Skull")), &spotify);
+        transport.push_response(204, "");
+        transport.push_response(204, "");
+        transport.push_response(204, "");
+
+        let address = bus.address.clone();
+        let mut commanded = false;
+        serve(&mut || { commanded |= mpris.process(&mut spotify, Duration::from_millis(10)); }, move || {
+            call(&address, "org.mpris.MediaPlayer2.connectr", "/org/mpris/MediaPlayer2",
+                 "org.mpris.MediaPlayer2.Player", "PlayPause", |m| m);
+            call(&address, "org.mpris.MediaPlayer2.connectr", "/org/mpris/MediaPlayer2",
+                 "org.freedesktop.DBus.Properties", "Set",
+                 |m| m.append3("org.mpris.MediaPlayer2.Player", "Volume", Variant(0.5f64)));
+            call(&address, "org.mpris.MediaPlayer2.connectr", "/org/mpris/MediaPlayer2",
+                 "org.mpris.MediaPlayer2.Player", "OpenUri",
+                 |m| m.append1("https://open.spotify.com/album/4c8eKK6kKrcdt1HToEX7Jc"));
+        });
+        assert!(commanded);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].url.ends_with("/pause"));
+        assert_eq!(requests[1].query, "volume_percent=50");
+        assert!(requests[2].url.ends_with("/play"));
+        assert!(requests[2].body.contains("spotify:album:4c8eKK6kKrcdt1HToEX7Jc"));
+    }
+
+    #[test]
+    #[ignore]
+    fn test_mpris_open_unplayable_uri() {
+        let bus = test_bus();
+        let (mut spotify, transport) = scripted_spotify();
+        let mut mpris = mpris::MprisServer::on_bus(Some(connect(&bus)));
+
+        let address = bus.address.clone();
+        let error = serve(&mut || { mpris.process(&mut spotify, Duration::from_millis(10)); }, move || {
+            try_call(&address, "org.mpris.MediaPlayer2.connectr", "/org/mpris/MediaPlayer2",
+                     "org.mpris.MediaPlayer2.Player", "OpenUri",
+                     |m| m.append1("spotify:user:mrmekon")).err().unwrap()
+        });
+        assert_eq!(error.name(), Some("org.freedesktop.DBus.Error.InvalidArgs"));
+        assert_eq!(transport.requests().len(), 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_mpris_properties_changed() {
+        let bus = test_bus();
+        let (spotify, _transport) = scripted_spotify();
+        let mut mpris = mpris::MprisServer::on_bus(Some(connect(&bus)));
+        mpris.update(Some(&player_state(true, "Skull")), &spotify);
+
+        let client = connect(&bus);
+        let rule = "type='signal',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'";
+        let add_match = Message::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                                 "org.freedesktop.DBus", "AddMatch").unwrap()
+            .append1(rule);
+        client.send_with_reply_and_block(add_match, Duration::from_secs(5)).unwrap();
+
+        mpris.update(Some(&player_state(false, "Magnificent Cardinal")), &spotify);
+        let start = Instant::now();
+        let changed = loop {
+            client.read_write(Some(Duration::from_millis(50))).unwrap();
+            if let Some(msg) = client.pop_message() {
+                if msg.member().map(|m| m.to_string()) == Some("PropertiesChanged".to_string()) {
+                    break reply_strings(&msg);
+                }
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "no PropertiesChanged");
+        };
+        assert!(changed.contains(&"PlaybackStatus".to_string()));
+        assert!(changed.contains(&"Paused".to_string()));
+        assert!(changed.contains(&"Magnificent Cardinal".to_string()));
+        // Unchanged properties aren't repeated.
+        assert!(!changed.contains(&"LoopStatus".to_string()));
+    }
+}
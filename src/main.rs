@@ -1,5 +1,5 @@
 extern crate connectr;
-use connectr::SpotifyResponse;
+use connectr::ConnectrError;
 use connectr::TStatusBar;
 use connectr::MenuItem;
 use connectr::NSCallback;
@@ -29,6 +29,9 @@ use std::process;
 // How often to refresh Spotify state (if nothing triggers a refresh earlier).
 pub const REFRESH_PERIOD: i64 = 30;
 
+// How many queued tracks to show in the 'Up Next' section.
+const UP_NEXT_COUNT: usize = 5;
+
 #[derive(Serialize, Deserialize, Debug)]
 enum CallbackAction {
     SelectDevice,
@@ -37,6 +40,9 @@ enum CallbackAction {
     SkipPrev,
     Volume,
     Preset,
+    SelectAccount,
+    ToggleLike,
+    AddToPlaylist,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -51,13 +57,24 @@ struct MenuItems {
     play: MenuItem,
     next: MenuItem,
     prev: MenuItem,
+    like: MenuItem,
+    playlist: Vec<MenuItem>,
     preset: Vec<MenuItem>,
     volume: Vec<MenuItem>,
+    account: Vec<MenuItem>,
 }
 struct ConnectrApp {
     menu: MenuItems,
+    accounts: Vec<connectr::Account>,
+    active_account: String,
     device_list: Option<connectr::ConnectDeviceList>,
     player_state: Option<connectr::PlayerState>,
+    /// Whether the current track is in the user's library, if known.
+    item_saved: Option<bool>,
+    /// (id, name) of playlists the user can add tracks to.  Fetched once.
+    playlists: Option<Vec<(String, String)>>,
+    /// Upcoming tracks, as menu labels.
+    up_next: Vec<String>,
 }
 
 fn play_action_label(is_playing: bool) -> &'static str {
@@ -71,44 +88,139 @@ fn update_state(app: &mut ConnectrApp, spotify: &mut connectr::SpotifyConnectr)
     let dev_list = spotify.request_device_list();
     let player_state = spotify.request_player_state();
     match dev_list {
-        Some(_) => { app.device_list = dev_list },
-        None => { return false },
+        Ok(list) => { app.device_list = Some(list) },
+        Err(e) => { warn!("Failed to fetch device list: {}", e); return false },
     }
     match player_state {
-        Some(_) => { app.player_state = player_state },
-        None => { return false },
+        Ok(state) => { app.player_state = state },
+        Err(e) => { warn!("Failed to fetch player state: {}", e); return false },
+    }
+    if app.playlists.is_none() {
+        match editable_playlists(spotify) {
+            Ok(playlists) => { app.playlists = Some(playlists) },
+            Err(e) => { warn!("Failed to fetch playlists: {}", e) },
+        }
+    }
+    app.up_next = match spotify.get_queue() {
+        Ok(queue) => queue.queue.iter().take(UP_NEXT_COUNT).map(|item| {
+            match item.artist_or_show().as_str() {
+                "" => item.name().to_string(),
+                artist => format!("{} - {}", item.name(), artist),
+            }
+        }).collect(),
+        Err(e) => { warn!("Failed to fetch queue: {}", e); Vec::new() },
+    };
+    // Not worth failing the whole refresh over, the item is just hidden.
+    app.item_saved = None;
+    // Only tracks can be liked.
+    let track_id = match app.player_state.as_ref().and_then(|s| s.item.as_ref()) {
+        Some(&connectr::PlaybackItem::Track(ref track)) => track.id.clone(),
+        _ => None,
+    };
+    if let Some(id) = track_id {
+        match spotify.check_saved_tracks(&[&id]) {
+            Ok(saved) => { app.item_saved = saved.first().cloned() },
+            Err(e) => { warn!("Failed to check saved tracks: {}", e) },
+        }
     }
     true
 }
 
+fn editable_playlists(spotify: &mut connectr::SpotifyConnectr) -> Result<Vec<(String, String)>, ConnectrError> {
+    let user = spotify.current_user()?;
+    let mut playlists = Vec::new();
+    for playlist in spotify.my_playlists_iter(Some(50)) {
+        let playlist = playlist?;
+        if playlist.owner.id == user.id || playlist.collaborative {
+            playlists.push((playlist.id, playlist.name));
+        }
+    }
+    Ok(playlists)
+}
+
 fn fill_menu<T: TStatusBar>(app: &mut ConnectrApp, spotify: &mut connectr::SpotifyConnectr, status: &mut T) {
     let ref device_list = app.device_list.as_ref().unwrap();
-    let ref player_state = app.player_state.as_ref().unwrap();
+    let player_state = app.player_state.as_ref();
+    let item = player_state.and_then(|state| state.item.as_ref());
 
-    println!("Playback State:\n{}", player_state);
-    let play_str = format!("{}\n{}\n{}",
-                           &player_state.item.name,
-                           &player_state.item.artists[0].name,
-                           &player_state.item.album.name);
+    if let Some(state) = player_state {
+        println!("Playback State:\n{}", state);
+    }
+    let play_str = match item {
+        Some(item) => format!("{}\n{}\n{}",
+                              item.name(),
+                              item.artist_or_show(),
+                              item.album_or_publisher()),
+        None => "connectr".to_string(),
+    };
     status.set_tooltip(&play_str);
 
     status.add_label("Now Playing:");
     status.add_separator();
-    //status.add_label(&player_state.item.name);
-    status.add_label(&format!("{:<50}", &player_state.item.name));
-    status.add_label(&format!("{:<50}", &player_state.item.artists[0].name));
-    status.add_label(&format!("{:<50}", &player_state.item.album.name));
-    let ms = player_state.item.duration_ms;
-    let min = ms / 1000 / 60;
-    let sec = (ms - (min * 60 * 1000)) / 1000;
-    status.add_label(&format!("{:<50}", format!("{}:{:02}", min, sec)));
+    match item {
+        Some(item) => {
+            status.add_label(&format!("{:<50}", item.name()));
+            status.add_label(&format!("{:<50}", item.artist_or_show()));
+            status.add_label(&format!("{:<50}", item.album_or_publisher()));
+            let ms = item.duration_ms();
+            let min = ms / 1000 / 60;
+            let sec = (ms - (min * 60 * 1000)) / 1000;
+            status.add_label(&format!("{:<50}", format!("{}:{:02}", min, sec)));
+        },
+        None => {
+            let label = match player_state.map(|state| state.currently_playing_type.as_str()) {
+                Some("ad") => "Advertisement",
+                _ => "Nothing",
+            };
+            status.add_label(&format!("{:<50}", label));
+        },
+    }
+    if let (Some(id), Some(saved)) = (item.and_then(|i| i.id()).map(|id| id.to_string()), app.item_saved) {
+        let cb: NSCallback = Box::new(move |sender, tx| {
+            let cmd = MenuCallbackCommand {
+                action: CallbackAction::ToggleLike,
+                sender: sender,
+                data: id.to_owned(),
+            };
+            let _ = tx.send(serde_json::to_string(&cmd).unwrap());
+        });
+        app.menu.like = status.add_item("Like", cb, saved);
+    }
+    if let (Some(item), Some(playlists)) = (item, app.playlists.as_ref()) {
+        if playlists.len() > 0 {
+            status.add_submenu("Add to Playlist");
+            for &(ref id, ref name) in playlists {
+                let data = format!("{} {}", id, item.uri());
+                let cb: NSCallback = Box::new(move |sender, tx| {
+                    let cmd = MenuCallbackCommand {
+                        action: CallbackAction::AddToPlaylist,
+                        sender: sender,
+                        data: data.to_owned(),
+                    };
+                    let _ = tx.send(serde_json::to_string(&cmd).unwrap());
+                });
+                let item = status.add_item(name, cb, false);
+                app.menu.playlist.push(item);
+            }
+            status.end_submenu();
+        }
+    }
+
+    if app.up_next.len() > 0 {
+        status.add_label("");
+        status.add_label("Up Next:");
+        status.add_separator();
+        for label in &app.up_next {
+            status.add_label(&format!("{:<50}", label));
+        }
+    }
 
     status.add_label("");
     status.add_label("Actions:");
     status.add_separator();
     {
-        let play_str = play_action_label(player_state.is_playing);
-        let is_playing = player_state.is_playing.clone();
+        let is_playing = player_state.map(|state| state.is_playing).unwrap_or(false);
+        let play_str = play_action_label(is_playing);
         let cb: NSCallback = Box::new(move |sender, tx| {
             let cmd = MenuCallbackCommand {
                 action: CallbackAction::PlayPause,
@@ -127,7 +239,9 @@ fn fill_menu<T: TStatusBar>(app: &mut ConnectrApp, spotify: &mut connectr::Spoti
             };
             let _ = tx.send(serde_json::to_string(&cmd).unwrap());
         });
-        app.menu.next = status.add_item("Next", cb, false);
+        if !player_state.map(|state| state.is_disallowed("skipping_next")).unwrap_or(false) {
+            app.menu.next = status.add_item("Next", cb, false);
+        }
 
         let cb: NSCallback = Box::new(move |sender, tx| {
             let cmd = MenuCallbackCommand {
@@ -137,7 +251,9 @@ fn fill_menu<T: TStatusBar>(app: &mut ConnectrApp, spotify: &mut connectr::Spoti
             };
             let _ = tx.send(serde_json::to_string(&cmd).unwrap());
         });
-        app.menu.prev = status.add_item("Previous", cb, false);
+        if !player_state.map(|state| state.is_disallowed("skipping_prev")).unwrap_or(false) {
+            app.menu.prev = status.add_item("Previous", cb, false);
+        }
     }
 
     status.add_label("");
@@ -152,7 +268,7 @@ fn fill_menu<T: TStatusBar>(app: &mut ConnectrApp, spotify: &mut connectr::Spoti
                 let cmd = MenuCallbackCommand {
                     action: CallbackAction::Preset,
                     sender: sender,
-                    data: uri.to_owned(),
+                    data: uri.to_string(),
                 };
                 let _ = tx.send(serde_json::to_string(&cmd).unwrap());
             });
@@ -210,6 +326,24 @@ fn fill_menu<T: TStatusBar>(app: &mut ConnectrApp, spotify: &mut connectr::Spoti
             i += 10;
         }
     }
+    if app.accounts.len() > 1 {
+        status.add_label("");
+        status.add_label("Accounts:");
+        status.add_separator();
+        for account in &app.accounts {
+            let name = account.name.clone();
+            let cb: NSCallback = Box::new(move |sender, tx| {
+                let cmd = MenuCallbackCommand {
+                    action: CallbackAction::SelectAccount,
+                    sender: sender,
+                    data: name.to_owned(),
+                };
+                let _ = tx.send(serde_json::to_string(&cmd).unwrap());
+            });
+            let item = status.add_item(&account.label, cb, account.name == app.active_account);
+            app.menu.account.push(item);
+        }
+    }
     status.add_separator();
     status.add_quit("Exit");
 }
@@ -220,8 +354,11 @@ fn clear_menu<T: TStatusBar>(app: &mut ConnectrApp, _: &mut connectr::SpotifyCon
         play: ptr::null_mut(),
         next: ptr::null_mut(),
         prev: ptr::null_mut(),
+        like: ptr::null_mut(),
+        playlist: Vec::<MenuItem>::new(),
         preset: Vec::<MenuItem>::new(),
         volume: Vec::<MenuItem>::new(),
+        account: Vec::<MenuItem>::new(),
     };
     status.clear_items();
 }
@@ -254,8 +391,32 @@ fn create_logger() {
     let _ = log4rs::init_config(config).unwrap();
 }
 
-fn handle_callback<T: TStatusBar>(app: &mut ConnectrApp, spotify: &mut connectr::SpotifyConnectr,
+/// Run a menu command through the shared command layer, with what the
+/// menu knows as its cache.  Errors are logged, and None returned.
+fn run_command(app: &mut ConnectrApp, accounts: &mut connectr::SpotifyAccounts,
+               command: connectr::control::Command) -> Option<serde_json::Value> {
+    let mut cache = connectr::control::StateCache {
+        devices: app.device_list.take(),
+        player_state: app.player_state.take(),
+    };
+    let result = connectr::control::execute(accounts, &mut cache, &command, None);
+    app.device_list = cache.devices;
+    app.player_state = cache.player_state;
+    match result {
+        Ok(value) => {
+            println!("Response: OK");
+            Some(value)
+        },
+        Err(e) => {
+            error!("Spotify request failed: {}", e);
+            None
+        },
+    }
+}
+
+fn handle_callback<T: TStatusBar>(app: &mut ConnectrApp, accounts: &mut connectr::SpotifyAccounts,
                    status: &mut T, cmd: &MenuCallbackCommand) {
+    use connectr::control::Command;
     info!("Executed action: {:?}", cmd.action);
     match cmd.action {
         CallbackAction::SelectDevice => {
@@ -265,58 +426,104 @@ fn handle_callback<T: TStatusBar>(app: &mut ConnectrApp, spotify: &mut connectr:
                 status.unsel_item(*item as u64);
             }
             status.sel_item(cmd.sender);
-            // Spotify is broken.  Must be 'true', always starts playing.
-            require(spotify.transfer(cmd.data.clone(), true));
+            run_command(app, accounts, Command::SelectDevice(cmd.data.clone()));
         },
         CallbackAction::PlayPause => {
-            let fresh_player_state = spotify.request_player_state();
-            let player_state = match fresh_player_state {
-                Some(ref state) => state,
-                None => app.player_state.as_ref().unwrap(),
-            };
-            match player_state.is_playing {
-                true => {require(spotify.pause());},
-                false => {require(spotify.play(None));},
+            if run_command(app, accounts, Command::PlayPause).is_some() {
+                // The command leaves the state it toggled from in the cache.
+                let was_playing = app.player_state.as_ref().map(|s| s.is_playing).unwrap_or(false);
+                status.update_item(app.menu.play, play_action_label(!was_playing));
             }
-            let play_str = play_action_label(!player_state.is_playing);
-            status.update_item(app.menu.play, play_str);
         },
         CallbackAction::Preset => {
-            play_uri(spotify, None, Some(&cmd.data));
+            match cmd.data.parse::<connectr::SpotifyUri>() {
+                Ok(uri) => { run_command(app, accounts, Command::Play(Some(uri))); },
+                Err(e) => error!("Bad preset: {}", e),
+            }
         }
         CallbackAction::SkipNext => {
-            require(spotify.next());
+            run_command(app, accounts, Command::SkipNext);
         }
         CallbackAction::SkipPrev => {
-            require(spotify.previous());
+            run_command(app, accounts, Command::SkipPrev);
         }
         CallbackAction::Volume => {
-            let vol = cmd.data.parse::<u32>().unwrap();
-            require(spotify.volume(vol));
+            let vol = match cmd.data.parse::<u32>() {
+                Ok(vol) => vol,
+                Err(_) => return,
+            };
+            run_command(app, accounts, Command::Volume(vol));
             let volume = &app.menu.volume;
             for item in volume {
                 status.unsel_item(*item as u64);
             }
             status.sel_item(cmd.sender);
         }
+        CallbackAction::ToggleLike => {
+            // Checks again, in case it was changed from another app.
+            let result = run_command(app, accounts, Command::ToggleLike);
+            if let Some(saved) = result.as_ref().and_then(|r| r.get("saved")).and_then(|s| s.as_bool()) {
+                match saved {
+                    true => status.sel_item(cmd.sender),
+                    false => status.unsel_item(cmd.sender),
+                }
+                app.item_saved = Some(saved);
+            }
+        }
+        CallbackAction::AddToPlaylist => {
+            let mut parts = cmd.data.splitn(2, ' ');
+            if let (Some(playlist), Some(uri)) = (parts.next(), parts.next()) {
+                match uri.parse::<connectr::SpotifyUri>() {
+                    Ok(uri) => {
+                        let command = Command::AddToPlaylist { playlist: playlist.to_string(), uri: uri };
+                        run_command(app, accounts, command);
+                    },
+                    Err(e) => error!("Cannot add to playlist: {}", e),
+                }
+            }
+        }
+        CallbackAction::SelectAccount => {
+            if run_command(app, accounts, Command::SelectAccount(cmd.data.clone())).is_none() {
+                return;
+            }
+            for item in &app.menu.account {
+                status.unsel_item(*item as u64);
+            }
+            status.sel_item(cmd.sender);
+            account_switched(app, accounts);
+        }
     }
 }
 
+/// Start over with the newly active account.
+fn account_switched(app: &mut ConnectrApp, accounts: &mut connectr::SpotifyAccounts) {
+    app.active_account = accounts.active_account().name.clone();
+    // Forget the old account's state, so it's never shown as the new one's.
+    app.device_list = None;
+    app.player_state = None;
+    app.item_saved = None;
+    app.playlists = None;
+    app.up_next.clear();
+    accounts.active_mut().set_target_device(None);
+}
+
 fn refresh_time(app: &mut ConnectrApp, now: i64) -> i64 {
     let refresh_offset = match app.player_state.as_ref() {
         Some(ref state) => {
+            // Ads have no item.  Check back soon, since they're short.
+            let duration_ms = state.item.as_ref().map(|item| item.duration_ms()).unwrap_or(0);
             match state.is_playing {
                 true => {
                     let track_end = match state.progress_ms {
                         Some(prog) => {
-                            if prog < state.item.duration_ms {
-                                state.item.duration_ms - prog
+                            if prog < duration_ms {
+                                duration_ms - prog
                             }
                             else {
                                 0
                             }
                         },
-                        None => state.item.duration_ms,
+                        None => duration_ms,
                     } as i64;
                     // Refresh 1 second after track ends
                     track_end/1000 + 1
@@ -362,21 +569,71 @@ fn main() {
             play: ptr::null_mut(),
             next: ptr::null_mut(),
             prev: ptr::null_mut(),
+            like: ptr::null_mut(),
+            playlist: Vec::<MenuItem>::new(),
             preset: Vec::<MenuItem>::new(),
             volume: Vec::<MenuItem>::new(),
+            account: Vec::<MenuItem>::new(),
         },
+        accounts: Vec::new(),
+        active_account: String::new(),
         device_list: None,
         player_state: None,
+        item_saved: None,
+        playlists: None,
+        up_next: Vec::new(),
     };
     let mut refresh_time_utc = 0;
     let (tx,rx) = channel::<String>();
-    let mut spotify = connectr::SpotifyConnectr::new();
+    let mut accounts = match connectr::SpotifyAccounts::new() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(0);
+        }
+    };
     info!("Created Spotify controller.");
-    spotify.connect();
+    if let Err(e) = accounts.connect() {
+        error!("Failed to connect to Spotify: {}", e);
+        process::exit(1);
+    }
     info!("Created Spotify connection.");
-    spotify.set_target_device(None);
+    accounts.active_mut().set_target_device(None);
+    app.accounts = accounts.accounts().into_iter().cloned().collect();
+    app.active_account = accounts.active_account().name.clone();
     let mut status = connectr::StatusBar::new(tx);
     info!("Created status bar.");
+    #[cfg(target_os = "linux")]
+    let mut mpris = connectr::linux::MprisServer::new();
+    // Commands from the control socket and HTTP API, run in the main loop.
+    let commands = connectr::control::CommandQueue::new();
+    #[cfg(unix)]
+    let _ipc = match accounts.active().settings().ipc {
+        true => {
+            let path = connectr::ipc::socket_path(accounts.active().settings());
+            match connectr::ipc::IpcServer::bind(&path, commands.handle()) {
+                Ok(server) => Some(server),
+                Err(e) => {
+                    warn!("Control socket disabled: {}", e);
+                    None
+                },
+            }
+        },
+        false => None,
+    };
+    let _server = match accounts.active().settings().server {
+        Some(ref settings) => {
+            let presets = accounts.active().settings().presets.clone();
+            match connectr::server::RestServer::bind(settings, presets, commands.handle()) {
+                Ok(server) => Some(server),
+                Err(e) => {
+                    warn!("Control API disabled: {}", e);
+                    None
+                },
+            }
+        },
+        None => None,
+    };
 
     let mut tiny: Option<process::Child> = None;
     if let Some(wine_dir) = find_wine_path() {
@@ -397,20 +654,46 @@ fn main() {
 
     while running.load(Ordering::SeqCst) {
         let now = time::now_utc().to_timespec().sec as i64;
-        if now > refresh_time_utc && status.can_redraw() && update_state(&mut app, &mut spotify) {
-            // Redraw the whole menu once every 60 seconds, or sooner if a
-            // command is processed later.
-            clear_menu(&mut app, &mut spotify, &mut status);
-            fill_menu(&mut app, &mut spotify, &mut status);
-            refresh_time_utc = refresh_time(&mut app, now);
-            info!("Refreshed Spotify state.");
+        if now > refresh_time_utc && status.can_redraw() {
+            let spotify = accounts.active_mut();
+            if update_state(&mut app, spotify) {
+                // Redraw the whole menu once every 60 seconds, or sooner if a
+                // command is processed later.
+                clear_menu(&mut app, spotify, &mut status);
+                fill_menu(&mut app, spotify, &mut status);
+                #[cfg(target_os = "linux")]
+                mpris.update(app.player_state.as_ref(), spotify);
+                commands.update(connectr::control::StateCache {
+                    devices: app.device_list.clone(),
+                    player_state: app.player_state.clone(),
+                });
+                refresh_time_utc = refresh_time(&mut app, now);
+                info!("Refreshed Spotify state.");
+            }
         }
 
-        spotify.await_once(false);
+        // Errors are logged per account.
+        let _ = accounts.await_once();
         if let Ok(s) = rx.try_recv() {
             println!("Received {}", s);
+            if s == connectr::QUIT_COMMAND {
+                break;
+            }
             let cmd: MenuCallbackCommand = serde_json::from_str(&s).unwrap();
-            handle_callback(&mut app, &mut spotify, &mut status, &cmd);
+            handle_callback(&mut app, &mut accounts, &mut status, &cmd);
+            refresh_time_utc = now + 1;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if mpris.process(accounts.active_mut(), Duration::from_millis(0)) {
+                refresh_time_utc = now + 1;
+            }
+        }
+        if commands.process(&mut accounts) {
+            refresh_time_utc = now + 1;
+        }
+        if accounts.active_account().name != app.active_account {
+            account_switched(&mut app, &mut accounts);
             refresh_time_utc = now + 1;
         }
         status.run(false);
@@ -423,30 +706,3 @@ fn main() {
     }
 }
 
-fn require(response: SpotifyResponse) {
-    match response.code.unwrap() {
-        200 ... 299 => (),
-        _ => panic!("{}", response)
-    }
-    println!("Response: {}", response.code.unwrap());
-}
-
-fn play_uri(spotify: &mut connectr::SpotifyConnectr, device: Option<&str>, uri: Option<&str>) {
-    match device {
-        Some(dev) => { spotify.set_target_device(Some(dev.to_string())); },
-        None => { spotify.set_target_device(None); },
-    }
-    match uri {
-        Some(s) => {
-            let ctx = connectr::PlayContext::new()
-                .context_uri(s)
-                .offset_position(0)
-                .build();
-            require(spotify.play(Some(&ctx)));
-        }
-        None => {
-            println!("Transfer!");
-            require(spotify.play(None));
-        }
-    };
-}
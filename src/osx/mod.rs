@@ -43,6 +43,8 @@ pub struct OSXStatusBar {
     app: *mut objc::runtime::Object,
     status_bar_item: *mut objc::runtime::Object,
     menu_bar: *mut objc::runtime::Object,
+    // Submenu being filled in, if any.  Items are added here instead.
+    submenu: Option<*mut objc::runtime::Object>,
 
     // Run loop state
     // Keeping these in persistent state instead of recalculating saves quite a
@@ -65,6 +67,7 @@ impl TStatusBar for OSXStatusBar {
                 app: app,
                 status_bar_item: status_bar.statusItemWithLength_(NSVariableStatusItemLength),
                 menu_bar: NSMenu::new(nil),
+                submenu: None,
                 object: NSObj::alloc(tx).setup(),
                 pool: Cell::new(nil),
                 run_count: Cell::new(0),
@@ -126,6 +129,7 @@ impl TStatusBar for OSXStatusBar {
         true
     }
     fn clear_items(&mut self) {
+        self.end_submenu();
         unsafe {
             let old_menu = self.menu_bar;
             self.menu_bar = NSMenu::new(nil);
@@ -149,7 +153,7 @@ impl TStatusBar for OSXStatusBar {
                 .initWithTitle_action_keyEquivalent_(txt, self.object.selector(), quit_key);
             let _ = msg_send![txt, release];
             let _ = msg_send![quit_key, release];
-            self.menu_bar.addItem_(app_menu_item);
+            self.current_menu().addItem_(app_menu_item);
             let _ = msg_send![app_menu_item, release];
         }
     }
@@ -169,7 +173,7 @@ impl TStatusBar for OSXStatusBar {
         unsafe {
             let cls = Class::get("NSMenuItem").unwrap();
             let sep: *mut Object = msg_send![cls, separatorItem];
-            self.menu_bar.addItem_(sep);
+            self.current_menu().addItem_(sep);
         }
     }
     fn add_item(&mut self, item: &str, callback: NSCallback, selected: bool) -> *mut Object {
@@ -187,11 +191,33 @@ impl TStatusBar for OSXStatusBar {
                 let _: () = msg_send![app_menu_item, setState: 1];
             }
             let item: *mut Object = app_menu_item;
-            self.menu_bar.addItem_(app_menu_item);
+            self.current_menu().addItem_(app_menu_item);
             let _ = msg_send![app_menu_item, release];
             item
         }
     }
+    fn add_submenu(&mut self, label: &str) {
+        self.end_submenu();
+        unsafe {
+            let txt = NSString::alloc(nil).init_str(label);
+            let no_key = NSString::alloc(nil).init_str("");
+            let app_menu_item = NSMenuItem::alloc(nil)
+                .initWithTitle_action_keyEquivalent_(txt, self.object.selector(), no_key);
+            let _ = msg_send![txt, release];
+            let _ = msg_send![no_key, release];
+            let submenu = NSMenu::new(nil);
+            let _: () = msg_send![app_menu_item, setSubmenu: submenu];
+            self.menu_bar.addItem_(app_menu_item);
+            let _ = msg_send![app_menu_item, release];
+            self.submenu = Some(submenu);
+        }
+    }
+    fn end_submenu(&mut self) {
+        if let Some(submenu) = self.submenu.take() {
+            // Retained by its parent item.
+            unsafe { let _ = msg_send![submenu, release]; }
+        }
+    }
     fn update_item(&mut self, item: *mut Object, label: &str) {
         unsafe {
             let ns_label = NSString::alloc(nil).init_str(label);
@@ -236,6 +262,15 @@ impl TStatusBar for OSXStatusBar {
     }
 }
 
+impl OSXStatusBar {
+    fn current_menu(&self) -> *mut objc::runtime::Object {
+        match self.submenu {
+            Some(submenu) => submenu,
+            None => self.menu_bar,
+        }
+    }
+}
+
 //pub fn osx_alert(text: &str) {
 //    unsafe {
 //        let ns_text = NSString::alloc(nil).init_str(text);
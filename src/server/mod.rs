@@ -0,0 +1,393 @@
+//! HTTP control API, for dashboards and home automation on the network.
+//!
+//!   GET  /api/state              What is playing (`?refresh=true` to ask Spotify)
+//!   GET  /api/devices            Available devices (`?refresh=true` to ask Spotify)
+//!   GET  /api/presets            Presets from connectr.ini
+//!   GET  /api/events             Server-sent events as the state changes
+//!   POST /api/command/<method>   Run a command, with its parameters as a JSON body
+//!
+//! Commands are those of `control::Command`, plus an optional `device` to send
+//! them to.  Every request needs the token from `[server]`, either as
+//! `Authorization: Bearer <token>` or, for EventSource which can't set
+//! headers, as `?token=<token>`.
+
+#[cfg(test)]
+mod test;
+
+extern crate serde_json;
+use self::serde_json::Value;
+
+extern crate url;
+use self::url::form_urlencoded;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::SpotifyUri;
+use super::control::{self, Command, CommandHandle, StateCache, object, encode_error};
+use super::error::ConnectrError;
+use super::settings::ServerSettings;
+
+/// Largest request line and headers accepted.
+const MAX_HEADER_BYTES: u64 = 8 * 1024;
+/// Largest command body accepted.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+/// Authorized clients served at once, event streams included.  More are
+/// turned away.
+const MAX_CLIENTS: usize = 16;
+/// Connections still sending their request.  Kept apart from `MAX_CLIENTS`,
+/// so that idle connections without the token can't lock out those with it.
+const MAX_PENDING: usize = 64;
+/// How long a connection has to send its request.
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+/// How long turning away a connection may hold up accepting the next one.
+const TURN_AWAY_TIMEOUT_MS: u64 = 250;
+/// How often an idle event stream is poked, so dead clients are noticed.
+const KEEPALIVE_SECS: u64 = 15;
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&&(ref key, _)| key.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| value.as_str())
+    }
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query.iter()
+            .find(|&&(ref key, _)| key == name)
+            .map(|&(_, ref value)| value.as_str())
+    }
+    /// Token from the Authorization header, or the query string.
+    fn token(&self) -> Option<&str> {
+        match self.header("Authorization") {
+            Some(auth) if auth.starts_with("Bearer ") => Some(auth["Bearer ".len()..].trim()),
+            _ => self.query("token"),
+        }
+    }
+}
+
+/// Read one request, or None if the client went away or sent garbage.
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    // Running out of header allowance looks like the end of the stream.
+    let mut head = reader.by_ref().take(MAX_HEADER_BYTES);
+    let mut request_line = String::new();
+    if head.read_line(&mut request_line).is_err() {
+        return None;
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return None,
+    };
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        match head.read_line(&mut line) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {},
+        }
+        let line = line.trim_right();
+        if line.len() == 0 {
+            break;
+        }
+        if let Some(idx) = line.find(':') {
+            headers.push((line[..idx].trim().to_string(), line[idx+1..].trim().to_string()));
+        }
+    }
+    let length = headers.iter()
+        .find(|&&(ref key, _)| key.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|&(_, ref value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    if length > MAX_BODY_BYTES {
+        return None;
+    }
+    let mut body = vec![0u8; length];
+    if reader.read_exact(&mut body).is_err() {
+        return None;
+    }
+    let (path, query) = match target.find('?') {
+        Some(idx) => (&target[..idx], &target[idx+1..]),
+        None => (target.as_str(), ""),
+    };
+    let query = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+    Some(Request { method: method, path: path.to_string(), query: query, headers: headers, body: body })
+}
+
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    fn json(status: &'static str, body: &Value) -> Response {
+        Response { status: status, headers: Vec::new(), body: body.to_string() }
+    }
+    fn error(status: &'static str, message: &str) -> Response {
+        Response::json(status, &object(vec![("error", object(vec![("message", message.into())]))]))
+    }
+    fn write_to(&self, stream: &mut TcpStream) {
+        let mut head = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                                Access-Control-Allow-Origin: *\r\nConnection: close\r\n",
+                               self.status, self.body.len());
+        for &(name, ref value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let _ = stream.write_all(head.as_bytes());
+        let _ = stream.write_all(self.body.as_bytes());
+    }
+}
+
+/// Response for a command that failed, with the error described as by
+/// `control::encode_error()`.
+fn command_error(err: &ConnectrError) -> Response {
+    let status = match *err {
+        ConnectrError::InvalidUri(_) | ConnectrError::Config(_) => "400 Bad Request",
+        ConnectrError::NoDevice(_) => "404 Not Found",
+        ConnectrError::RateLimited(_) => "429 Too Many Requests",
+        ConnectrError::Transport(_) => "504 Gateway Timeout",
+        ConnectrError::Io(_) => "500 Internal Server Error",
+        _ => "502 Bad Gateway",
+    };
+    let mut error = encode_error(err);
+    if let Some(error) = error.as_object_mut() {
+        error.insert("message".to_string(), err.to_string().into());
+    }
+    let mut response = Response::json(status, &object(vec![("error", error)]));
+    if let ConnectrError::RateLimited(Some(secs)) = *err {
+        response.headers.push(("Retry-After", secs.to_string()));
+    }
+    response
+}
+
+/// Compare without giving away how much of the token was right.
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() &&
+        given.bytes().zip(expected.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+struct ServerState {
+    token: String,
+    presets: Vec<(String, SpotifyUri)>,
+    commands: CommandHandle,
+    pending: Arc<AtomicUsize>,
+    clients: Arc<AtomicUsize>,
+}
+
+/// One of a limited number of connections, given back when dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    /// Take a slot from `count`, unless all `max` are in use.
+    fn take(count: &Arc<AtomicUsize>, max: usize) -> Option<Slot> {
+        if count.fetch_add(1, Ordering::SeqCst) >= max {
+            count.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot(count.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn run_command(state: &ServerState, command: Command, device: Option<String>) -> Response {
+    match state.commands.run(&command, device) {
+        Ok(result) => Response::json("200 OK", &object(vec![("result", result)])),
+        Err(e) => command_error(&e),
+    }
+}
+
+fn route(request: &Request, state: &ServerState) -> Response {
+    let refresh = match request.query("refresh") {
+        Some("true") | Some("1") => true,
+        _ => false,
+    };
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/api/state") => run_command(state, Command::PlayerState { refresh: refresh }, None),
+        ("GET", "/api/devices") => run_command(state, Command::Devices { refresh: refresh }, None),
+        ("GET", "/api/presets") => {
+            let presets = state.presets.iter()
+                .map(|&(ref name, ref uri)| object(vec![("name", name.as_str().into()), ("uri", uri.to_string().into())]))
+                .collect::<Vec<_>>();
+            Response::json("200 OK", &object(vec![("result", presets.into())]))
+        },
+        ("POST", path) if path.starts_with("/api/command/") => {
+            let method = &path["/api/command/".len()..];
+            if !control::METHODS.contains(&method) {
+                return Response::error("404 Not Found", &format!("unknown command '{}'", method));
+            }
+            let params = match request.body.len() {
+                0 => object(vec![]),
+                _ => match serde_json::from_slice::<Value>(&request.body) {
+                    Ok(params @ Value::Object(_)) => params,
+                    _ => return Response::error("400 Bad Request", "body must be a JSON object"),
+                },
+            };
+            let command = match Command::parse(method, &params) {
+                Ok(command) => command,
+                Err(e) => return Response::error("400 Bad Request", &e),
+            };
+            // select_device's device is where to go, not where to send it.
+            let device = match command {
+                Command::SelectDevice(_) => None,
+                _ => params.get("device").and_then(|d| d.as_str()).map(|d| d.to_string()),
+            };
+            run_command(state, command, device)
+        },
+        (_, "/api/state") | (_, "/api/devices") | (_, "/api/presets") | (_, "/api/events") => {
+            Response::error("405 Method Not Allowed", "method not allowed")
+        },
+        _ => Response::error("404 Not Found", "not found"),
+    }
+}
+
+/// What an event stream sends for the current state, as (event, data).
+fn events(cache: &StateCache) -> Vec<(&'static str, String)> {
+    let devices = cache.devices.as_ref().map(|d| &d.devices);
+    vec![("devices", serde_json::to_string(&devices).unwrap_or("null".to_string())),
+         ("player_state", serde_json::to_string(&cache.player_state).unwrap_or("null".to_string()))]
+}
+
+/// Push state to the client as it changes, until it goes away.
+fn stream_events(mut stream: TcpStream, commands: &CommandHandle) {
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                Access-Control-Allow-Origin: *\r\nConnection: keep-alive\r\n\r\n";
+    if stream.write_all(head.as_bytes()).is_err() {
+        return;
+    }
+    let (mut version, mut cache) = commands.state();
+    let mut sent: Vec<(&'static str, String)> = Vec::new();
+    loop {
+        let mut message = String::new();
+        for (event, data) in events(&cache) {
+            if !sent.contains(&(event, data.clone())) {
+                message.push_str(&format!("event: {}\ndata: {}\n\n", event, data));
+                sent.retain(|&(e, _)| e != event);
+                sent.push((event, data));
+            }
+        }
+        if message.len() == 0 {
+            message.push_str(": keepalive\n\n");
+        }
+        if stream.write_all(message.as_bytes()).and_then(|_| stream.flush()).is_err() {
+            return;
+        }
+        let (new_version, new_cache) = commands.wait_for_update(version, Duration::from_secs(KEEPALIVE_SECS));
+        version = new_version;
+        cache = new_cache;
+    }
+}
+
+fn serve_client(mut stream: TcpStream, state: &ServerState, pending: Slot) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)));
+    let request = match read_request(&stream) {
+        Some(request) => request,
+        None => return Response::error("400 Bad Request", "bad request").write_to(&mut stream),
+    };
+    if request.method == "OPTIONS" {
+        // CORS preflight, so dashboards on other origins can send the token.
+        let mut response = Response { status: "204 No Content", headers: Vec::new(), body: String::new() };
+        response.headers.push(("Access-Control-Allow-Methods", "GET, POST, OPTIONS".to_string()));
+        response.headers.push(("Access-Control-Allow-Headers", "Authorization, Content-Type".to_string()));
+        return response.write_to(&mut stream);
+    }
+    if !request.token().map(|t| same_token(t, &state.token)).unwrap_or(false) {
+        warn!("Rejected unauthorized request for {}", request.path);
+        let mut response = Response::error("401 Unauthorized", "missing or invalid token");
+        response.headers.push(("WWW-Authenticate", "Bearer realm=\"connectr\"".to_string()));
+        return response.write_to(&mut stream);
+    }
+    drop(pending);
+    let _slot = match Slot::take(&state.clients, MAX_CLIENTS) {
+        Some(slot) => slot,
+        None => {
+            warn!("Turning away a client: already serving {}", MAX_CLIENTS);
+            return too_busy().write_to(&mut stream);
+        },
+    };
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/api/events") => stream_events(stream, &state.commands),
+        _ => route(&request, state).write_to(&mut stream),
+    }
+}
+
+fn too_busy() -> Response {
+    let mut response = Response::error("503 Service Unavailable", "too many clients");
+    response.headers.push(("Retry-After", "5".to_string()));
+    response
+}
+
+/// Tell a connection there are too many others, without starting a thread.
+/// Whatever it sent is read first, but only briefly, so that closing the
+/// connection doesn't reset it before the client reads the response.
+fn turn_away(mut stream: TcpStream) {
+    let timeout = Some(Duration::from_millis(TURN_AWAY_TIMEOUT_MS));
+    let _ = stream.set_read_timeout(timeout);
+    let _ = stream.set_write_timeout(timeout);
+    let _ = read_request(&stream);
+    too_busy().write_to(&mut stream);
+}
+
+/// Serves the HTTP control API.  Each client is served on its own thread,
+/// and commands are sent to the main thread's `CommandQueue`.
+pub struct RestServer {
+    addr: SocketAddr,
+}
+
+impl RestServer {
+    pub fn bind(settings: &ServerSettings, presets: Vec<(String, SpotifyUri)>,
+                commands: CommandHandle) -> Result<RestServer, ConnectrError> {
+        let host = format!("{}:{}", settings.bind, settings.port);
+        let listener = TcpListener::bind(&host)
+            .map_err(|e| ConnectrError::Config(format!("can't listen on {}: {}", host, e)))?;
+        let addr = listener.local_addr().map_err(|e| ConnectrError::Transport(e.to_string()))?;
+        let state = Arc::new(ServerState {
+            token: settings.token.clone(),
+            presets: presets,
+            commands: commands,
+            pending: Arc::new(AtomicUsize::new(0)),
+            clients: Arc::new(AtomicUsize::new(0)),
+        });
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let pending = match Slot::take(&state.pending, MAX_PENDING) {
+                    Some(slot) => slot,
+                    None => {
+                        warn!("Turning away a connection: {} are still sending requests", MAX_PENDING);
+                        turn_away(stream);
+                        continue;
+                    },
+                };
+                let state = state.clone();
+                thread::spawn(move || serve_client(stream, &state, pending));
+            }
+        });
+        info!("Control API at http://{}/api", addr);
+        Ok(RestServer { addr: addr })
+    }
+    /// Address actually listened on, which tells the port if 0 was asked for.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
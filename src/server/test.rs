@@ -0,0 +1,197 @@
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+    use super::super::*;
+    use ::ConnectDeviceList;
+    use ::control::CommandQueue;
+    use ::settings::{Settings, ServerSettings};
+    use ::webapi::fixtures::{DEVICES, scripted_accounts, serve_commands};
+
+    const TOKEN: &'static str = "0123456789abcdef";
+
+    fn start(queue: &CommandQueue) -> SocketAddr {
+        let settings = ServerSettings { port: 0, bind: "127.0.0.1".to_string(), token: TOKEN.to_string() };
+        let presets = vec![("Bakesale".to_string(), "spotify:album:1pzvBxYgT6OVwJLtHkrdQK".parse().unwrap())];
+        RestServer::bind(&settings, presets, queue.handle()).unwrap().local_addr()
+    }
+
+    /// Send one request, and return the status code, headers and body.
+    fn request(addr: SocketAddr, method: &str, target: &str, token: Option<&str>, body: &str) -> (u32, String, Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let auth = match token {
+            Some(token) => format!("Authorization: Bearer {}\r\n", token),
+            None => String::new(),
+        };
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+               method, target, auth, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let idx = response.find("\r\n\r\n").unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = serde_json::from_str(&response[idx+4..]).unwrap_or(Value::Null);
+        (status, response[..idx].to_string(), body)
+    }
+
+    #[test]
+    fn test_server_auth() {
+        let queue = CommandQueue::new();
+        let addr = start(&queue);
+        let (status, headers, _) = request(addr, "GET", "/api/presets", None, "");
+        assert_eq!(status, 401);
+        assert!(headers.contains("WWW-Authenticate: Bearer"));
+        assert_eq!(request(addr, "GET", "/api/presets", Some("0123456789abcdeF"), "").0, 401);
+        assert_eq!(request(addr, "GET", "/api/presets", Some("short"), "").0, 401);
+
+        let (status, headers, body) = request(addr, "GET", "/api/presets", Some(TOKEN), "");
+        assert_eq!(status, 200);
+        assert!(headers.contains("Access-Control-Allow-Origin: *"));
+        assert_eq!(body["result"][0]["name"], "Bakesale");
+        let target = format!("/api/presets?token={}", TOKEN);
+        assert_eq!(request(addr, "GET", &target, None, "").0, 200);
+
+        // Browsers ask before sending the token from another origin.
+        let (status, headers, _) = request(addr, "OPTIONS", "/api/command/pause", None, "");
+        assert_eq!(status, 204);
+        assert!(headers.contains("Access-Control-Allow-Headers: Authorization"));
+    }
+
+    #[test]
+    fn test_server_limits() {
+        let queue = CommandQueue::new();
+        let addr = start(&queue);
+        // Headers past the limit are never read, token included.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let junk = "x".repeat(MAX_HEADER_BYTES as usize);
+        let _ = write!(stream, "GET /api/presets HTTP/1.1\r\nX-Junk: {}\r\nAuthorization: Bearer {}\r\n\r\n",
+                       junk, TOKEN);
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(!response.contains("200 OK"));
+
+        // Connections without the token don't keep out those with it.
+        let idle: Vec<TcpStream> = (0..MAX_CLIENTS).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        assert_eq!(request(addr, "GET", "/api/presets", Some(TOKEN), "").0, 200);
+        drop(idle);
+
+        // Authorized clients that hold on to their connection use up the rest.
+        let streams: Vec<TcpStream> = (0..MAX_CLIENTS).map(|_| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET /api/events?token={} HTTP/1.1\r\n\r\n", TOKEN).unwrap();
+            let mut status = [0u8; 12];
+            stream.read_exact(&mut status).unwrap();
+            assert_eq!(&status, b"HTTP/1.1 200");
+            stream
+        }).collect();
+        let (status, headers, _) = request(addr, "GET", "/api/presets", Some(TOKEN), "");
+        assert_eq!(status, 503);
+        assert!(headers.contains("Retry-After"));
+        drop(streams);
+        // Closed streams are noticed when there's next something to send.
+        let served = (0..100).any(|_| {
+            queue.update(StateCache::default());
+            thread::sleep(Duration::from_millis(10));
+            request(addr, "GET", "/api/presets", Some(TOKEN), "").0 == 200
+        });
+        assert!(served);
+    }
+
+    #[test]
+    fn test_server_pending_limit() {
+        let queue = CommandQueue::new();
+        let addr = start(&queue);
+        // Turned away before a thread is started for them.
+        let idle: Vec<TcpStream> = (0..MAX_PENDING).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let (status, headers, _) = request(addr, "GET", "/api/presets", Some(TOKEN), "");
+        assert_eq!(status, 503);
+        assert!(headers.contains("Retry-After"));
+        drop(idle);
+        let served = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            request(addr, "GET", "/api/presets", Some(TOKEN), "").0 == 200
+        });
+        assert!(served);
+    }
+
+    #[test]
+    fn test_server_queries() {
+        let queue = CommandQueue::new();
+        let addr = start(&queue);
+        let devices: ConnectDeviceList = serde_json::from_str(DEVICES).unwrap();
+        queue.update(StateCache { devices: Some(devices), player_state: None });
+
+        let (status, _, body) = request(addr, "GET", "/api/devices", Some(TOKEN), "");
+        assert_eq!(status, 200);
+        assert_eq!(body["result"][0]["name"], "Kitchen");
+        let (status, _, body) = request(addr, "GET", "/api/state", Some(TOKEN), "");
+        assert_eq!(status, 200);
+        assert_eq!(body["result"], Value::Null);
+
+        assert_eq!(request(addr, "GET", "/api/nothing", Some(TOKEN), "").0, 404);
+        assert_eq!(request(addr, "DELETE", "/api/devices", Some(TOKEN), "").0, 405);
+        assert_eq!(request(addr, "POST", "/api/command/rewind", Some(TOKEN), "").0, 404);
+        assert_eq!(request(addr, "POST", "/api/command/volume", Some(TOKEN), r#"{"percent": 300}"#).0, 400);
+        assert_eq!(request(addr, "POST", "/api/command/volume", Some(TOKEN), "[30]").0, 400);
+    }
+
+    #[test]
+    fn test_server_commands() {
+        let queue = CommandQueue::new();
+        let addr = start(&queue);
+        let (mut accounts, transport) = scripted_accounts(Settings::for_test());
+
+        transport.push_response(200, DEVICES);
+        transport.push_response(204, "");
+        transport.push_response_with_headers(429, &[("Retry-After", "7")], "");
+        transport.push_response(200, DEVICES);
+        let (responses, _) = serve_commands(&queue, &mut accounts, move || {
+            vec![request(addr, "POST", "/api/command/volume", Some(TOKEN), r#"{"percent": 30, "device": "kitchen"}"#),
+                 request(addr, "POST", "/api/command/skip_next", Some(TOKEN), ""),
+                 request(addr, "POST", "/api/command/pause", Some(TOKEN), r#"{"device": "garage"}"#)]
+        });
+        assert_eq!(responses[0].0, 200);
+        assert_eq!(responses[0].2, serde_json::from_str::<Value>(r#"{"result": null}"#).unwrap());
+        assert_eq!(responses[1].0, 429);
+        assert!(responses[1].1.contains("Retry-After: 7"));
+        assert_eq!(responses[1].2["error"]["kind"], "rate_limited");
+        assert_eq!(responses[2].0, 404);
+        assert_eq!(responses[2].2["error"]["kind"], "no_device");
+
+        let requests = transport.requests();
+        assert!(requests[1].query.contains("device_id=abc123"));
+        assert!(requests[1].query.contains("volume_percent=30"));
+    }
+
+    #[test]
+    fn test_server_events() {
+        let queue = CommandQueue::new();
+        let addr = start(&queue);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream, "GET /api/events?token={} HTTP/1.1\r\nHost: localhost\r\n\r\n", TOKEN).unwrap();
+        let mut reader = BufReader::new(stream);
+        // Read up to the next event's data.
+        let mut next_event = || {
+            let mut event = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.starts_with("event: ") {
+                    event = line["event: ".len()..].trim().to_string();
+                }
+                if line.starts_with("data: ") {
+                    let data: Value = serde_json::from_str(&line["data: ".len()..]).unwrap();
+                    return (event, data);
+                }
+            }
+        };
+
+        // Everything is sent at first, then only what changes.
+        assert_eq!(next_event(), ("devices".to_string(), Value::Null));
+        assert_eq!(next_event(), ("player_state".to_string(), Value::Null));
+        let devices: ConnectDeviceList = serde_json::from_str(DEVICES).unwrap();
+        queue.update(StateCache { devices: Some(devices), player_state: None });
+        let (event, data) = next_event();
+        assert_eq!(event, "devices");
+        assert_eq!(data[0]["name"], "Kitchen");
+    }
+}
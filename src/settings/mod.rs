@@ -6,20 +6,87 @@ extern crate time;
 #[cfg(target_os = "macos")]
 use super::osx;
 
+use super::error::ConnectrError;
+use super::tokens::Tokens;
+use super::webapi::SpotifyUri;
+
 use std::env;
 use std::fs;
 use std::path;
 
 const INIFILE: &'static str = "connectr.ini";
 
+/// A named Spotify account from the `[accounts]` section.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+    /// Short name, used in the name of the account's token file.
+    pub name: String,
+    /// Name displayed in the menu.
+    pub label: String,
+}
+
+#[derive(Clone)]
 pub struct Settings {
     pub port: u32,
-    pub secret: String,
+    /// Address the OAuth callback server listens on.
+    pub bind: String,
+    /// Host in the redirect URI registered with Spotify.
+    pub redirect_host: String,
+    /// Never try to open a browser; print the login URL instead.
+    pub headless: bool,
+    pub secret: Option<String>,
     pub client_id: String,
-    pub access_token: Option<String>,
-    pub refresh_token: Option<String>,
-    pub expire_utc: Option<u64>,
-    pub presets: Vec<(String,String)>,
+    /// Authenticate with PKCE instead of the client secret.
+    pub pkce: bool,
+    /// Tokens from the `[tokens]` section older versions wrote into
+    /// connectr.ini.  Only used to migrate them into a `TokenStore`.
+    pub legacy_tokens: Option<Tokens>,
+    pub presets: Vec<(String,SpotifyUri)>,
+    /// Named accounts, sorted by name.  Empty if only a single, unnamed
+    /// account is used.
+    pub accounts: Vec<Account>,
+    /// Serve the IPC control socket (Unix only).
+    pub ipc: bool,
+    /// Where to put the control socket, instead of the default.
+    pub ipc_socket: Option<String>,
+    /// Serve the HTTP control API, if configured.
+    pub server: Option<ServerSettings>,
+}
+
+/// The `[server]` section: HTTP control API for dashboards and other
+/// machines on the network.
+#[derive(Clone, Debug)]
+pub struct ServerSettings {
+    pub port: u16,
+    pub bind: String,
+    /// Secret that clients must send as a bearer token.
+    pub token: String,
+}
+
+impl Settings {
+    /// Redirect URI registered with the Spotify application.
+    pub fn redirect_uri(&self) -> String {
+        format!("http://{}:{}", self.redirect_host, self.port)
+    }
+    /// Minimal valid settings, for tests that never read connectr.ini.
+    #[cfg(test)]
+    pub fn for_test() -> Settings {
+        Settings {
+            port: 5432,
+            bind: "127.0.0.1".to_string(),
+            redirect_host: "127.0.0.1".to_string(),
+            headless: true,
+            secret: Some("secret".to_string()),
+            client_id: "client_id".to_string(),
+            pkce: false,
+            legacy_tokens: None,
+            presets: Vec::new(),
+            accounts: Vec::new(),
+            ipc: false,
+            ipc_socket: None,
+            server: None,
+        }
+    }
 }
 
 #[cfg(target_os = "macos")]
@@ -62,7 +129,18 @@ fn inifile() -> String {
     String::new()
 }
 
-pub fn read_settings() -> Option<Settings> {
+fn valid_account_name(name: &str) -> bool {
+    name.len() > 0 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn parse_bool(value: Option<&String>) -> bool {
+    match value.map(|s| s.as_str()) {
+        Some("true") | Some("1") | Some("yes") => true,
+        _ => false,
+    }
+}
+
+pub fn read_settings() -> Result<Settings, ConnectrError> {
     info!("Attempting to read config file.");
     let conf = match Ini::load_from_file(&inifile()) {
         Ok(c) => c,
@@ -81,13 +159,38 @@ pub fn read_settings() -> Option<Settings> {
         }
     };
 
-    let section = conf.section(Some("connectr".to_owned())).unwrap();
-    let port = section.get("port").unwrap().parse().unwrap();
+    let section = match conf.section(Some("connectr".to_owned())) {
+        Some(section) => section,
+        None => return Err(ConnectrError::Config("missing [connectr] section".to_string())),
+    };
+    let port = match section.get("port").map(|p| p.parse::<u32>()) {
+        Some(Ok(port)) => port,
+        _ => return Err(ConnectrError::Config("[connectr] port must be an integer".to_string())),
+    };
+    let bind = section.get("bind").cloned().unwrap_or("127.0.0.1".to_string());
+    let redirect_host = section.get("redirect_host").cloned().unwrap_or("127.0.0.1".to_string());
+    let headless = parse_bool(section.get("headless"));
+    let ipc_socket = section.get("ipc_socket").cloned();
+    let ipc = parse_bool(section.get("ipc")) || ipc_socket.is_some();
 
-    let section = conf.section(Some("application".to_owned())).unwrap();
-    let secret = section.get("secret").unwrap();
-    let client_id = section.get("client_id").unwrap();
-    if client_id.starts_with('<') || secret.starts_with('<') {
+    let section = match conf.section(Some("application".to_owned())) {
+        Some(section) => section,
+        None => return Err(ConnectrError::Config("missing [application] section".to_string())),
+    };
+    let pkce = parse_bool(section.get("pkce"));
+    let secret = section.get("secret").map(|s| s.as_str()).unwrap_or("<MISSING>");
+    let client_id = section.get("client_id").map(|s| s.as_str()).unwrap_or("<MISSING>");
+    if pkce && client_id.starts_with('<') {
+        error!("Invalid or missing configuration.  Cannot continue.");
+        println!("");
+        println!("ERROR: Spotify Client ID not set in connectr.ini!");
+        println!("");
+        println!("Create a Spotify application at https://developer.spotify.com/my-applications/ and");
+        println!("add the client ID to connectr.ini.");
+        println!("");
+        return Err(ConnectrError::Config("Spotify client ID not set".to_string()));
+    }
+    if !pkce && (client_id.starts_with('<') || secret.starts_with('<')) {
         error!("Invalid or missing configuration.  Cannot continue.");
         println!("");
         println!("ERROR: Spotify Client ID or Secret not set in connectr.ini!");
@@ -98,38 +201,77 @@ pub fn read_settings() -> Option<Settings> {
         println!("Be sure to add a redirect URI of http://127.0.0.1:<PORT> to your Spotify application,");
         println!("and make sure the port matches in connectr.ini.");
         println!("");
-        return None;
+        return Err(ConnectrError::Config("Spotify client ID or secret not set".to_string()));
     }
 
-    let mut access = None;
-    let mut refresh = None;
-    let mut expire_utc = None;
+    let mut legacy_tokens = None;
     if let Some(section) = conf.section(Some("tokens".to_owned())) {
-        access = Some(section.get("access").unwrap().clone());
-        refresh = Some(section.get("refresh").unwrap().clone());
-        expire_utc = Some(section.get("expire").unwrap().parse().unwrap());
-        println!("Read access token from INI!");
+        if let (Some(access), Some(refresh)) = (section.get("access"), section.get("refresh")) {
+            legacy_tokens = Some(Tokens {
+                access: access.clone(),
+                refresh: refresh.clone(),
+                expire_utc: section.get("expire").and_then(|e| e.parse().ok()).unwrap_or(0),
+                scope: None,
+            });
+        }
     }
 
-    let mut presets = Vec::<(String,String)>::new();
+    let mut presets = Vec::<(String,SpotifyUri)>::new();
     if let Some(section) = conf.section(Some("presets".to_owned())) {
         for (key, value) in section {
-            presets.push((key.to_owned(), value.to_owned()));
+            // Catch typos now, rather than when the preset is clicked.  One
+            // bad preset shouldn't keep the rest of the app from starting.
+            match value.parse::<SpotifyUri>() {
+                Ok(ref uri) if !uri.is_playable() => warn!("Skipping preset '{}': {} can't be played", key, uri),
+                Ok(uri) => presets.push((key.to_owned(), uri)),
+                Err(e) => warn!("Skipping preset '{}': {}", key, e),
+            }
         }
     }
 
-    Some(Settings { secret: secret.to_string(), client_id: client_id.to_string(), port: port,
-                    access_token: access, refresh_token: refresh, expire_utc: expire_utc,
-                    presets: presets})
-}
+    let server = match conf.section(Some("server".to_owned())) {
+        Some(section) => {
+            let port = match section.get("port").map(|p| p.parse::<u16>()) {
+                Some(Ok(port)) => port,
+                _ => return Err(ConnectrError::Config("[server] port must be an integer".to_string())),
+            };
+            // Anyone who can reach the port could control the account.
+            let token = match section.get("token") {
+                Some(token) if token.len() >= 16 => token.clone(),
+                _ => return Err(ConnectrError::Config("[server] token must be at least 16 characters".to_string())),
+            };
+            Some(ServerSettings {
+                port: port,
+                bind: section.get("bind").cloned().unwrap_or("127.0.0.1".to_string()),
+                token: token,
+            })
+        },
+        None => None,
+    };
+
+    let mut accounts = Vec::<Account>::new();
+    if let Some(section) = conf.section(Some("accounts".to_owned())) {
+        for (key, value) in section {
+            if !valid_account_name(key) {
+                return Err(ConnectrError::Config(format!(
+                    "[accounts] name '{}' may only contain letters, numbers, '-' and '_'", key)));
+            }
+            let label = match value.trim().len() {
+                0 => key.to_owned(),
+                _ => value.trim().to_owned(),
+            };
+            accounts.push(Account { name: key.to_owned(), label: label });
+        }
+    }
+    // The ini file's order is lost, so go by name: the first starts active.
+    accounts.sort_by(|a, b| a.name.cmp(&b.name));
 
-pub type SettingsError = String;
-pub fn save_tokens(access: &str, refresh: &str, expire_utc: u64) -> Result<(), SettingsError> {
-    let mut conf = Ini::load_from_file(&inifile()).unwrap();
-    conf.with_section(Some("tokens".to_owned()))
-        .set("access", access)
-        .set("refresh", refresh)
-        .set("expire", expire_utc.to_string());
-    conf.write_to_file(&inifile()).unwrap();
-    Ok(())
+    let secret = match secret.starts_with('<') {
+        true => None,
+        false => Some(secret.to_string()),
+    };
+    Ok(Settings { secret: secret, client_id: client_id.to_string(), pkce: pkce, port: port,
+                  bind: bind, redirect_host: redirect_host, headless: headless,
+                  legacy_tokens: legacy_tokens, presets: presets, accounts: accounts,
+                  ipc: ipc, ipc_socket: ipc_socket, server: server})
 }
@@ -0,0 +1,124 @@
+#[cfg(test)]
+mod test;
+
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use super::error::ConnectrError;
+use super::fsutil::{io_error, write_atomic};
+
+const TOKENFILE: &'static str = ".connectr.tokens";
+
+/// OAuth credentials for one Spotify account.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Tokens {
+    pub access: String,
+    pub refresh: String,
+    /// Expiration time (UTC) of the access token
+    pub expire_utc: u64,
+    /// Space-separated scopes Spotify granted, if known.  Files saved by
+    /// older versions don't have it.
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+/// Persistent storage for OAuth credentials.
+///
+/// `load` returns `Ok(None)` if nothing has been saved yet.
+pub trait TokenStore {
+    fn load(&self) -> Result<Option<Tokens>, ConnectrError>;
+    fn save(&self, tokens: &Tokens) -> Result<(), ConnectrError>;
+}
+
+/// Stores tokens as JSON in a file only readable by the current user.
+///
+/// Saving writes a temporary file alongside the real one and renames it
+/// into place, so a crash never leaves behind a half-written file.
+pub struct FileTokenStore {
+    path: PathBuf,
+}
+
+impl FileTokenStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileTokenStore {
+        FileTokenStore { path: path.into() }
+    }
+    /// `~/.connectr.tokens`, or the working directory if there is no home.
+    pub fn default_path() -> PathBuf {
+        match env::home_dir() {
+            Some(home) => home.join(TOKENFILE),
+            None => PathBuf::from(TOKENFILE),
+        }
+    }
+    /// Store for one of several named accounts, in `~/.connectr.<account>.tokens`.
+    /// An account named `default` shares the single-account file.
+    pub fn for_account(account: &str) -> FileTokenStore {
+        if account == "default" {
+            return FileTokenStore::default();
+        }
+        let name = format!(".connectr.{}.tokens", account);
+        match env::home_dir() {
+            Some(home) => FileTokenStore::new(home.join(name)),
+            None => FileTokenStore::new(name),
+        }
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Default for FileTokenStore {
+    fn default() -> FileTokenStore {
+        FileTokenStore::new(FileTokenStore::default_path())
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> Result<Option<Tokens>, ConnectrError> {
+        let mut file = match fs::File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_error(&self.path, "read", e)),
+        };
+        let mut contents = String::new();
+        if let Err(e) = file.read_to_string(&mut contents) {
+            return Err(io_error(&self.path, "read", e));
+        }
+        Ok(Some(serde_json::from_str(&contents)?))
+    }
+    fn save(&self, tokens: &Tokens) -> Result<(), ConnectrError> {
+        let contents = serde_json::to_string_pretty(tokens)?;
+        write_atomic(&self.path, contents.as_bytes())
+    }
+}
+
+/// Keeps tokens in memory only.  Clones share the same tokens, so tests can
+/// hold on to one to inspect what was saved.
+#[derive(Clone, Default)]
+pub struct MemoryTokenStore {
+    tokens: Rc<RefCell<Option<Tokens>>>,
+}
+
+impl MemoryTokenStore {
+    pub fn new(tokens: Option<Tokens>) -> MemoryTokenStore {
+        MemoryTokenStore { tokens: Rc::new(RefCell::new(tokens)) }
+    }
+    pub fn tokens(&self) -> Option<Tokens> {
+        self.tokens.borrow().clone()
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn load(&self) -> Result<Option<Tokens>, ConnectrError> {
+        Ok(self.tokens())
+    }
+    fn save(&self, tokens: &Tokens) -> Result<(), ConnectrError> {
+        *self.tokens.borrow_mut() = Some(tokens.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use std::env;
+    use std::fs;
+
+    fn tokens() -> Tokens {
+        Tokens {
+            access: "access".to_string(),
+            refresh: "refresh".to_string(),
+            expire_utc: 1492766270,
+            scope: Some("streaming".to_string()),
+        }
+    }
+
+    fn temp_store(name: &str) -> FileTokenStore {
+        let path = env::temp_dir().join(format!("connectr-{}-{}.tokens", name, ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        FileTokenStore::new(path)
+    }
+
+    #[test]
+    fn test_file_store_roundtrip() {
+        let store = temp_store("roundtrip");
+        assert_eq!(store.load().unwrap(), None);
+        store.save(&tokens()).unwrap();
+        assert_eq!(store.load().unwrap(), Some(tokens()));
+
+        let mut updated = tokens();
+        updated.access = "new_access".to_string();
+        store.save(&updated).unwrap();
+        assert_eq!(store.load().unwrap(), Some(updated));
+        assert!(!::fsutil::temp_path(store.path()).exists());
+        let _ = fs::remove_file(store.path());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_store_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let store = temp_store("private");
+        store.save(&tokens()).unwrap();
+        let mode = fs::metadata(store.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let _ = fs::remove_file(store.path());
+    }
+
+    #[test]
+    fn test_file_store_corrupt() {
+        let store = temp_store("corrupt");
+        fs::write(store.path(), "not json").unwrap();
+        match store.load() {
+            Err(ConnectrError::Deserialize(_)) => {},
+            _ => assert!(false),
+        }
+        let _ = fs::remove_file(store.path());
+    }
+
+    #[test]
+    fn test_memory_store_shared() {
+        let store = MemoryTokenStore::new(None);
+        let other = store.clone();
+        store.save(&tokens()).unwrap();
+        assert_eq!(other.load().unwrap(), Some(tokens()));
+    }
+}
@@ -0,0 +1,116 @@
+use super::SpotifyConnectr;
+use super::super::settings::{self, Account, Settings};
+use super::super::tokens::{FileTokenStore, TokenStore};
+use super::super::error::ConnectrError;
+
+struct Profile<'a> {
+    account: Account,
+    spotify: SpotifyConnectr<'a>,
+    connected: bool,
+}
+
+/// Several Spotify accounts, each with its own credentials and refresh
+/// timer, of which one at a time is active.
+///
+/// Accounts are connected the first time they become active, so logging in
+/// to an account that is never used is not required.
+pub struct SpotifyAccounts<'a> {
+    profiles: Vec<Profile<'a>>,
+    active: usize,
+}
+
+impl<'a> SpotifyAccounts<'a> {
+    pub fn new() -> Result<SpotifyAccounts<'a>, ConnectrError> {
+        let settings = settings::read_settings()?;
+        SpotifyAccounts::from_settings(settings)
+    }
+    /// One account per entry in `[accounts]`, or a single account named
+    /// `default` if there are none.  The first account starts active, and
+    /// takes over the unnamed account's tokens if it has none of its own.
+    pub fn from_settings(settings: Settings) -> Result<SpotifyAccounts<'a>, ConnectrError> {
+        let mut accounts = settings.accounts.clone();
+        if accounts.is_empty() {
+            accounts.push(Account { name: "default".to_string(), label: "Default".to_string() });
+        }
+        let mut clients = Vec::new();
+        for (idx, account) in accounts.into_iter().enumerate() {
+            let mut settings = settings.clone();
+            // Tokens from an old connectr.ini, or from before `[accounts]` was
+            // added, belong to the account that starts active.
+            if idx > 0 {
+                settings.legacy_tokens = None;
+            }
+            else if settings.legacy_tokens.is_none() && account.name != "default" {
+                settings.legacy_tokens = FileTokenStore::default().load().unwrap_or(None);
+            }
+            let store = FileTokenStore::for_account(&account.name);
+            clients.push((account, SpotifyConnectr::from_settings_with_store(settings, Box::new(store))));
+        }
+        SpotifyAccounts::from_clients(clients)
+    }
+    /// Manage already constructed clients.  The first one starts active.
+    pub fn from_clients(clients: Vec<(Account, SpotifyConnectr<'a>)>) -> Result<SpotifyAccounts<'a>, ConnectrError> {
+        if clients.is_empty() {
+            return Err(ConnectrError::Config("no Spotify accounts configured".to_string()));
+        }
+        let profiles = clients.into_iter()
+            .map(|(account, spotify)| Profile { account: account, spotify: spotify, connected: false })
+            .collect();
+        Ok(SpotifyAccounts { profiles: profiles, active: 0 })
+    }
+    pub fn accounts(&self) -> Vec<&Account> {
+        self.profiles.iter().map(|p| &p.account).collect()
+    }
+    pub fn active_account(&self) -> &Account {
+        &self.profiles[self.active].account
+    }
+    pub fn active(&self) -> &SpotifyConnectr<'a> {
+        &self.profiles[self.active].spotify
+    }
+    pub fn active_mut(&mut self) -> &mut SpotifyConnectr<'a> {
+        &mut self.profiles[self.active].spotify
+    }
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut SpotifyConnectr<'a>> {
+        self.profiles.iter_mut().find(|p| p.account.name == name).map(|p| &mut p.spotify)
+    }
+    /// Connect the active account, if it isn't already.
+    pub fn connect(&mut self) -> Result<(), ConnectrError> {
+        let active = self.active;
+        self.connect_profile(active)
+    }
+    fn connect_profile(&mut self, idx: usize) -> Result<(), ConnectrError> {
+        let profile = &mut self.profiles[idx];
+        if !profile.connected {
+            info!("Connecting Spotify account '{}'.", profile.account.name);
+            profile.spotify.connect()?;
+            profile.connected = true;
+        }
+        Ok(())
+    }
+    /// Make the named account active, connecting it first if necessary.
+    /// The active account is unchanged if connecting fails.
+    pub fn switch(&mut self, name: &str) -> Result<(), ConnectrError> {
+        let idx = match self.profiles.iter().position(|p| p.account.name == name) {
+            Some(idx) => idx,
+            None => return Err(ConnectrError::Config(format!("no account named '{}'", name))),
+        };
+        self.connect_profile(idx)?;
+        info!("Switched to Spotify account '{}'.", name);
+        self.active = idx;
+        Ok(())
+    }
+    /// Refresh the credentials of any connected account that needs it.
+    /// Never blocks.  Returns the first error, after trying every account.
+    pub fn await_once(&mut self) -> Result<(), ConnectrError> {
+        let mut result = Ok(());
+        for profile in self.profiles.iter_mut().filter(|p| p.connected) {
+            if let Err(e) = profile.spotify.await_once(false) {
+                warn!("Failed to refresh credentials for '{}': {}", profile.account.name, e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
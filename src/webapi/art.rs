@@ -0,0 +1,210 @@
+extern crate serde_json;
+extern crate sha2;
+use self::sha2::{Digest, Sha256};
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+
+use super::{SpotifyConnectr, PlaybackItem};
+use super::super::http;
+use super::super::error::ConnectrError;
+use super::super::fsutil::{io_error, write_atomic};
+
+const ARTDIR: &'static str = ".connectr.art";
+const INDEX: &'static str = "index.json";
+
+/// Size limit of `ArtCache::default()`.
+pub const DEFAULT_ART_CACHE_BYTES: u64 = 20 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ArtEntry {
+    url: String,
+    file: String,
+    bytes: u64,
+    /// Value of `ArtIndex::clock` when this was last used.
+    last_used: u64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct ArtIndex {
+    clock: u64,
+    /// Keyed by the SHA-256 of the URL, in hex.
+    entries: BTreeMap<String, ArtEntry>,
+}
+
+/// Downloaded album art and episode artwork, kept on disk.
+///
+/// Files are named after a hash of their URL, and listed in an `index.json`
+/// alongside them.  When the total size goes over the limit, the least
+/// recently used images are deleted.
+pub struct ArtCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: ArtIndex,
+    /// Whether `index` has changes not yet written to disk.
+    dirty: bool,
+}
+
+fn cache_key(url: &str) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(url.as_bytes());
+    hasher.result().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn extension(content_type: Option<&str>) -> &'static str {
+    match content_type.map(|t| t.split(';').next().unwrap_or("").trim()) {
+        Some("image/png") => "png",
+        Some("image/webp") => "webp",
+        // Spotify serves JPEG.
+        _ => "jpg",
+    }
+}
+
+impl ArtCache {
+    /// Cache in `dir`, holding at most `max_bytes` of images.  The directory
+    /// is created when the first image is stored.
+    pub fn new<P: Into<PathBuf>>(dir: P, max_bytes: u64) -> ArtCache {
+        let dir = dir.into();
+        let mut contents = String::new();
+        // A missing or damaged index just means starting over.
+        let index = fs::File::open(dir.join(INDEX))
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .ok()
+            .and_then(|_| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        ArtCache { dir: dir, max_bytes: max_bytes, index: index, dirty: false }
+    }
+    /// `~/.connectr.art`, or the working directory if there is no home.
+    pub fn default_dir() -> PathBuf {
+        match env::home_dir() {
+            Some(home) => home.join(ARTDIR),
+            None => PathBuf::from(ARTDIR),
+        }
+    }
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    /// Total size of all cached images.
+    pub fn total_bytes(&self) -> u64 {
+        self.index.entries.values().map(|e| e.bytes).sum()
+    }
+    pub fn len(&self) -> usize {
+        self.index.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.index.entries.is_empty()
+    }
+    /// Local path of the image at `url`, if it is cached.
+    pub fn get(&mut self, url: &str) -> Option<PathBuf> {
+        let key = cache_key(url);
+        let path = match self.index.entries.get(&key) {
+            Some(entry) => self.dir.join(&entry.file),
+            None => return None,
+        };
+        if !path.is_file() {
+            // Deleted behind our back.
+            self.index.entries.remove(&key);
+            let _ = self.save_index();
+            return None;
+        }
+        // Hits are frequent, so their order is only saved with the next
+        // change, or when the cache is dropped.
+        self.index.clock += 1;
+        let clock = self.index.clock;
+        if let Some(entry) = self.index.entries.get_mut(&key) {
+            entry.last_used = clock;
+        }
+        self.dirty = true;
+        Some(path)
+    }
+    /// Store the image downloaded from `url`, and return its local path.
+    pub fn insert(&mut self, url: &str, content_type: Option<&str>, data: &[u8]) -> Result<PathBuf, ConnectrError> {
+        if let Err(e) = fs::create_dir_all(&self.dir) {
+            return Err(io_error(&self.dir, "create", e));
+        }
+        let key = cache_key(url);
+        let file = format!("{}.{}", key, extension(content_type));
+        let path = self.dir.join(&file);
+        write_atomic(&path, data)?;
+        self.index.clock += 1;
+        let entry = ArtEntry {
+            url: url.to_string(),
+            file: file,
+            bytes: data.len() as u64,
+            last_used: self.index.clock,
+        };
+        if let Some(old) = self.index.entries.insert(key.clone(), entry) {
+            if old.file != self.index.entries[&key].file {
+                let _ = fs::remove_file(self.dir.join(&old.file));
+            }
+        }
+        self.evict(&key);
+        self.save_index()?;
+        Ok(path)
+    }
+    /// Delete least recently used images until under the size limit.  The
+    /// image just stored (`keep`) stays, even if it's bigger than the limit.
+    fn evict(&mut self, keep: &str) {
+        while self.total_bytes() > self.max_bytes {
+            let oldest = self.index.entries.iter()
+                .filter(|&(k, _)| k != keep)
+                .min_by_key(|&(_, e)| e.last_used)
+                .map(|(k, _)| k.clone());
+            let key = match oldest {
+                Some(key) => key,
+                None => break,
+            };
+            if let Some(entry) = self.index.entries.remove(&key) {
+                let _ = fs::remove_file(self.dir.join(&entry.file));
+            }
+        }
+    }
+    fn save_index(&mut self) -> Result<(), ConnectrError> {
+        let contents = serde_json::to_string(&self.index)?;
+        write_atomic(&self.dir.join(INDEX), contents.as_bytes())?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for ArtCache {
+    fn drop(&mut self) {
+        if self.dirty {
+            let _ = self.save_index();
+        }
+    }
+}
+
+impl Default for ArtCache {
+    fn default() -> ArtCache {
+        ArtCache::new(ArtCache::default_dir(), DEFAULT_ART_CACHE_BYTES)
+    }
+}
+
+impl<'a> SpotifyConnectr<'a> {
+    /// Local path of the image at `url`, downloading it into `cache` first
+    /// if needed.
+    pub fn fetch_image(&self, cache: &mut ArtCache, url: &str) -> Result<PathBuf, ConnectrError> {
+        if let Some(path) = cache.get(url) {
+            return Ok(path);
+        }
+        // Images are public; don't hand the access token to the CDN.
+        let response = self.http(url, "", "", http::HttpMethod::GET, http::AccessToken::None);
+        match response.code {
+            Some(200...299) => cache.insert(url, response.header("content-type"), &response.bytes),
+            _ => Err(ConnectrError::from_response(&response)),
+        }
+    }
+    /// Local path of the album art of a track, or the artwork of an episode,
+    /// in the size closest to `size` pixels wide.  None if it has no images.
+    pub fn fetch_art(&self, cache: &mut ArtCache, item: &PlaybackItem,
+                     size: u32) -> Result<Option<PathBuf>, ConnectrError> {
+        match item.image(size) {
+            Some(image) => self.fetch_image(cache, &image.url).map(Some),
+            None => Ok(None),
+        }
+    }
+}
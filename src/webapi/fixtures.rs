@@ -0,0 +1,111 @@
+//! Scripted clients and canned responses, shared by the tests of every module.
+
+use super::{SpotifyAccounts, SpotifyConnectr};
+use super::super::control::CommandQueue;
+use super::super::SpotifyEndpoints;
+use super::super::http::{RetryPolicy, ScriptedTransport};
+use super::super::settings::{Account, Settings};
+use super::super::tokens::{MemoryTokenStore, Tokens};
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Endpoints of the fake Spotify server run by the webapi tests.
+pub const TEST_API: SpotifyEndpoints = SpotifyEndpoints {
+    scopes: "user-read-private streaming user-read-playback-state",
+    authorize: "http://127.0.0.1:9799/en/authorize",
+    token: "http://127.0.0.1:9799/api/token",
+    devices: "http://127.0.0.1:9799/v1/me/player/devices",
+    player_state: "http://127.0.0.1:9799/v1/me/player",
+    play: "http://127.0.0.1:9799/v1/me/player/play",
+    pause: "http://127.0.0.1:9799/v1/me/player/pause",
+    next: "http://127.0.0.1:9799/v1/me/player/next",
+    previous: "http://127.0.0.1:9799/v1/me/player/previous",
+    seek: "http://127.0.0.1:9799/v1/me/player/seek",
+    volume: "http://127.0.0.1:9799/v1/me/player/volume",
+    shuffle: "http://127.0.0.1:9799/v1/me/player/shuffle",
+    repeat: "http://127.0.0.1:9799/v1/me/player/repeat",
+    player: "http://127.0.0.1:9799/v1/me/player",
+    search: "http://127.0.0.1:9799/v1/search",
+    saved_tracks: "http://127.0.0.1:9799/v1/me/tracks",
+    saved_tracks_contains: "http://127.0.0.1:9799/v1/me/tracks/contains",
+    saved_albums: "http://127.0.0.1:9799/v1/me/albums",
+    saved_albums_contains: "http://127.0.0.1:9799/v1/me/albums/contains",
+    me: "http://127.0.0.1:9799/v1/me",
+    my_playlists: "http://127.0.0.1:9799/v1/me/playlists",
+    playlists: "http://127.0.0.1:9799/v1/playlists",
+    users: "http://127.0.0.1:9799/v1/users",
+    queue: "http://127.0.0.1:9799/v1/me/player/queue",
+    recently_played: "http://127.0.0.1:9799/v1/me/player/recently-played",
+    top: "http://127.0.0.1:9799/v1/me/top",
+};
+
+pub const DEVICES: &'static str = r#"{"devices": [
+    {"id": "abc123", "is_active": true, "is_restricted": false, "name": "Kitchen",
+     "type": "Speaker", "volume_percent": 40},
+    {"id": "def456", "is_active": false, "is_restricted": false, "name": "Office Computer",
+     "type": "Computer", "volume_percent": 100}]}"#;
+
+/// Playing a track on the "Kitchen" device of `DEVICES`.
+pub const PLAYER_STATE: &'static str = r#"{"timestamp": 1, "progress_ms": 1000, "is_playing": true,
+    "shuffle_state": false, "repeat_state": "off", "context": null,
+    "currently_playing_type": "track",
+    "device": {"id": "abc123", "is_active": true, "is_restricted": false, "name": "Kitchen",
+        "type": "Speaker", "volume_percent": 40},
+    "item": {"type": "track", "id": "6rqhFgbbKwnb9MLmUQDhG6", "name": "Magnet's Coil",
+        "uri": "spotify:track:6rqhFgbbKwnb9MLmUQDhG6", "duration_ms": 60000,
+        "artists": [{"name": "Sebadoh", "uri": "spotify:artist:d"}],
+        "album": {"name": "Bakesale", "uri": "spotify:album:1pzvBxYgT6OVwJLtHkrdQK", "images": []}}}"#;
+
+pub fn test_tokens() -> Tokens {
+    Tokens {
+        access: "valid_access_code".to_string(),
+        refresh: "valid_refresh_code".to_string(),
+        expire_utc: 0,
+        scope: None,
+    }
+}
+
+/// A client that answers from the returned transport's script.  Failed
+/// requests are retried without waiting.
+pub fn scripted_client<'a>(settings: Settings, tokens: Tokens) -> (SpotifyConnectr<'a>, ScriptedTransport) {
+    let transport = ScriptedTransport::new();
+    let retry = RetryPolicy { base_delay_ms: 0, jitter: false, .. RetryPolicy::default() };
+    let store = MemoryTokenStore::new(Some(tokens));
+    let spotify = SpotifyConnectr::from_settings_with_store(settings, Box::new(store))
+        .with_api(TEST_API)
+        .with_transport(Box::new(transport.clone()))
+        .with_retry_policy(retry);
+    (spotify, transport)
+}
+
+pub fn scripted_spotify<'a>() -> (SpotifyConnectr<'a>, ScriptedTransport) {
+    scripted_client(Settings::for_test(), test_tokens())
+}
+
+/// A single account named "home", which doesn't retry, so that each
+/// scripted response answers exactly one command.
+pub fn scripted_accounts<'a>(settings: Settings) -> (SpotifyAccounts<'a>, ScriptedTransport) {
+    let (spotify, transport) = scripted_client(settings, test_tokens());
+    let spotify = spotify.with_retry_policy(RetryPolicy::none());
+    let account = Account { name: "home".to_string(), label: "Home".to_string() };
+    (SpotifyAccounts::from_clients(vec![(account, spotify)]).unwrap(), transport)
+}
+
+/// Run `client` on another thread, running the commands it queues until it's
+/// done.  Returns its result, and whether any command changed what is playing.
+pub fn serve_commands<T, F>(queue: &CommandQueue, accounts: &mut SpotifyAccounts, client: F) -> (T, bool)
+    where T: Send + 'static, F: FnOnce() -> T + Send + 'static {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || tx.send(client()).unwrap());
+    let mut changed = false;
+    loop {
+        changed |= queue.process(accounts);
+        match rx.try_recv() {
+            Ok(result) => return (result, changed),
+            Err(mpsc::TryRecvError::Empty) => thread::sleep(Duration::from_millis(5)),
+            Err(e) => panic!("client failed: {}", e),
+        }
+    }
+}
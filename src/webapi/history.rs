@@ -0,0 +1,121 @@
+use super::{SpotifyConnectr, Page, CursorPage, PageIter, QueryString, PlayHistory};
+use super::{ConnectPlaybackItem, ConnectPlaybackArtist};
+use super::super::error::ConnectrError;
+
+/// Where to start reading listening history.  Times are Unix timestamps in
+/// milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HistoryCursor {
+    /// Tracks played before this time, newest first.
+    Before(u64),
+    /// Tracks played after this time.
+    After(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TopItemType {
+    Artists,
+    Tracks,
+}
+impl ToString for TopItemType {
+    fn to_string(&self) -> String {
+        match self {
+            &TopItemType::Artists => "artists".to_string(),
+            &TopItemType::Tracks => "tracks".to_string(),
+        }
+    }
+}
+
+/// Period that top items are calculated over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeRange {
+    /// About the last 4 weeks
+    Short,
+    /// About the last 6 months
+    Medium,
+    /// Several years
+    Long,
+}
+impl ToString for TimeRange {
+    fn to_string(&self) -> String {
+        match self {
+            &TimeRange::Short => "short_term".to_string(),
+            &TimeRange::Medium => "medium_term".to_string(),
+            &TimeRange::Long => "long_term".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TopItems {
+    Artists(Page<ConnectPlaybackArtist>),
+    Tracks(Page<ConnectPlaybackItem>),
+}
+
+fn history_query(limit: Option<u32>, cursor: Option<HistoryCursor>) -> String {
+    let (before, after) = match cursor {
+        Some(HistoryCursor::Before(t)) => (Some(t.to_string()), None),
+        Some(HistoryCursor::After(t)) => (None, Some(t.to_string())),
+        None => (None, None),
+    };
+    QueryString::new()
+        .add_opt("limit", limit.map(|l| l.to_string()))
+        .add_opt("before", before)
+        .add_opt("after", after)
+        .build()
+}
+
+fn top_query(time_range: TimeRange, limit: Option<u32>, offset: Option<u32>) -> String {
+    QueryString::new()
+        .add("time_range", time_range)
+        .add_opt("limit", limit.map(|l| l.to_string()))
+        .add_opt("offset", offset.map(|o| o.to_string()))
+        .build()
+}
+
+impl<'a> SpotifyConnectr<'a> {
+    /// Tracks the user recently played.  `limit` is 1-50, default 20.
+    /// Without a cursor, the most recent tracks are returned.
+    pub fn recently_played(&mut self, limit: Option<u32>,
+                           cursor: Option<HistoryCursor>) -> Result<CursorPage<PlayHistory>, ConnectrError> {
+        self.get_json(self.api.get().recently_played, &history_query(limit, cursor))
+    }
+    /// Iterate over recently played tracks, starting at `cursor`.  Without a
+    /// cursor, or with `Before`, pages are followed back in time until
+    /// Spotify runs out of history, which is only about the last 50 tracks.
+    /// Spotify's next page always goes further back, so with `After` only
+    /// the first page is returned; ask for up to 50 tracks with `limit`.
+    pub fn recently_played_iter<'s>(&'s mut self, limit: Option<u32>,
+                                    cursor: Option<HistoryCursor>) -> PageIter<'s, 'a, CursorPage<PlayHistory>> {
+        let url = self.api.get().recently_played;
+        let iter = self.page_iter(url, &history_query(limit, cursor));
+        match cursor {
+            Some(HistoryCursor::After(_)) => iter.first_page_only(),
+            _ => iter,
+        }
+    }
+    /// The user's most listened to artists or tracks.  `limit` is 1-50,
+    /// default 20.  Use `top_artists_iter()` or `top_tracks_iter()` to go
+    /// through all of them.
+    pub fn top_items(&mut self, item_type: TopItemType, time_range: TimeRange,
+                     limit: Option<u32>, offset: Option<u32>) -> Result<TopItems, ConnectrError> {
+        let url = format!("{}/{}", self.api.get().top, item_type.to_string());
+        let query = top_query(time_range, limit, offset);
+        match item_type {
+            TopItemType::Artists => self.get_json(&url, &query).map(TopItems::Artists),
+            TopItemType::Tracks => self.get_json(&url, &query).map(TopItems::Tracks),
+        }
+    }
+    /// Iterate over the user's top artists, `limit` per request.
+    pub fn top_artists_iter<'s>(&'s mut self, time_range: TimeRange,
+                                limit: Option<u32>) -> PageIter<'s, 'a, Page<ConnectPlaybackArtist>> {
+        let url = format!("{}/{}", self.api.get().top, TopItemType::Artists.to_string());
+        self.page_iter(&url, &top_query(time_range, limit, None))
+    }
+    /// Iterate over the user's top tracks, `limit` per request.
+    pub fn top_tracks_iter<'s>(&'s mut self, time_range: TimeRange,
+                               limit: Option<u32>) -> PageIter<'s, 'a, Page<ConnectPlaybackItem>> {
+        let url = format!("{}/{}", self.api.get().top, TopItemType::Tracks.to_string());
+        self.page_iter(&url, &top_query(time_range, limit, None))
+    }
+}
@@ -0,0 +1,74 @@
+extern crate serde_json;
+
+use super::{SpotifyConnectr, QueryString, Page, PageIter, SavedTrack, SavedAlbum};
+use super::spotify_command;
+use super::super::http;
+use super::super::error::ConnectrError;
+
+// Spotify accepts at most this many IDs per library request.
+const MAX_IDS: usize = 50;
+
+fn id_query(ids: &[&str]) -> Result<String, ConnectrError> {
+    match ids.len() {
+        0 => Err(ConnectrError::Config("no IDs given".to_string())),
+        x if x > MAX_IDS => Err(ConnectrError::Config(format!("at most {} IDs per request", MAX_IDS))),
+        _ => Ok(QueryString::new().add("ids", ids.join(",")).build()),
+    }
+}
+
+fn page_query(limit: Option<u32>, offset: Option<u32>) -> String {
+    QueryString::new()
+        .add_opt("limit", limit.map(|l| l.to_string()))
+        .add_opt("offset", offset.map(|o| o.to_string()))
+        .build()
+}
+
+impl<'a> SpotifyConnectr<'a> {
+    /// Tracks in the user's library, most recently saved first.  `limit`
+    /// is 1-50, default 20.
+    pub fn saved_tracks(&mut self, limit: Option<u32>, offset: Option<u32>) -> Result<Page<SavedTrack>, ConnectrError> {
+        self.get_json(self.api.get().saved_tracks, &page_query(limit, offset))
+    }
+    /// Iterate over the whole library, `limit` tracks per request.
+    pub fn saved_tracks_iter<'s>(&'s mut self, limit: Option<u32>) -> PageIter<'s, 'a, Page<SavedTrack>> {
+        let url = self.api.get().saved_tracks;
+        self.page_iter(url, &page_query(limit, None))
+    }
+    /// Save tracks, by Spotify ID, to the user's library ("Like" them).
+    pub fn save_tracks(&mut self, ids: &[&str]) -> Result<(), ConnectrError> {
+        let query = id_query(ids)?;
+        spotify_command(self.api_request(self.api.get().saved_tracks, &query, "", http::HttpMethod::PUT))
+    }
+    pub fn remove_saved_tracks(&mut self, ids: &[&str]) -> Result<(), ConnectrError> {
+        let query = id_query(ids)?;
+        spotify_command(self.api_request(self.api.get().saved_tracks, &query, "", http::HttpMethod::DELETE))
+    }
+    /// Whether each track is in the user's library, in the same order as `ids`.
+    pub fn check_saved_tracks(&mut self, ids: &[&str]) -> Result<Vec<bool>, ConnectrError> {
+        let query = id_query(ids)?;
+        self.get_json(self.api.get().saved_tracks_contains, &query)
+    }
+    /// Albums in the user's library, most recently saved first.  `limit`
+    /// is 1-50, default 20.
+    pub fn saved_albums(&mut self, limit: Option<u32>, offset: Option<u32>) -> Result<Page<SavedAlbum>, ConnectrError> {
+        self.get_json(self.api.get().saved_albums, &page_query(limit, offset))
+    }
+    pub fn saved_albums_iter<'s>(&'s mut self, limit: Option<u32>) -> PageIter<'s, 'a, Page<SavedAlbum>> {
+        let url = self.api.get().saved_albums;
+        self.page_iter(url, &page_query(limit, None))
+    }
+    /// Save albums, by Spotify ID, to the user's library.
+    pub fn save_albums(&mut self, ids: &[&str]) -> Result<(), ConnectrError> {
+        let query = id_query(ids)?;
+        spotify_command(self.api_request(self.api.get().saved_albums, &query, "", http::HttpMethod::PUT))
+    }
+    pub fn remove_saved_albums(&mut self, ids: &[&str]) -> Result<(), ConnectrError> {
+        let query = id_query(ids)?;
+        spotify_command(self.api_request(self.api.get().saved_albums, &query, "", http::HttpMethod::DELETE))
+    }
+    /// Whether each album is in the user's library, in the same order as `ids`.
+    pub fn check_saved_albums(&mut self, ids: &[&str]) -> Result<Vec<bool>, ConnectrError> {
+        let query = id_query(ids)?;
+        self.get_json(self.api.get().saved_albums_contains, &query)
+    }
+}
@@ -1,147 +1,118 @@
 #[cfg(test)]
 mod test;
+#[cfg(test)]
+pub mod fixtures;
+
+mod model;
+pub use self::model::*;
+mod accounts;
+pub use self::accounts::SpotifyAccounts;
+mod search;
+pub use self::search::{SearchType, SearchResults};
+mod library;
+mod playlists;
+mod queue;
+pub use self::queue::Queue;
+mod history;
+pub use self::history::HistoryCursor;
+pub use self::history::{TopItemType, TimeRange, TopItems};
+mod art;
+pub use self::art::{ArtCache, DEFAULT_ART_CACHE_BYTES};
+mod uri;
+pub use self::uri::{SpotifyUri, UriKind};
+mod paging;
+pub use self::paging::{Paged, PageIter};
 
 extern crate time;
 extern crate timer;
 extern crate chrono;
 
-use std::fmt;
-use std::iter;
-use std::process;
+use std::thread;
 use std::cell::Cell;
 use std::collections::BTreeMap;
 use std::sync::mpsc::{channel, Receiver};
 
+extern crate serde;
 extern crate serde_json;
+use self::serde::de::DeserializeOwned;
 use self::serde_json::Value;
 
+extern crate url;
+use self::url::form_urlencoded;
+
 use super::http;
 use super::settings;
+use super::tokens::{Tokens, TokenStore, FileTokenStore};
 use super::SpotifyEndpoints;
 use super::SPOTIFY_API;
 use super::http::HttpResponse;
+use super::error::ConnectrError;
 
 pub type DeviceId = String;
-pub type SpotifyResponse = HttpResponse;
 
-pub fn parse_spotify_token(json: &str) -> (String, String, u64) {
-    let json_data: Value = serde_json::from_str(json).unwrap();
-    let access_token = json_data["access_token"].as_str().unwrap();
+/// Seconds until a failed token refresh is tried again, when the failure
+/// wasn't Spotify revoking the credentials.
+const REFRESH_RETRY_SECS: i64 = 60;
+
+/// Parse a token response into the access token, refresh token (empty if
+/// none was sent), seconds until expiry, and granted scopes if listed.
+pub fn parse_spotify_token(json: &str) -> Result<(String, String, u64, Option<String>), ConnectrError> {
+    let json_data: Value = serde_json::from_str(json)?;
+    let access_token = match json_data.get("access_token").and_then(|j| j.as_str()) {
+        Some(token) => token,
+        None => return Err(ConnectrError::Deserialize("token response missing access_token".to_string())),
+    };
     let refresh_token = match json_data.get("refresh_token") {
-        Some(j) => j.as_str().unwrap(),
+        Some(j) => j.as_str().unwrap_or(""),
         None => "",
     };
-    let expires_in = json_data["expires_in"].as_u64().unwrap();
-    (String::from(access_token),String::from(refresh_token), expires_in)
-}
-
-#[derive(Deserialize, Debug)]
-pub struct ConnectDevice {
-    pub id: String,
-    pub is_active: bool,
-    pub is_restricted: bool,
-    pub name: String,
-    #[serde(rename(deserialize = "type"))]
-    pub device_type: String,
-    pub volume_percent: Option<u32>
+    let expires_in = match json_data.get("expires_in").and_then(|j| j.as_u64()) {
+        Some(expires) => expires,
+        None => return Err(ConnectrError::Deserialize("token response missing expires_in".to_string())),
+    };
+    let scope = json_data.get("scope").and_then(|j| j.as_str()).map(String::from);
+    Ok((String::from(access_token),String::from(refresh_token), expires_in, scope))
 }
 
-impl fmt::Display for ConnectDevice {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:<40} <{}>", self.name, self.id)
+/// Whether a failed token refresh means logging in again.  Network trouble,
+/// rate limits and server errors don't: the saved credentials are still good.
+fn refresh_needs_login(err: &ConnectrError) -> bool {
+    match *err {
+        ConnectrError::AuthExpired | ConnectrError::Authorization { .. } => true,
+        ConnectrError::Http { error: Some(ref e), .. } => e.reason.as_ref().map(|r| r == "invalid_grant").unwrap_or(false),
+        _ => false,
     }
 }
 
-#[derive(Deserialize)]
-pub struct ConnectDeviceList {
-    pub devices: Vec<ConnectDevice>,
-}
-
-impl fmt::Display for ConnectDeviceList {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for dev in &self.devices {
-            let _ = write!(f, "{}\n", dev);
-        }
-        Ok(())
-    }
+/// Scopes in the space-separated `required` list that are not in `granted`.
+pub fn missing_scopes<'b>(required: &'b str, granted: &str) -> Vec<&'b str> {
+    let granted: Vec<&str> = granted.split_whitespace().collect();
+    required.split_whitespace().filter(|s| !granted.contains(s)).collect()
 }
 
-impl<'a> iter::IntoIterator for &'a ConnectDeviceList {
-    type Item = &'a ConnectDevice;
-    //type IntoIter = ::std::vec::IntoIter<ConnectDevice>;
-    type IntoIter = ::std::slice::Iter<'a, ConnectDevice>;
-    fn into_iter(self) -> Self::IntoIter {
-        (&self.devices).into_iter()
+/// Turn an HTTP response into its body on 2xx, or a typed error otherwise.
+fn spotify_result(response: HttpResponse) -> Result<String, ConnectrError> {
+    match response.code {
+        Some(200...299) => match response.data {
+            Ok(body) => Ok(body),
+            Err(e) => Err(ConnectrError::Transport(e)),
+        },
+        _ => Err(ConnectrError::from_response(&response)),
     }
 }
 
-impl iter::IntoIterator for ConnectDeviceList {
-    type Item = ConnectDevice;
-    type IntoIter = ::std::vec::IntoIter<ConnectDevice>;
-    fn into_iter(self) -> Self::IntoIter {
-        self.devices.into_iter()
+/// Short description of a response for log messages.
+fn response_status(response: &HttpResponse) -> String {
+    match (response.code, &response.data) {
+        (Some(code), _) => format!("HTTP {}", code),
+        (None, &Err(ref e)) => e.clone(),
+        (None, &Ok(_)) => "no response".to_string(),
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub struct ConnectPlaybackArtist {
-    pub name: String,
-    pub uri: String,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct ConnectPlaybackAlbum {
-    pub name: String,
-    pub uri: String,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct ConnectPlaybackItem {
-    pub duration_ms: u32,
-    pub name: String,
-    pub uri: String,
-    pub album: ConnectPlaybackAlbum,
-    pub artists: Vec<ConnectPlaybackArtist>,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct ConnectContext {
-    uri: String,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct PlayerState {
-    pub timestamp: u64,
-    pub device: ConnectDevice,
-    pub progress_ms: Option<u32>,
-    pub is_playing: bool,
-    pub item: ConnectPlaybackItem,
-    pub shuffle_state: bool,
-    pub repeat_state: String,
-    pub context: Option<ConnectContext>,
-}
-
-impl fmt::Display for PlayerState {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let play_state = match self.is_playing {
-            true => "Playing",
-            false => "Paused",
-        };
-        let volume = match self.device.volume_percent {
-            Some(x) => x.to_string(),
-            None => "???".to_string(),
-        };
-        let position: f64 = match self.progress_ms {
-            Some(x) => (x as f64)/1000.0,
-            None => 0.0,
-        };
-        let duration: f64 = (self.item.duration_ms as f64) / 1000.0;
-        let progress: f64 = position/duration*100.0;
-        write!(f, "{} on {} [Volume {}%]\n{} <{}>\n{}s / {}s ({:.1}%)\n",
-               play_state, self.device.name, volume,
-               &self.item.name, &self.item.uri,
-               position, duration, progress)
-    }
+/// Like `spotify_result()`, but for commands where the body is meaningless.
+fn spotify_command(response: HttpResponse) -> Result<(), ConnectrError> {
+    spotify_result(response).map(|_| ())
 }
 
 #[derive(Serialize)]
@@ -174,11 +145,11 @@ impl PlayContext {
     pub fn new() -> PlayContext {
         PlayContext::default()
     }
-    pub fn context_uri<'a>(&'a mut self, uri: &str) -> &'a mut PlayContext {
+    pub fn context_uri<'a>(&'a mut self, uri: &SpotifyUri) -> &'a mut PlayContext {
         self.context_uri = Some(uri.to_string());
         self
     }
-    pub fn uri<'a>(&'a mut self, uri: &str) -> &'a mut PlayContext {
+    pub fn uri<'a>(&'a mut self, uri: &SpotifyUri) -> &'a mut PlayContext {
         match self.uris {
             Some(ref mut uris) => uris.push(uri.to_string()),
             None => {
@@ -200,7 +171,7 @@ impl PlayContext {
         };
         self
     }
-    pub fn offset_uri<'a>(&'a mut self, uri: &str) -> &'a mut PlayContext {
+    pub fn offset_uri<'a>(&'a mut self, uri: &SpotifyUri) -> &'a mut PlayContext {
         match self.offset {
             Some(ref mut o) => o.uri = Some(uri.to_string()),
             None => {
@@ -242,12 +213,16 @@ impl QueryString {
                 0 => { } // '?' inserted in HTTP layer
                 _ => { s = s + "&"; }
             }
+            // Values can be free text (search queries), so escape anything
+            // that would break up the query.  Spaces as %20, not '+'.
+            let val = form_urlencoded::byte_serialize(val.as_bytes()).collect::<String>().replace("+", "%20");
             s = s + &format!("{}={}", key, val);
         }
         s
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SpotifyRepeat {
     Off,
     Track,
@@ -271,11 +246,15 @@ struct DeviceIdList {
 
 pub struct SpotifyConnectr<'a> {
     api: Cell<SpotifyEndpoints<'a>>,
+    transport: Box<http::HttpTransport>,
+    retry: http::RetryPolicy,
     settings: settings::Settings,
+    tokens: Box<TokenStore>,
     auth_code: String,
     access_token: Option<String>,
     refresh_token: Option<String>,
     expire_utc: Option<u64>,
+    scope: Option<String>,
     device: Option<DeviceId>,
 
     refresh_timer: timer::Timer,
@@ -284,30 +263,129 @@ pub struct SpotifyConnectr<'a> {
 }
 
 impl<'a> SpotifyConnectr<'a> {
-    pub fn new() -> SpotifyConnectr<'a> {
-        let settings = match settings::read_settings() {
-            Some(s) => s,
-            None => process::exit(0),
-        };
-        let expire = settings.expire_utc;
-        let access = settings.access_token.clone();
-        let refresh = settings.refresh_token.clone();
-        SpotifyConnectr {api:Cell::new(SPOTIFY_API),
+    pub fn new() -> Result<SpotifyConnectr<'a>, ConnectrError> {
+        let settings = settings::read_settings()?;
+        Ok(SpotifyConnectr::from_settings(settings))
+    }
+    /// Create a client that keeps its credentials in `~/.connectr.tokens`.
+    pub fn from_settings(settings: settings::Settings) -> SpotifyConnectr<'a> {
+        SpotifyConnectr::from_settings_with_store(settings, Box::new(FileTokenStore::default()))
+    }
+    /// Create a client that loads and saves credentials with the given store.
+    pub fn from_settings_with_store(settings: settings::Settings,
+                                    tokens: Box<TokenStore>) -> SpotifyConnectr<'a> {
+        let mut spotify = SpotifyConnectr {api:Cell::new(SPOTIFY_API),
+                         transport: Box::new(http::CurlTransport::new()),
+                         retry: http::RetryPolicy::default(),
                          settings: settings,
+                         tokens: tokens,
                          auth_code: String::new(),
-                         access_token: access,
-                         refresh_token: refresh,
-                         expire_utc: expire,
+                         access_token: None,
+                         refresh_token: None,
+                         expire_utc: None,
+                         scope: None,
                          device: None,
                          refresh_timer: timer::Timer::new(),
                          refresh_timer_guard: None,
-                         refresh_timer_channel: None}
+                         refresh_timer_channel: None};
+        spotify.load_tokens();
+        spotify
     }
     #[cfg(test)]
     fn with_api(self, api: SpotifyEndpoints<'a>) -> SpotifyConnectr<'a> {
         self.api.set(api);
         self
     }
+    /// Settings this client was created with.
+    pub fn settings(&self) -> &settings::Settings {
+        &self.settings
+    }
+    /// Send all Web API traffic through the given transport instead of curl.
+    pub fn with_transport(mut self, transport: Box<http::HttpTransport>) -> SpotifyConnectr<'a> {
+        self.transport = transport;
+        self
+    }
+    /// Change how failed requests are retried.  See `http::RetryPolicy`.
+    pub fn with_retry_policy(mut self, retry: http::RetryPolicy) -> SpotifyConnectr<'a> {
+        self.retry = retry;
+        self
+    }
+    fn load_tokens(&mut self) {
+        let stored = match self.tokens.load() {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!("Ignoring saved credentials: {}", e);
+                None
+            },
+        };
+        // Tokens used to be saved in connectr.ini, or by the single unnamed
+        // account.  Move them to the store.
+        let stored = match (stored, self.settings.legacy_tokens.take()) {
+            (None, Some(legacy)) => {
+                info!("Migrating credentials saved by an older connectr.  Any [tokens] section in connectr.ini can be removed.");
+                if let Err(e) = self.tokens.save(&legacy) {
+                    warn!("Failed to migrate credentials: {}", e);
+                }
+                Some(legacy)
+            },
+            (stored, _) => stored,
+        };
+        self.access_token = stored.as_ref().map(|t| t.access.clone());
+        self.refresh_token = stored.as_ref().map(|t| t.refresh.clone());
+        self.expire_utc = stored.as_ref().map(|t| t.expire_utc);
+        self.scope = stored.and_then(|t| t.scope);
+    }
+    fn save_tokens(&self) -> Result<(), ConnectrError> {
+        match (self.access_token.as_ref(), self.refresh_token.as_ref()) {
+            (Some(access), Some(refresh)) => {
+                self.tokens.save(&Tokens {
+                    access: access.clone(),
+                    refresh: refresh.clone(),
+                    expire_utc: self.expire_utc.unwrap_or(0),
+                    scope: self.scope.clone(),
+                })
+            },
+            _ => Ok(()),
+        }
+    }
+    fn http(&self, url: &str, query: &str, body: &str,
+            method: http::HttpMethod, access_token: http::AccessToken) -> HttpResponse {
+        // Only the accounts service takes form posts.  Web API endpoints,
+        // reached with the bearer token, want their parameters in the URL.
+        let web_api = match access_token {
+            http::AccessToken::Bearer(_) => true,
+            _ => false,
+        };
+        let mut request = http::HttpRequest::new(url, query, body, method, access_token);
+        if web_api {
+            request = request.query_in_url();
+        }
+        // Every Web API request body is JSON.
+        if body.len() > 0 {
+            request = request.header("Content-Type", "application/json");
+        }
+        let mut attempt = 1;
+        loop {
+            let response = self.transport.request(&request);
+            match self.retry.retry_delay(attempt, method, &response) {
+                Some(delay) => {
+                    let delay_ms = delay.as_secs() * 1000 + (delay.subsec_nanos() / 1_000_000) as u64;
+                    warn!("{:?} {} failed ({}), retrying in {} ms (attempt {}/{})",
+                          method, url, response_status(&response), delay_ms,
+                          attempt + 1, self.retry.max_attempts);
+                    thread::sleep(delay);
+                    attempt += 1;
+                },
+                None => {
+                    if attempt > 1 {
+                        info!("{:?} {} finished with {} after {} attempts",
+                              method, url, response_status(&response), attempt);
+                    }
+                    return response;
+                },
+            }
+        }
+    }
     //fn is_token_expired(&self) -> bool {
     //    let now = time::now_utc().to_timespec().sec as u64;
     //    let expire_utc = self.expire_utc.unwrap_or(0);
@@ -328,47 +406,59 @@ impl<'a> SpotifyConnectr<'a> {
     fn schedule_token_refresh(&mut self) -> Result<(), ()> {
         match self.expire_utc {
             Some(expire_utc) => {
-                let (tx, rx) = channel::<()>();
-                self.refresh_timer_channel = Some(rx);
                 let expire_offset = self.expire_utc_to_offset(expire_utc) as i64;
                 // Refresh a bit before it expires
                 let expire_offset = match expire_offset {
                     x if x > 60 => x - 60,
                     _ => expire_offset,
                 };
-                let expire_offset = chrono::Duration::seconds(expire_offset);
-                info!("Refreshing Spotify credentials in {} sec", expire_offset.num_seconds());
-                let closure = move || { tx.send(()).unwrap(); };
-                self.refresh_timer_guard = Some(self.refresh_timer.schedule_with_delay(expire_offset, closure));
+                self.schedule_refresh_in(expire_offset);
                 Ok(())
             }
             _ => Err(())
         }
     }
-    pub fn refresh_access_token(&mut self) {
+    fn schedule_refresh_in(&mut self, secs: i64) {
+        let (tx, rx) = channel::<()>();
+        self.refresh_timer_channel = Some(rx);
+        info!("Refreshing Spotify credentials in {} sec", secs);
+        let closure = move || { let _ = tx.send(()); };
+        self.refresh_timer_guard = Some(self.refresh_timer.schedule_with_delay(chrono::Duration::seconds(secs), closure));
+    }
+    pub fn refresh_access_token(&mut self) -> Result<(), ConnectrError> {
         info!("Refreshing Spotify credentials now.");
         self.refresh_timer_channel = None;
         match self.refresh_oauth_tokens() {
-            Some((access_token, expires_in)) => {
+            Ok((access_token, refresh_token, expires_in, scope)) => {
                 self.access_token = Some(access_token.clone());
+                // Spotify may rotate the refresh token, invalidating the old one.
+                if refresh_token.len() > 0 {
+                    self.refresh_token = Some(refresh_token);
+                }
                 self.expire_utc = Some(self.expire_offset_to_utc(expires_in));
+                if scope.is_some() {
+                    self.scope = scope;
+                }
             },
-            None => {
-                self.authenticate();
+            Err(ref e) if refresh_needs_login(e) => {
+                warn!("Refresh failed: {}.  Requesting new credentials.", e);
+                return self.authenticate();
+            }
+            Err(e) => {
+                warn!("Refresh failed: {}.  Trying again in {} sec.", e, REFRESH_RETRY_SECS);
+                self.schedule_refresh_in(REFRESH_RETRY_SECS);
+                return Err(e);
             }
         }
-        //let (access_token, expires_in) = ;
 
+        if !self.has_required_scopes() {
+            return self.authenticate();
+        }
         info!("Refreshed credentials.");
         let _ = self.schedule_token_refresh();
-
-        let access_token = self.access_token.clone().unwrap();
-        let refresh_token = self.refresh_token.clone().unwrap();
-        let _ = settings::save_tokens(&access_token,
-                                      &refresh_token,
-                                      self.expire_utc.unwrap());
+        self.save_tokens()
     }
-    pub fn await_once(&mut self, blocking: bool) {
+    pub fn await_once(&mut self, blocking: bool) -> Result<(), ConnectrError> {
         // Choose between blocking or non-blocking receive.
         let recv_fn: Box<Fn(&Receiver<()>) -> bool> = match blocking {
             true  => Box::new(move |rx| { match rx.recv() { Ok(_) => true, Err(_) => false } }),
@@ -379,38 +469,68 @@ impl<'a> SpotifyConnectr<'a> {
             _ => false,
         };
         if !need_refresh {
-            return ()
+            return Ok(())
         }
-        self.refresh_access_token();
+        self.refresh_access_token()
     }
-    pub fn authenticate(&mut self) {
+    pub fn authenticate(&mut self) -> Result<(), ConnectrError> {
         info!("Requesting fresh credentials.");
-        self.auth_code = http::authenticate(self.api.get().scopes, self.api.get().authorize, &self.settings);
-        let (access_token, refresh_token, expires_in) = self.request_oauth_tokens(&self.auth_code, &self.settings);
+        let pkce = match self.settings.pkce {
+            true => Some(http::PkceChallenge::new()),
+            false => None,
+        };
+        self.auth_code = http::authenticate(self.api.get().scopes, self.api.get().authorize,
+                                            &self.settings, pkce.as_ref())?;
+        let verifier = pkce.as_ref().map(|p| p.verifier.as_str());
+        let (access_token, refresh_token, expires_in, scope) = self.request_oauth_tokens(&self.auth_code, &self.settings, verifier)?;
         let expire_utc = self.expire_offset_to_utc(expires_in);
-        let _ = settings::save_tokens(&access_token, &refresh_token, expire_utc);
         self.access_token = Some(access_token);
         self.refresh_token = Some(refresh_token);
         self.expire_utc = Some(expire_utc);
+        self.scope = scope;
         let _ = self.schedule_token_refresh();
+        self.save_tokens()
+    }
+    /// Exchange an authorization code for tokens.  `code_verifier` must be
+    /// given if the code was requested with a PKCE challenge, in which case
+    /// the client secret is not sent.
+    pub fn request_oauth_tokens(&self, auth_code: &str, settings: &settings::Settings,
+                                code_verifier: Option<&str>) -> Result<(String, String, u64, Option<String>), ConnectrError> {
+        let secret = match code_verifier {
+            Some(_) => None,
+            None => settings.secret.clone(),
+        };
+        let query = QueryString::new()
+            .add("grant_type", "authorization_code")
+            .add("code", auth_code)
+            .add("redirect_uri", settings.redirect_uri())
+            .add("client_id", settings.client_id.clone())
+            .add_opt("client_secret", secret)
+            .add_opt("code_verifier", code_verifier.map(|v| v.to_string()))
+            .build();
+        let json_response = self.http(self.api.get().token, &query, "", http::HttpMethod::POST,
+                                      http::AccessToken::None);
+        parse_spotify_token(&spotify_result(json_response)?)
+    }
+    /// False if the saved credentials are known to lack a scope this version
+    /// of connectr asks for.  Credentials without a recorded scope are
+    /// assumed fine until a refresh says otherwise.
+    fn has_required_scopes(&self) -> bool {
+        let granted = match self.scope {
+            Some(ref scope) => scope,
+            None => return true,
+        };
+        let missing = missing_scopes(self.api.get().scopes, granted);
+        if missing.len() > 0 {
+            warn!("Saved credentials are missing permissions ({}).  Logging in again.", missing.join(" "));
+            return false;
+        }
+        true
     }
-    pub fn request_oauth_tokens(&self, auth_code: &str, settings: &settings::Settings) -> (String, String, u64) {
-    let query = QueryString::new()
-        .add("grant_type", "authorization_code")
-        .add("code", auth_code)
-        .add("redirect_uri", format!("http://127.0.0.1:{}", settings.port))
-        .add("client_id", settings.client_id.clone())
-        .add("client_secret", settings.secret.clone())
-        .build();
-        let json_response = http::http(self.api.get().token, &query, "", http::HttpMethod::POST,
-                                       http::AccessToken::None).unwrap();
-        parse_spotify_token(&json_response)
-    }
-    pub fn connect(&mut self) {
-        if self.access_token.is_some() {
+    pub fn connect(&mut self) -> Result<(), ConnectrError> {
+        if self.access_token.is_some() && self.has_required_scopes() {
             info!("Reusing saved credentials.");
-            self.refresh_access_token();
-            return ()
+            return self.refresh_access_token();
         }
         self.authenticate()
     }
@@ -426,112 +546,136 @@ impl<'a> SpotifyConnectr<'a> {
             None => http::AccessToken::None,
         }
     }
-    pub fn refresh_oauth_tokens(&self) -> Option<(String, u64)> {
+    /// Exchange the refresh token for an access token.  Returns the access
+    /// token, the new refresh token (empty if Spotify kept the old one),
+    /// seconds until expiry, and the granted scopes if Spotify listed them.
+    pub fn refresh_oauth_tokens(&self) -> Result<(String, String, u64, Option<String>), ConnectrError> {
+        let refresh_token = match self.refresh_token {
+            Some(ref token) => token,
+            None => return Err(ConnectrError::AuthExpired),
+        };
+        // PKCE refreshes are authorized by the client ID alone.
+        let secret = match self.settings.pkce {
+            true => None,
+            false => self.settings.secret.clone(),
+        };
         let query = QueryString::new()
             .add("grant_type", "refresh_token")
-            .add("refresh_token", self.refresh_token.as_ref().unwrap())
+            .add("refresh_token", refresh_token)
             .add("client_id", self.settings.client_id.clone())
-            .add("client_secret", self.settings.secret.clone())
+            .add_opt("client_secret", secret)
             .build();
-        let json_response = http::http(self.api.get().token, &query, "",
+        let json_response = self.http(self.api.get().token, &query, "",
                                        http::HttpMethod::POST, http::AccessToken::None);
-        match json_response.code {
-            Some(200) => {
-                let (access_token, _, expires_in) = parse_spotify_token(&json_response.data.unwrap());
-                Some((access_token, expires_in))
-            },
-            _ => { None }
+        parse_spotify_token(&spotify_result(json_response)?)
+    }
+    /// Send an authorized Web API request.  If the access token was rejected,
+    /// credentials are refreshed and the request is sent once more.
+    fn api_request(&mut self, url: &str, query: &str, body: &str,
+                   method: http::HttpMethod) -> HttpResponse {
+        let response = self.http(url, query, body, method, self.bearer_token());
+        if response.code != Some(401) {
+            return response;
         }
-    }
-    pub fn request_device_list(&mut self) -> Option<ConnectDeviceList> {
-        let json_response = http::http(self.api.get().devices, "", "",
-                                       http::HttpMethod::GET, self.bearer_token());
-        match json_response.code {
-            Some(200) => serde_json::from_str(&json_response.data.unwrap()).unwrap(),
-            Some(401) => {
-                warn!("Access token invalid.  Attempting to reauthenticate.");
-                self.refresh_access_token();
-                None
-            }
-            _ => None
+        warn!("Access token invalid.  Attempting to reauthenticate.");
+        match self.refresh_access_token() {
+            Ok(()) => self.http(url, query, body, method, self.bearer_token()),
+            Err(e) => {
+                warn!("Reauthentication failed: {}", e);
+                response
+            },
         }
     }
-    pub fn request_player_state(&mut self) -> Option<PlayerState> {
-        let json_response = http::http(self.api.get().player_state, "", "",
-                                       http::HttpMethod::GET, self.bearer_token());
-        match json_response.code {
-            Some(200) => serde_json::from_str(&json_response.data.unwrap()).unwrap(),
-            Some(401) => {
-                warn!("Access token invalid.  Attempting to reauthenticate.");
-                self.refresh_access_token();
-                None
+    /// GET a JSON response from the Web API.
+    fn get_json<T: DeserializeOwned>(&mut self, url: &str, query: &str) -> Result<T, ConnectrError> {
+        let json_response = self.api_request(url, query, "", http::HttpMethod::GET);
+        spotify_result(json_response)
+            .and_then(|body| serde_json::from_str(&body).map_err(ConnectrError::from))
+    }
+    pub fn request_device_list(&mut self) -> Result<ConnectDeviceList, ConnectrError> {
+        self.get_json(self.api.get().devices, "")
+    }
+    /// Current playback, or `None` if no device is active.
+    pub fn request_player_state(&mut self) -> Result<Option<PlayerState>, ConnectrError> {
+        let query = QueryString::new().add("additional_types", "episode").build();
+        let json_response = self.api_request(self.api.get().player_state, &query, "", http::HttpMethod::GET);
+        spotify_result(json_response).and_then(|body| {
+            match body.trim().len() {
+                0 => Ok(None), // 204 No Content
+                _ => serde_json::from_str(&body).map(Some).map_err(ConnectrError::from),
             }
-            _ => None
-        }
+        })
     }
     pub fn set_target_device(&mut self, device: Option<DeviceId>) {
         self.device = device;
     }
-    pub fn play(&self, context: Option<&PlayContext>) -> SpotifyResponse {
+    /// Device that commands are sent to.  None means the active device.
+    pub fn target_device(&self) -> Option<&str> {
+        self.device.as_ref().map(|d| d.as_str())
+    }
+    pub fn play(&mut self, context: Option<&PlayContext>) -> Result<(), ConnectrError> {
         let query = QueryString::new().add_opt("device_id", self.device.clone()).build();
         let body = match context {
-            Some(x) => serde_json::to_string(x).unwrap(),
+            Some(x) => serde_json::to_string(x)?,
             None => String::new(),
         };
-        http::http(self.api.get().play, &query, &body, http::HttpMethod::PUT, self.bearer_token())
+        spotify_command(self.api_request(self.api.get().play, &query, &body, http::HttpMethod::PUT))
     }
-    pub fn pause(&self) -> SpotifyResponse {
+    pub fn pause(&mut self) -> Result<(), ConnectrError> {
         let query = QueryString::new().add_opt("device_id", self.device.clone()).build();
-        http::http(self.api.get().pause, &query, "", http::HttpMethod::PUT, self.bearer_token())
+        spotify_command(self.api_request(self.api.get().pause, &query, "", http::HttpMethod::PUT))
     }
-    pub fn next(&self) -> SpotifyResponse {
+    pub fn next(&mut self) -> Result<(), ConnectrError> {
         let query = QueryString::new().add_opt("device_id", self.device.clone()).build();
-        http::http(self.api.get().next, &query, "", http::HttpMethod::POST, self.bearer_token())
+        spotify_command(self.api_request(self.api.get().next, &query, "", http::HttpMethod::POST))
     }
-    pub fn previous(&self) -> SpotifyResponse {
+    pub fn previous(&mut self) -> Result<(), ConnectrError> {
         let query = QueryString::new().add_opt("device_id", self.device.clone()).build();
-        http::http(self.api.get().previous, &query, "", http::HttpMethod::POST, self.bearer_token())
+        spotify_command(self.api_request(self.api.get().previous, &query, "", http::HttpMethod::POST))
     }
-    pub fn seek(&self, position: u32) -> SpotifyResponse {
+    pub fn seek(&mut self, position: u32) -> Result<(), ConnectrError> {
         let query = QueryString::new()
             .add_opt("device_id", self.device.clone())
             .add("position_ms", position)
             .build();
-        http::http(self.api.get().seek, &query, "", http::HttpMethod::PUT, self.bearer_token())
+        spotify_command(self.api_request(self.api.get().seek, &query, "", http::HttpMethod::PUT))
     }
-    pub fn volume(&self, volume: u32) -> SpotifyResponse {
+    pub fn volume(&mut self, volume: u32) -> Result<(), ConnectrError> {
         let query = QueryString::new()
             .add_opt("device_id", self.device.clone())
             .add("volume_percent", volume)
             .build();
-        http::http(self.api.get().volume, &query, "", http::HttpMethod::PUT, self.bearer_token())
+        spotify_command(self.api_request(self.api.get().volume, &query, "", http::HttpMethod::PUT))
     }
-    pub fn shuffle(&self, shuffle: bool) -> SpotifyResponse {
+    pub fn shuffle(&mut self, shuffle: bool) -> Result<(), ConnectrError> {
         let query = QueryString::new()
             .add_opt("device_id", self.device.clone())
             .add("state", shuffle)
             .build();
-        http::http(self.api.get().shuffle, &query, "", http::HttpMethod::PUT, self.bearer_token())
+        spotify_command(self.api_request(self.api.get().shuffle, &query, "", http::HttpMethod::PUT))
     }
-    pub fn repeat(&self, repeat: SpotifyRepeat) -> SpotifyResponse {
+    pub fn repeat(&mut self, repeat: SpotifyRepeat) -> Result<(), ConnectrError> {
         let query = QueryString::new()
             .add_opt("device_id", self.device.clone())
             .add("state", repeat)
             .build();
-        http::http(self.api.get().repeat, &query, "", http::HttpMethod::PUT, self.bearer_token())
+        spotify_command(self.api_request(self.api.get().repeat, &query, "", http::HttpMethod::PUT))
     }
-    pub fn transfer_multi(&mut self, devices: Vec<String>, play: bool) -> SpotifyResponse {
-        let device = devices[0].clone();
-        let body = serde_json::to_string(&DeviceIdList {device_ids: devices, play: play}).unwrap();
+    pub fn transfer_multi(&mut self, devices: Vec<String>, play: bool) -> Result<(), ConnectrError> {
+        let device = match devices.first() {
+            Some(device) => device.clone(),
+            None => return Err(ConnectrError::Config("no device to transfer to".to_string())),
+        };
+        let body = serde_json::to_string(&DeviceIdList {device_ids: devices, play: play})?;
         self.set_target_device(Some(device));
-        http::http(self.api.get().player, "", &body, http::HttpMethod::PUT, self.bearer_token())
+        spotify_command(self.api_request(self.api.get().player, "", &body, http::HttpMethod::PUT))
     }
-    pub fn transfer(&mut self, device: String, play: bool) -> SpotifyResponse {
-        let body = serde_json::to_string(&DeviceIdList {device_ids: vec![device.clone()], play: play}).unwrap();
+    pub fn transfer(&mut self, device: String, play: bool) -> Result<(), ConnectrError> {
+        let body = serde_json::to_string(&DeviceIdList {device_ids: vec![device.clone()], play: play})?;
         self.set_target_device(Some(device));
-        http::http(self.api.get().player, "", &body, http::HttpMethod::PUT, self.bearer_token())
+        spotify_command(self.api_request(self.api.get().player, "", &body, http::HttpMethod::PUT))
     }
-    pub fn get_presets(&mut self) -> &Vec<(String,String)> {
+    pub fn get_presets(&mut self) -> &Vec<(String,SpotifyUri)> {
         &self.settings.presets
     }
 }
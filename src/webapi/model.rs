@@ -0,0 +1,561 @@
+extern crate serde;
+extern crate serde_json;
+use self::serde_json::Value;
+
+use std::fmt;
+use std::iter;
+use std::collections::BTreeMap;
+
+/// Links to an object outside the Web API, keyed by type (e.g. `spotify`).
+pub type ExternalUrls = BTreeMap<String, String>;
+
+/// Standard identifiers of an object, keyed by type (e.g. `isrc`, `upc`).
+pub type ExternalIds = BTreeMap<String, String>;
+
+/// Cover art or a profile picture.  Sizes are in pixels, and missing if
+/// unknown.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Image {
+    pub url: String,
+    pub height: Option<u32>,
+    pub width: Option<u32>,
+}
+
+/// The smallest image at least `size` pixels wide, or the largest if none
+/// are that big.  Images of unknown size are treated as very large.
+pub fn closest_image(images: &[Image], size: u32) -> Option<&Image> {
+    let width = |i: &Image| i.width.unwrap_or(u32::max_value());
+    images.iter().filter(|i| width(i) >= size).min_by_key(|i| width(i))
+        .or_else(|| images.iter().max_by_key(|i| width(i)))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Followers {
+    pub href: Option<String>,
+    pub total: u32,
+}
+
+/// Why content can't be played, e.g. `market`, `product` or `explicit`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Restrictions {
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Copyright {
+    pub text: String,
+    /// `C` for copyright, `P` for performance copyright
+    #[serde(rename = "type")]
+    pub copyright_type: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectDevice {
+    pub id: String,
+    pub is_active: bool,
+    #[serde(default)]
+    pub is_private_session: bool,
+    pub is_restricted: bool,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    pub volume_percent: Option<u32>,
+}
+
+impl fmt::Display for ConnectDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<40} <{}>", self.name, self.id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectDeviceList {
+    pub devices: Vec<ConnectDevice>,
+}
+
+impl fmt::Display for ConnectDeviceList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for dev in &self.devices {
+            let _ = write!(f, "{}\n", dev);
+        }
+        Ok(())
+    }
+}
+
+impl ConnectDeviceList {
+    /// Look up a device by ID or name.  Names are matched ignoring case, and
+    /// may be abbreviated as long as only one device matches.
+    pub fn find(&self, name: &str) -> Option<&ConnectDevice> {
+        if let Some(dev) = self.devices.iter().find(|d| d.id == name) {
+            return Some(dev);
+        }
+        let name = name.to_lowercase();
+        if let Some(dev) = self.devices.iter().find(|d| d.name.to_lowercase() == name) {
+            return Some(dev);
+        }
+        let mut partial = self.devices.iter().filter(|d| d.name.to_lowercase().contains(&name));
+        match (partial.next(), partial.next()) {
+            (Some(dev), None) => Some(dev),
+            _ => None,
+        }
+    }
+    pub fn active(&self) -> Option<&ConnectDevice> {
+        self.devices.iter().find(|d| d.is_active)
+    }
+}
+
+impl<'a> iter::IntoIterator for &'a ConnectDeviceList {
+    type Item = &'a ConnectDevice;
+    //type IntoIter = ::std::vec::IntoIter<ConnectDevice>;
+    type IntoIter = ::std::slice::Iter<'a, ConnectDevice>;
+    fn into_iter(self) -> Self::IntoIter {
+        (&self.devices).into_iter()
+    }
+}
+
+impl iter::IntoIterator for ConnectDeviceList {
+    type Item = ConnectDevice;
+    type IntoIter = ::std::vec::IntoIter<ConnectDevice>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.devices.into_iter()
+    }
+}
+
+/// An artist.  Genres, images, popularity and followers are only present
+/// where Spotify returns the full artist, e.g. search and top artists.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectPlaybackArtist {
+    /// Missing for artists of local files.
+    pub id: Option<String>,
+    pub name: String,
+    pub uri: String,
+    pub href: Option<String>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    pub popularity: Option<u32>,
+    pub followers: Option<Followers>,
+}
+
+/// An album.  Genres, label, popularity and copyrights are only present
+/// where Spotify returns the full album.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectPlaybackAlbum {
+    /// Missing for albums of local files.
+    pub id: Option<String>,
+    pub name: String,
+    pub uri: String,
+    pub href: Option<String>,
+    /// `album`, `single` or `compilation`
+    pub album_type: Option<String>,
+    #[serde(default)]
+    pub artists: Vec<ConnectPlaybackArtist>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    /// `1981`, `1981-12` or `1981-12-15`, depending on the precision.
+    pub release_date: Option<String>,
+    /// `year`, `month` or `day`
+    pub release_date_precision: Option<String>,
+    pub total_tracks: Option<u32>,
+    #[serde(default)]
+    pub available_markets: Vec<String>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+    #[serde(default)]
+    pub external_ids: ExternalIds,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    pub label: Option<String>,
+    pub popularity: Option<u32>,
+    #[serde(default)]
+    pub copyrights: Vec<Copyright>,
+}
+
+impl ConnectPlaybackAlbum {
+    /// Cover art closest to `size` pixels wide.
+    pub fn image(&self, size: u32) -> Option<&Image> {
+        closest_image(&self.images, size)
+    }
+}
+
+/// A track.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectPlaybackItem {
+    /// Missing for local files, which can't be saved or queued by ID.
+    pub id: Option<String>,
+    pub duration_ms: u32,
+    pub name: String,
+    pub uri: String,
+    pub href: Option<String>,
+    pub album: ConnectPlaybackAlbum,
+    pub artists: Vec<ConnectPlaybackArtist>,
+    #[serde(default)]
+    pub explicit: bool,
+    pub popularity: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub track_number: Option<u32>,
+    #[serde(default)]
+    pub is_local: bool,
+    /// Only present when a market was given with the request.
+    pub is_playable: Option<bool>,
+    pub restrictions: Option<Restrictions>,
+    pub preview_url: Option<String>,
+    #[serde(default)]
+    pub available_markets: Vec<String>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+    #[serde(default)]
+    pub external_ids: ExternalIds,
+}
+
+/// Spotify sometimes returns `null` in place of items that are unavailable,
+/// e.g. deleted playlists in search results.  Drop them.
+fn skip_null_items<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where D: serde::Deserializer<'de>, T: serde::Deserialize<'de> {
+    let items: Vec<Option<T>> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(items.into_iter().filter_map(|i| i).collect())
+}
+
+/// Skip items that aren't tracks or episodes (ads, etc.) rather than
+/// failing the whole list.
+pub(super) fn known_items<'de, D>(deserializer: D) -> Result<Vec<PlaybackItem>, D::Error>
+    where D: serde::Deserializer<'de> {
+    let items: Vec<Value> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(items.into_iter().filter_map(|i| serde_json::from_value(i).ok()).collect())
+}
+
+/// Treat an item that isn't a track or episode as missing.
+pub(super) fn known_item<'de, D>(deserializer: D) -> Result<Option<PlaybackItem>, D::Error>
+    where D: serde::Deserializer<'de> {
+    let item: Option<Value> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(item.and_then(|i| serde_json::from_value(i).ok()))
+}
+
+/// One page of a longer list, as returned by search and library endpoints.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound(deserialize = "T: serde::Deserialize<'de>"))]
+pub struct Page<T> {
+    pub href: String,
+    #[serde(deserialize_with = "skip_null_items")]
+    pub items: Vec<T>,
+    pub limit: u32,
+    pub next: Option<String>,
+    pub offset: u32,
+    pub previous: Option<String>,
+    pub total: u32,
+}
+
+impl<T> Page<T> {
+    /// Offset to request to get the following page, if there is one.
+    pub fn next_offset(&self) -> Option<u32> {
+        match self.next {
+            Some(_) => Some(self.offset + self.limit),
+            None => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Cursors {
+    pub after: Option<String>,
+    pub before: Option<String>,
+}
+
+/// One page of a list that is paged by cursor instead of offset.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CursorPage<T> {
+    pub href: String,
+    pub items: Vec<T>,
+    pub limit: u32,
+    pub next: Option<String>,
+    pub cursors: Option<Cursors>,
+    pub total: Option<u32>,
+}
+
+/// The album, artist, playlist or show something is played from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectContext {
+    pub uri: String,
+    #[serde(rename = "type")]
+    pub context_type: Option<String>,
+    pub href: Option<String>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+/// A podcast.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectPlaybackShow {
+    pub id: Option<String>,
+    pub name: String,
+    pub uri: String,
+    pub href: Option<String>,
+    pub publisher: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    #[serde(default)]
+    pub explicit: bool,
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// `audio` or `mixed`
+    pub media_type: Option<String>,
+    pub total_episodes: Option<u32>,
+    #[serde(default)]
+    pub available_markets: Vec<String>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+/// How far the user got through an episode.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResumePoint {
+    pub fully_played: bool,
+    pub resume_position_ms: u32,
+}
+
+/// A podcast episode.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectPlaybackEpisode {
+    pub id: Option<String>,
+    pub duration_ms: u32,
+    pub name: String,
+    pub uri: String,
+    pub href: Option<String>,
+    pub description: Option<String>,
+    /// Missing where the show is already known, e.g. in search results.
+    pub show: Option<ConnectPlaybackShow>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    pub release_date: Option<String>,
+    pub release_date_precision: Option<String>,
+    #[serde(default)]
+    pub explicit: bool,
+    #[serde(default)]
+    pub languages: Vec<String>,
+    pub is_playable: Option<bool>,
+    pub audio_preview_url: Option<String>,
+    /// Only present with the `user-read-playback-position` scope.
+    pub resume_point: Option<ResumePoint>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+/// A track or podcast episode, as found in the player and queue.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum PlaybackItem {
+    #[serde(rename = "track")]
+    Track(ConnectPlaybackItem),
+    #[serde(rename = "episode")]
+    Episode(ConnectPlaybackEpisode),
+}
+
+impl PlaybackItem {
+    pub fn id(&self) -> Option<&str> {
+        match *self {
+            PlaybackItem::Track(ref t) => t.id.as_ref().map(|s| s.as_str()),
+            PlaybackItem::Episode(ref e) => e.id.as_ref().map(|s| s.as_str()),
+        }
+    }
+    pub fn name(&self) -> &str {
+        match *self {
+            PlaybackItem::Track(ref t) => &t.name,
+            PlaybackItem::Episode(ref e) => &e.name,
+        }
+    }
+    pub fn uri(&self) -> &str {
+        match *self {
+            PlaybackItem::Track(ref t) => &t.uri,
+            PlaybackItem::Episode(ref e) => &e.uri,
+        }
+    }
+    pub fn duration_ms(&self) -> u32 {
+        match *self {
+            PlaybackItem::Track(ref t) => t.duration_ms,
+            PlaybackItem::Episode(ref e) => e.duration_ms,
+        }
+    }
+    /// Artists of a track, joined with commas, or the show of an episode.
+    pub fn artist_or_show(&self) -> String {
+        match *self {
+            PlaybackItem::Track(ref t) =>
+                t.artists.iter().map(|a| a.name.as_str()).collect::<Vec<&str>>().join(", "),
+            PlaybackItem::Episode(ref e) =>
+                e.show.as_ref().map(|s| s.name.clone()).unwrap_or_default(),
+        }
+    }
+    /// Album of a track, or publisher of an episode.
+    pub fn album_or_publisher(&self) -> &str {
+        match *self {
+            PlaybackItem::Track(ref t) => &t.album.name,
+            PlaybackItem::Episode(ref e) =>
+                e.show.as_ref().map(|s| s.publisher.as_str()).unwrap_or(""),
+        }
+    }
+    /// Album cover or episode artwork closest to `size` pixels wide.
+    pub fn image(&self, size: u32) -> Option<&Image> {
+        match *self {
+            PlaybackItem::Track(ref t) => t.album.image(size),
+            PlaybackItem::Episode(ref e) => closest_image(&e.images, size),
+        }
+    }
+}
+
+/// Player actions that are currently not allowed, e.g. `skipping_prev` at
+/// the start of a context, or `pausing` during an ad.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PlayerActions {
+    #[serde(default)]
+    pub disallows: BTreeMap<String, bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayerState {
+    pub timestamp: u64,
+    pub device: ConnectDevice,
+    pub progress_ms: Option<u32>,
+    pub is_playing: bool,
+    /// Missing while nothing, an advertisement, or something else that
+    /// isn't a track or episode is playing.
+    #[serde(default, deserialize_with = "known_item")]
+    pub item: Option<PlaybackItem>,
+    /// `track`, `episode`, `ad` or `unknown`
+    pub currently_playing_type: String,
+    #[serde(default)]
+    pub actions: PlayerActions,
+    pub shuffle_state: bool,
+    pub repeat_state: String,
+    pub context: Option<ConnectContext>,
+}
+
+impl PlayerState {
+    /// Whether an action (`pausing`, `skipping_next`, etc.) is disallowed.
+    pub fn is_disallowed(&self, action: &str) -> bool {
+        self.actions.disallows.get(action).cloned().unwrap_or(false)
+    }
+}
+
+impl fmt::Display for PlayerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let play_state = match self.is_playing {
+            true => "Playing",
+            false => "Paused",
+        };
+        let volume = match self.device.volume_percent {
+            Some(x) => x.to_string(),
+            None => "???".to_string(),
+        };
+        let item = match self.item {
+            Some(ref item) => item,
+            None => return write!(f, "{} {} on {} [Volume {}%]\n",
+                                  play_state, self.currently_playing_type, self.device.name, volume),
+        };
+        let position: f64 = match self.progress_ms {
+            Some(x) => (x as f64)/1000.0,
+            None => 0.0,
+        };
+        let duration: f64 = (item.duration_ms() as f64) / 1000.0;
+        // Local files and some episodes report no duration.
+        let progress: f64 = match item.duration_ms() {
+            0 => 0.0,
+            _ => position/duration*100.0,
+        };
+        write!(f, "{} on {} [Volume {}%]\n{} <{}>\n{}s / {}s ({:.1}%)\n",
+               play_state, self.device.name, volume,
+               item.name(), item.uri(),
+               position, duration, progress)
+    }
+}
+
+/// A track the user played, and when.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayHistory {
+    pub track: ConnectPlaybackItem,
+    /// ISO 8601 timestamp, e.g. `2017-05-01T09:24:03.123Z`
+    pub played_at: String,
+    pub context: Option<ConnectContext>,
+}
+
+/// A Spotify user, from `/v1/me` or as the owner of a playlist.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserProfile {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub uri: String,
+    pub href: Option<String>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    pub followers: Option<Followers>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+    /// Only present with the `user-read-private` scope.
+    pub country: Option<String>,
+    pub product: Option<String>,
+    /// Only present with the `user-read-email` scope.
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaylistOwner {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub uri: Option<String>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+/// Where to fetch a playlist's entries, and how many there are.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaylistTracksRef {
+    pub href: String,
+    pub total: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaylistSummary {
+    pub id: String,
+    pub name: String,
+    pub uri: String,
+    pub href: Option<String>,
+    pub description: Option<String>,
+    pub owner: PlaylistOwner,
+    pub collaborative: bool,
+    pub public: Option<bool>,
+    pub snapshot_id: Option<String>,
+    /// Spotify returns no images for empty playlists.
+    #[serde(default)]
+    pub images: Vec<Image>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+    pub tracks: PlaylistTracksRef,
+}
+
+/// A track in the user's library.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedTrack {
+    pub added_at: String,
+    pub track: ConnectPlaybackItem,
+}
+
+/// An album in the user's library.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedAlbum {
+    pub added_at: String,
+    pub album: ConnectPlaybackAlbum,
+}
+
+/// An entry in a playlist.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaylistTrack {
+    pub added_at: Option<String>,
+    #[serde(default)]
+    pub is_local: bool,
+    /// A track or podcast episode.  Missing if it is no longer available,
+    /// or of a type this library doesn't know.
+    #[serde(default, deserialize_with = "known_item")]
+    pub track: Option<PlaybackItem>,
+}
@@ -0,0 +1,102 @@
+extern crate serde;
+use self::serde::de::DeserializeOwned;
+
+use std::collections::VecDeque;
+
+use super::{SpotifyConnectr, Page, CursorPage};
+use super::super::error::ConnectrError;
+
+/// A page of a list that may link to the following page.
+pub trait Paged {
+    type Item;
+    /// This page's items, and the URL of the next page if there is one.
+    fn into_items(self) -> (Vec<Self::Item>, Option<String>);
+}
+
+impl<T> Paged for Page<T> {
+    type Item = T;
+    fn into_items(self) -> (Vec<T>, Option<String>) {
+        (self.items, self.next)
+    }
+}
+
+impl<T> Paged for CursorPage<T> {
+    type Item = T;
+    fn into_items(self) -> (Vec<T>, Option<String>) {
+        (self.items, self.next)
+    }
+}
+
+/// Lazy iterator over every item of a paged list.
+///
+/// Pages are fetched as the iterator reaches them, by following each page's
+/// `next` link.  Use `take()` to stop early; later pages are never fetched.
+/// Iteration ends after the first error.
+pub struct PageIter<'s, 'a: 's, P: Paged> {
+    spotify: &'s mut SpotifyConnectr<'a>,
+    items: VecDeque<P::Item>,
+    /// URL and query of the next page to fetch
+    next: Option<(String, String)>,
+    /// Whether to follow `next` links past the first page
+    follow: bool,
+}
+
+impl<'s, 'a, P> Iterator for PageIter<'s, 'a, P> where P: Paged + DeserializeOwned {
+    type Item = Result<P::Item, ConnectrError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        // Loop, since Spotify can return an empty page that still has a next.
+        loop {
+            if let Some(item) = self.items.pop_front() {
+                return Some(Ok(item));
+            }
+            let (url, query) = match self.next.take() {
+                Some(next) => next,
+                None => return None,
+            };
+            let page: P = match self.spotify.get_json(&url, &query) {
+                Ok(page) => page,
+                Err(e) => return Some(Err(e)),
+            };
+            let (items, next) = page.into_items();
+            self.items.extend(items);
+            // Next links already carry the query.
+            self.next = match self.follow {
+                true => next.map(|url| (url, String::new())),
+                false => None,
+            };
+        }
+    }
+}
+
+impl<'s, 'a, P: Paged> PageIter<'s, 'a, P> {
+    /// Stop after the first page, for lists whose `next` links don't lead
+    /// where the first page's query asked to go.
+    pub(super) fn first_page_only(mut self) -> PageIter<'s, 'a, P> {
+        self.follow = false;
+        self
+    }
+}
+
+impl<'a> SpotifyConnectr<'a> {
+    /// Iterate over a list starting with the page at `url`, which isn't
+    /// fetched until the first item is needed.
+    pub fn page_iter<'s, P: Paged>(&'s mut self, url: &str, query: &str) -> PageIter<'s, 'a, P> {
+        PageIter {
+            spotify: self,
+            items: VecDeque::new(),
+            next: Some((url.to_string(), query.to_string())),
+            follow: true,
+        }
+    }
+    /// Iterate over the rest of a list, starting with a page that was
+    /// already fetched.
+    pub fn follow_pages<'s, P: Paged>(&'s mut self, first: P) -> PageIter<'s, 'a, P> {
+        let (items, next) = first.into_items();
+        PageIter {
+            spotify: self,
+            items: items.into_iter().collect(),
+            next: next.map(|url| (url, String::new())),
+            follow: true,
+        }
+    }
+}
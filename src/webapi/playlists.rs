@@ -0,0 +1,155 @@
+extern crate serde_json;
+
+extern crate url;
+use self::url::percent_encoding;
+
+use super::{SpotifyConnectr, Page, PageIter, QueryString, PlaylistSummary, PlaylistTrack, UserProfile, SpotifyUri};
+use super::spotify_result;
+use super::super::http;
+use super::super::error::ConnectrError;
+
+#[derive(Serialize)]
+struct NewPlaylist<'a> {
+    name: &'a str,
+    public: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct AddItems {
+    uris: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ItemUri {
+    uri: String,
+}
+
+#[derive(Serialize)]
+struct RemoveItems<'a> {
+    tracks: Vec<ItemUri>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot_id: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ReorderItems<'a> {
+    range_start: u32,
+    insert_before: u32,
+    range_length: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot_id: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    snapshot_id: String,
+}
+
+fn path_segment(id: &str) -> String {
+    percent_encoding::utf8_percent_encode(id, percent_encoding::PATH_SEGMENT_ENCODE_SET).collect()
+}
+
+// Spotify accepts at most this many items per add or remove request.
+const MAX_ITEMS: usize = 100;
+
+fn check_items(uris: &[SpotifyUri]) -> Result<(), ConnectrError> {
+    match uris.len() {
+        0 => Err(ConnectrError::Config("no items given".to_string())),
+        x if x > MAX_ITEMS => Err(ConnectrError::Config(format!("at most {} items per request", MAX_ITEMS))),
+        _ => Ok(()),
+    }
+}
+
+impl<'a> SpotifyConnectr<'a> {
+    fn playlist_url(&self, playlist_id: &str, suffix: &str) -> String {
+        format!("{}/{}{}", self.api.get().playlists, path_segment(playlist_id), suffix)
+    }
+    fn snapshot_result(response: http::HttpResponse) -> Result<String, ConnectrError> {
+        let snapshot: Snapshot = serde_json::from_str(&spotify_result(response)?)?;
+        Ok(snapshot.snapshot_id)
+    }
+    pub fn current_user(&mut self) -> Result<UserProfile, ConnectrError> {
+        self.get_json(self.api.get().me, "")
+    }
+    /// Playlists owned or followed by the user.  `limit` is 1-50, default 20.
+    pub fn my_playlists(&mut self, limit: Option<u32>, offset: Option<u32>) -> Result<Page<PlaylistSummary>, ConnectrError> {
+        let query = QueryString::new()
+            .add_opt("limit", limit.map(|l| l.to_string()))
+            .add_opt("offset", offset.map(|o| o.to_string()))
+            .build();
+        self.get_json(self.api.get().my_playlists, &query)
+    }
+    /// Iterate over all of the user's playlists, `limit` per request.
+    pub fn my_playlists_iter<'s>(&'s mut self, limit: Option<u32>) -> PageIter<'s, 'a, Page<PlaylistSummary>> {
+        let query = QueryString::new().add_opt("limit", limit.map(|l| l.to_string())).build();
+        let url = self.api.get().my_playlists;
+        self.page_iter(url, &query)
+    }
+    /// Entries of a playlist.  `limit` is 1-100, default 100.
+    pub fn playlist_tracks(&mut self, playlist_id: &str, limit: Option<u32>,
+                           offset: Option<u32>) -> Result<Page<PlaylistTrack>, ConnectrError> {
+        let query = QueryString::new()
+            .add_opt("limit", limit.map(|l| l.to_string()))
+            .add_opt("offset", offset.map(|o| o.to_string()))
+            .add("additional_types", "episode")
+            .build();
+        let url = self.playlist_url(playlist_id, "/tracks");
+        self.get_json(&url, &query)
+    }
+    /// Iterate over all entries of a playlist, `limit` per request.
+    pub fn playlist_tracks_iter<'s>(&'s mut self, playlist_id: &str,
+                                    limit: Option<u32>) -> PageIter<'s, 'a, Page<PlaylistTrack>> {
+        let query = QueryString::new()
+            .add_opt("limit", limit.map(|l| l.to_string()))
+            .add("additional_types", "episode")
+            .build();
+        let url = self.playlist_url(playlist_id, "/tracks");
+        self.page_iter(&url, &query)
+    }
+    /// Create an empty playlist owned by `user_id`, which must be the logged in user.
+    pub fn create_playlist(&mut self, user_id: &str, name: &str, public: bool,
+                           description: Option<&str>) -> Result<PlaylistSummary, ConnectrError> {
+        let body = serde_json::to_string(&NewPlaylist { name: name, public: public, description: description })?;
+        let url = format!("{}/{}/playlists", self.api.get().users, path_segment(user_id));
+        let json_response = self.api_request(&url, "", &body, http::HttpMethod::POST);
+        Ok(serde_json::from_str(&spotify_result(json_response)?)?)
+    }
+    /// Add tracks or episodes, by URI, at `position` or the end.  Returns the
+    /// playlist's new snapshot ID.
+    pub fn add_to_playlist(&mut self, playlist_id: &str, uris: &[SpotifyUri],
+                           position: Option<u32>) -> Result<String, ConnectrError> {
+        check_items(uris)?;
+        let uris = uris.iter().map(|u| u.to_string()).collect();
+        let body = serde_json::to_string(&AddItems { uris: uris, position: position })?;
+        let url = self.playlist_url(playlist_id, "/tracks");
+        SpotifyConnectr::snapshot_result(self.api_request(&url, "", &body, http::HttpMethod::POST))
+    }
+    /// Remove every occurrence of the given URIs.  If `snapshot_id` is given,
+    /// they are removed from that version of the playlist.  Returns the new
+    /// snapshot ID.
+    pub fn remove_from_playlist(&mut self, playlist_id: &str, uris: &[SpotifyUri],
+                                snapshot_id: Option<&str>) -> Result<String, ConnectrError> {
+        check_items(uris)?;
+        let tracks = uris.iter().map(|u| ItemUri { uri: u.to_string() }).collect();
+        let body = serde_json::to_string(&RemoveItems { tracks: tracks, snapshot_id: snapshot_id })?;
+        let url = self.playlist_url(playlist_id, "/tracks");
+        SpotifyConnectr::snapshot_result(self.api_request(&url, "", &body, http::HttpMethod::DELETE))
+    }
+    /// Move `range_length` entries starting at `range_start` to before the
+    /// entry at `insert_before`.  Returns the new snapshot ID.
+    pub fn reorder_playlist(&mut self, playlist_id: &str, range_start: u32, range_length: u32,
+                            insert_before: u32, snapshot_id: Option<&str>) -> Result<String, ConnectrError> {
+        let body = serde_json::to_string(&ReorderItems {
+            range_start: range_start,
+            insert_before: insert_before,
+            range_length: range_length,
+            snapshot_id: snapshot_id,
+        })?;
+        let url = self.playlist_url(playlist_id, "/tracks");
+        SpotifyConnectr::snapshot_result(self.api_request(&url, "", &body, http::HttpMethod::PUT))
+    }
+}
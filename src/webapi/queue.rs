@@ -0,0 +1,33 @@
+use super::{SpotifyConnectr, QueryString, PlaybackItem, SpotifyUri, UriKind};
+use super::{known_item, known_items};
+use super::spotify_command;
+use super::super::http;
+use super::super::error::ConnectrError;
+
+/// What is playing now, and what will play after it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Queue {
+    #[serde(default, deserialize_with = "known_item")]
+    pub currently_playing: Option<PlaybackItem>,
+    #[serde(default, deserialize_with = "known_items")]
+    pub queue: Vec<PlaybackItem>,
+}
+
+impl<'a> SpotifyConnectr<'a> {
+    /// Add a track or episode URI to the end of the queue on the target device.
+    pub fn add_to_queue(&mut self, uri: &SpotifyUri) -> Result<(), ConnectrError> {
+        match uri.kind() {
+            UriKind::Track | UriKind::Episode => {},
+            _ => return Err(ConnectrError::InvalidUri(format!("{} can't be queued", uri))),
+        }
+        let query = QueryString::new()
+            .add_opt("device_id", self.device.clone())
+            .add("uri", uri.to_string())
+            .build();
+        spotify_command(self.api_request(self.api.get().queue, &query, "", http::HttpMethod::POST))
+    }
+    /// The user's queue.  Spotify returns up to 20 upcoming items.
+    pub fn get_queue(&mut self) -> Result<Queue, ConnectrError> {
+        self.get_json(self.api.get().queue, "")
+    }
+}
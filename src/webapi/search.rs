@@ -0,0 +1,62 @@
+use super::{SpotifyConnectr, Page, QueryString, ConnectPlaybackItem, ConnectPlaybackAlbum, ConnectPlaybackArtist};
+use super::{PlaylistSummary, ConnectPlaybackShow, ConnectPlaybackEpisode};
+use super::super::error::ConnectrError;
+
+/// Kinds of item a search can return.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchType {
+    Album,
+    Artist,
+    Playlist,
+    Track,
+    Show,
+    Episode,
+}
+impl ToString for SearchType {
+    fn to_string(&self) -> String {
+        match self {
+            &SearchType::Album => "album".to_string(),
+            &SearchType::Artist => "artist".to_string(),
+            &SearchType::Playlist => "playlist".to_string(),
+            &SearchType::Track => "track".to_string(),
+            &SearchType::Show => "show".to_string(),
+            &SearchType::Episode => "episode".to_string(),
+        }
+    }
+}
+
+/// Results of a search.  Only the requested types are present.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchResults {
+    pub tracks: Option<Page<ConnectPlaybackItem>>,
+    pub albums: Option<Page<ConnectPlaybackAlbum>>,
+    pub artists: Option<Page<ConnectPlaybackArtist>>,
+    pub playlists: Option<Page<PlaylistSummary>>,
+    pub shows: Option<Page<ConnectPlaybackShow>>,
+    pub episodes: Option<Page<ConnectPlaybackEpisode>>,
+}
+
+impl<'a> SpotifyConnectr<'a> {
+    /// Search the Spotify catalog.
+    ///
+    /// `query` supports Spotify's field filters, e.g. `album:Bakesale artist:Sebadoh`.
+    /// `limit` is per type (1-50, default 20).  Shows and episodes are only
+    /// returned if a `market` is given, or the user's country is known.
+    /// Pass one of the result's pages to `follow_pages()` to iterate over the
+    /// rest of it.
+    pub fn search(&mut self, query: &str, types: &[SearchType], limit: Option<u32>,
+                  offset: Option<u32>, market: Option<&str>) -> Result<SearchResults, ConnectrError> {
+        if types.is_empty() {
+            return Err(ConnectrError::Config("search needs at least one type".to_string()));
+        }
+        let types = types.iter().map(|t| t.to_string()).collect::<Vec<String>>().join(",");
+        let query = QueryString::new()
+            .add("q", query)
+            .add("type", types)
+            .add_opt("limit", limit.map(|l| l.to_string()))
+            .add_opt("offset", offset.map(|o| o.to_string()))
+            .add_opt("market", market.map(|m| m.to_string()))
+            .build();
+        self.get_json(self.api.get().search, &query)
+    }
+}
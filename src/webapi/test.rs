@@ -4,7 +4,12 @@ mod tests {
     extern crate hyper;
 
     use super::super::*;
-    use super::super::super::SpotifyEndpoints;
+    use super::super::super::settings::{Account, Settings};
+    use super::super::super::http::{AccessToken, HttpMethod, HttpRequest, HttpTransport};
+    use super::super::super::http::{RetryPolicy, ScriptedTransport};
+    use super::super::super::tokens::{Tokens, TokenStore, MemoryTokenStore};
+    use super::super::super::error::SpotifyError;
+    use super::super::fixtures::{TEST_API, PLAYER_STATE, test_tokens, scripted_client, scripted_spotify};
 
     use std;
     use std::thread;
@@ -26,22 +31,6 @@ mod tests {
         static ref WEBSERVER_STARTED: AtomicBool = AtomicBool::new(false);
     }
 
-    pub const TEST_API: SpotifyEndpoints = SpotifyEndpoints {
-        scopes: "user-read-private streaming user-read-playback-state",
-        authorize: "http://127.0.0.1:9799/en/authorize",
-        token: "http://127.0.0.1:9799/api/token",
-        devices: "http://127.0.0.1:9799/v1/me/player/devices",
-        player_state: "http://127.0.0.1:9799/v1/me/player",
-        play: "http://127.0.0.1:9799/v1/me/player/play",
-        pause: "http://127.0.0.1:9799/v1/me/player/pause",
-        next: "http://127.0.0.1:9799/v1/me/player/next",
-        previous: "http://127.0.0.1:9799/v1/me/player/previous",
-        seek: "http://127.0.0.1:9799/v1/me/player/seek",
-        volume: "http://127.0.0.1:9799/v1/me/player/volume",
-        shuffle: "http://127.0.0.1:9799/v1/me/player/shuffle",
-        repeat: "http://127.0.0.1:9799/v1/me/player/repeat",
-        player: "http://127.0.0.1:9799/v1/me/player",
-    };
 
     /// Macro to parse the body of a POST request and send a response.
     ///
@@ -124,35 +113,771 @@ mod tests {
 
     #[test]
     fn test_refresh_oauth_tokens_no_connection() {
-        let spotify = SpotifyConnectr::new().with_api(TEST_API);
+        let spotify = SpotifyConnectr::new().unwrap().with_api(TEST_API);
         let res = spotify.refresh_oauth_tokens();
         // Unlock webserver init so all other tests can run
         WEBSERVER_STARTED.store(true, Ordering::Relaxed);
-        assert!(res.is_none());
+        assert!(res.is_err());
     }
 
     #[test]
     fn test_refresh_oauth_tokens_pass() {
         init();
-        let spotify = SpotifyConnectr::new().with_api(TEST_API);
+        let spotify = SpotifyConnectr::new().unwrap().with_api(TEST_API);
         match spotify.refresh_oauth_tokens() {
-            Some((access,expires)) => {
+            Ok((access,_,expires,scope)) => {
                 assert_eq!(access, "valid_access_code");
                 assert_eq!(expires, 3600);
+                assert_eq!(scope, Some("user-read-private user-read-email".to_string()));
             },
-            None => { assert!(false) },
+            Err(_) => { assert!(false) },
         }
     }
 
     #[test]
     fn test_refresh_oauth_tokens_error_status() {
         init();
-        let mut spotify = SpotifyConnectr::new().with_api(TEST_API);
+        let mut spotify = SpotifyConnectr::new().unwrap().with_api(TEST_API);
         spotify.refresh_token = Some("error".to_string());
         match spotify.refresh_oauth_tokens() {
-            Some(_) => { assert!(false) },
-            None => { },
+            Ok(_) => { assert!(false) },
+            Err(ConnectrError::Http { code, .. }) => { assert_eq!(code, 403) },
+            Err(_) => { assert!(false) },
+        }
+    }
+
+    fn uri(s: &str) -> SpotifyUri {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_scripted_play_sends_device_and_token() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(204, "");
+        spotify.set_target_device(Some("abc123".to_string()));
+        assert!(spotify.play(None).is_ok());
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, HttpMethod::PUT);
+        assert_eq!(requests[0].url, TEST_API.play);
+        assert_eq!(requests[0].query, "device_id=abc123");
+        assert_eq!(requests[0].authorization, Some("Bearer valid_access_code".to_string()));
+    }
+
+    #[test]
+    fn test_scripted_device_list() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(200, r#"{"devices": [{"id": "abc123", "is_active": true,
+            "is_restricted": false, "name": "Kitchen", "type": "Speaker", "volume_percent": 40}]}"#);
+        let devices = spotify.request_device_list().unwrap();
+        assert_eq!(devices.devices.len(), 1);
+        assert_eq!(devices.devices[0].name, "Kitchen");
+        assert_eq!(devices.devices[0].volume_percent, Some(40));
+    }
+
+    #[test]
+    fn test_scripted_error_body() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(404, r#"{"error": {"status": 404,
+            "message": "Player command failed: No active device found", "reason": "NO_ACTIVE_DEVICE"}}"#);
+        match spotify.next() {
+            Err(ConnectrError::Http { code: 404, error: Some(e) }) => {
+                assert_eq!(e.reason, Some("NO_ACTIVE_DEVICE".to_string()));
+            },
+            _ => { assert!(false) },
+        }
+    }
+
+    #[test]
+    fn test_scripted_transport_failure() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_error("Couldn't connect to server");
+        match spotify.pause() {
+            Err(ConnectrError::Transport(_)) => {},
+            _ => { assert!(false) },
+        }
+    }
+
+    #[test]
+    fn test_retry_server_error() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(503, "");
+        transport.push_error("Connection reset by peer");
+        transport.push_response(200, r#"{"devices": []}"#);
+        assert!(spotify.request_device_list().is_ok());
+        assert_eq!(transport.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_retry_non_idempotent() {
+        let (mut spotify, transport) = scripted_spotify();
+        // Might have skipped already: don't skip again.
+        transport.push_response(503, "");
+        assert!(spotify.next().is_err());
+        transport.push_error("Connection reset by peer");
+        assert!(spotify.next().is_err());
+        assert_eq!(transport.requests().len(), 2);
+
+        // Rate limited or never sent: safe to try again.
+        transport.push_response(429, "");
+        transport.push_connect_error("Couldn't connect to server");
+        transport.push_response(204, "");
+        assert!(spotify.next().is_ok());
+        assert_eq!(transport.requests().len(), 5);
+    }
+
+    #[test]
+    fn test_retry_gives_up() {
+        let (mut spotify, transport) = scripted_spotify();
+        for _ in 0..5 {
+            transport.push_response(502, "");
+        }
+        match spotify.request_device_list() {
+            Err(ConnectrError::Http { code: 502, .. }) => {},
+            _ => { assert!(false) },
+        }
+        assert_eq!(transport.requests().len(), RetryPolicy::default().max_attempts as usize);
+    }
+
+    #[test]
+    fn test_retry_client_error_not_retried() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(400, "");
+        transport.push_response(204, "");
+        assert!(spotify.next().is_err());
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response_with_headers(429, &[("Retry-After", "0")], "");
+        transport.push_response(204, "");
+        assert!(spotify.next().is_ok());
+        assert_eq!(transport.requests().len(), 2);
+
+        // Longer than the policy's maximum delay: fail immediately.
+        transport.push_response_with_headers(429, &[("Retry-After", "120")], "");
+        match spotify.next() {
+            Err(ConnectrError::RateLimited(Some(120))) => {},
+            _ => { assert!(false) },
+        }
+        assert_eq!(transport.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_scripted_headers() {
+        let transport = ScriptedTransport::new();
+        transport.push_response_with_headers(200, &[("ETag", "\"abc\"")], "{}");
+        let request = HttpRequest::new("http://127.0.0.1/v1/me/tracks", "ids=1", "",
+                                       HttpMethod::DELETE, AccessToken::Bearer("token"))
+            .header("If-None-Match", "\"xyz\"");
+        let response = transport.request(&request);
+        assert_eq!(response.header("etag"), Some("\"abc\""));
+        assert_eq!(response.header("ETAG"), Some("\"abc\""));
+        assert_eq!(response.header("retry-after"), None);
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, HttpMethod::DELETE);
+        assert_eq!(requests[0].headers, vec![("If-None-Match".to_string(), "\"xyz\"".to_string())]);
+    }
+
+    #[test]
+    fn test_pkce_token_requests_omit_secret() {
+        let (mut spotify, transport) = scripted_spotify();
+        spotify.settings.pkce = true;
+        let token = r#"{"access_token": "a", "refresh_token": "r", "expires_in": 3600}"#;
+        transport.push_response(200, token);
+        transport.push_response(200, token);
+        spotify.request_oauth_tokens("code", &Settings::for_test(), Some("verifier")).unwrap();
+        spotify.refresh_oauth_tokens().unwrap();
+        let requests = transport.requests();
+        assert!(requests[0].query.contains("code_verifier=verifier"));
+        assert!(!requests[0].query.contains("client_secret"));
+        assert!(requests[1].query.contains("client_id=client_id"));
+        assert!(!requests[1].query.contains("client_secret"));
+    }
+
+    #[test]
+    fn test_refresh_saves_to_token_store() {
+        let store = MemoryTokenStore::new(Some(test_tokens()));
+        let transport = ScriptedTransport::new();
+        let mut spotify = SpotifyConnectr::from_settings_with_store(Settings::for_test(), Box::new(store.clone()))
+            .with_api(TEST_API)
+            .with_transport(Box::new(transport.clone()));
+        transport.push_response(200, r#"{"access_token": "fresh", "expires_in": 3600}"#);
+        assert!(spotify.refresh_access_token().is_ok());
+        let saved = store.tokens().unwrap();
+        assert_eq!(saved.access, "fresh");
+        assert_eq!(saved.refresh, "valid_refresh_code");
+        assert!(saved.expire_utc > 0);
+    }
+
+    #[test]
+    fn test_refresh_saves_rotated_refresh_token() {
+        let store = MemoryTokenStore::new(Some(test_tokens()));
+        let transport = ScriptedTransport::new();
+        let mut spotify = SpotifyConnectr::from_settings_with_store(Settings::for_test(), Box::new(store.clone()))
+            .with_api(TEST_API)
+            .with_transport(Box::new(transport.clone()));
+        transport.push_response(200, r#"{"access_token": "fresh", "refresh_token": "rotated", "expires_in": 3600}"#);
+        transport.push_response(200, r#"{"access_token": "fresher", "expires_in": 3600}"#);
+        assert!(spotify.refresh_access_token().is_ok());
+        assert_eq!(store.tokens().unwrap().refresh, "rotated");
+        // The next refresh uses the new one.
+        assert!(spotify.refresh_access_token().is_ok());
+        assert!(transport.requests()[1].query.contains("refresh_token=rotated"));
+        assert_eq!(store.tokens().unwrap().refresh, "rotated");
+    }
+
+    #[test]
+    fn test_refresh_outage_keeps_credentials() {
+        let store = MemoryTokenStore::new(Some(test_tokens()));
+        let transport = ScriptedTransport::new();
+        let mut spotify = SpotifyConnectr::from_settings_with_store(Settings::for_test(), Box::new(store.clone()))
+            .with_api(TEST_API)
+            .with_transport(Box::new(transport.clone()));
+        transport.push_response(503, "");
+        match spotify.refresh_access_token() {
+            Err(ConnectrError::Http { code: 503, .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        // No login was attempted, and the refresh will be tried again.
+        assert_eq!(transport.requests().len(), 1);
+        assert_eq!(store.tokens(), Some(test_tokens()));
+        assert!(spotify.refresh_timer_channel.is_some());
+    }
+
+    #[test]
+    fn test_scripted_retry_after_refresh() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(401, r#"{"error": {"status": 401, "message": "The access token expired"}}"#);
+        transport.push_response(200, r#"{"access_token": "fresh", "expires_in": 3600}"#);
+        transport.push_response(204, "");
+        assert!(spotify.pause().is_ok());
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].url, TEST_API.token);
+        assert_eq!(requests[2].url, TEST_API.pause);
+        assert_eq!(requests[2].authorization, Some("Bearer fresh".to_string()));
+
+        // Still rejected after refreshing: give up.
+        transport.push_response(401, "");
+        transport.push_response(200, r#"{"access_token": "fresher", "expires_in": 3600}"#);
+        transport.push_response(401, "");
+        match spotify.pause() {
+            Err(ConnectrError::AuthExpired) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(transport.requests().len(), 6);
+    }
+
+    #[test]
+    fn test_refresh_needs_login() {
+        let revoked = SpotifyError::parse(400, r#"{"error": "invalid_grant", "error_description": "Refresh token revoked"}"#);
+        assert!(refresh_needs_login(&ConnectrError::Http { code: 400, error: revoked }));
+        assert!(refresh_needs_login(&ConnectrError::AuthExpired));
+        assert!(!refresh_needs_login(&ConnectrError::Http { code: 500, error: None }));
+        assert!(!refresh_needs_login(&ConnectrError::RateLimited(Some(5))));
+        assert!(!refresh_needs_login(&ConnectrError::Transport("offline".to_string())));
+    }
+
+    #[test]
+    fn test_refresh_saves_granted_scope() {
+        let store = MemoryTokenStore::new(Some(test_tokens()));
+        let transport = ScriptedTransport::new();
+        let mut spotify = SpotifyConnectr::from_settings_with_store(Settings::for_test(), Box::new(store.clone()))
+            .with_api(TEST_API)
+            .with_transport(Box::new(transport.clone()));
+        transport.push_response(200, r#"{"access_token": "fresh", "expires_in": 3600,
+            "scope": "streaming user-read-playback-state user-read-private"}"#);
+        assert!(spotify.refresh_access_token().is_ok());
+        assert_eq!(store.tokens().unwrap().scope,
+                   Some("streaming user-read-playback-state user-read-private".to_string()));
+        assert!(spotify.has_required_scopes());
+    }
+
+    #[test]
+    fn test_missing_scopes() {
+        let required = "user-read-private streaming user-read-playback-state";
+        assert_eq!(missing_scopes(required, "streaming user-read-private user-read-playback-state"),
+                   Vec::<&str>::new());
+        assert_eq!(missing_scopes(required, "user-read-private"),
+                   vec!["streaming", "user-read-playback-state"]);
+        assert_eq!(missing_scopes(required, ""), vec!["user-read-private", "streaming", "user-read-playback-state"]);
+
+        let (mut spotify, _) = scripted_spotify();
+        assert!(spotify.has_required_scopes());
+        spotify.scope = Some("user-read-private".to_string());
+        assert!(!spotify.has_required_scopes());
+    }
+
+    #[test]
+    fn test_legacy_tokens_migrated() {
+        let mut settings = Settings::for_test();
+        settings.legacy_tokens = Some(test_tokens());
+        let store = MemoryTokenStore::new(None);
+        let spotify = SpotifyConnectr::from_settings_with_store(settings, Box::new(store.clone()));
+        assert_eq!(store.load().unwrap(), Some(test_tokens()));
+        assert_eq!(spotify.access_token, Some("valid_access_code".to_string()));
+    }
+
+    fn scripted_account<'a>(name: &str, access: &str) -> ((Account, SpotifyConnectr<'a>), ScriptedTransport) {
+        let tokens = Tokens { access: access.to_string(), .. test_tokens() };
+        let (spotify, transport) = scripted_client(Settings::for_test(), tokens);
+        let account = Account { name: name.to_string(), label: name.to_string() };
+        ((account, spotify), transport)
+    }
+
+    #[test]
+    fn test_switch_accounts() {
+        let (home, home_transport) = scripted_account("home", "home_access");
+        let (work, work_transport) = scripted_account("work", "work_access");
+        let mut accounts = SpotifyAccounts::from_clients(vec![home, work]).unwrap();
+        assert_eq!(accounts.active_account().name, "home");
+
+        // Switching connects the account, refreshing its saved token.
+        work_transport.push_response(200, r#"{"access_token": "work_fresh", "expires_in": 3600}"#);
+        assert!(accounts.switch("work").is_ok());
+        assert_eq!(accounts.active_account().name, "work");
+        work_transport.push_response(204, "");
+        assert!(accounts.active_mut().next().is_ok());
+        let requests = work_transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].authorization, Some("Bearer work_fresh".to_string()));
+        assert_eq!(home_transport.requests().len(), 0);
+
+        assert!(accounts.switch("nobody").is_err());
+        assert_eq!(accounts.active_account().name, "work");
+    }
+
+    #[test]
+    fn test_scripted_search() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(200, r#"{
+            "albums": {"href": "x", "limit": 1, "next": "y", "offset": 0, "previous": null, "total": 5,
+                "items": [{"id": "70XjdLKH7HHsFVWoQipP0T", "name": "Bakesale", "album_type": "album",
+                    "uri": "spotify:album:70XjdLKH7HHsFVWoQipP0T", "release_date": "1994-08-23",
+                    "total_tracks": 14, "artists": [{"name": "Sebadoh", "uri": "spotify:artist:abc"}]}]},
+            "playlists": {"href": "x", "limit": 1, "next": null, "offset": 0, "previous": null, "total": 1,
+                "items": [null]}}"#);
+        let results = spotify.search("album:Bakesale & more", &[SearchType::Album, SearchType::Playlist],
+                                     Some(1), None, Some("US")).unwrap();
+        let albums = results.albums.unwrap();
+        assert_eq!(albums.items[0].name, "Bakesale");
+        assert_eq!(albums.items[0].artists[0].name, "Sebadoh");
+        assert_eq!(albums.next_offset(), Some(1));
+        let playlists = results.playlists.unwrap();
+        assert_eq!(playlists.items.len(), 0);
+        assert_eq!(playlists.next_offset(), None);
+        assert!(results.tracks.is_none());
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].url, TEST_API.search);
+        assert_eq!(requests[0].query,
+                   "limit=1&market=US&q=album%3ABakesale%20%26%20more&type=album%2Cplaylist");
+    }
+
+    #[test]
+    fn test_scripted_library() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(200, "[true, false]");
+        transport.push_response(200, "");
+        transport.push_response(200, "");
+        assert_eq!(spotify.check_saved_tracks(&["abc", "def"]).unwrap(), vec![true, false]);
+        assert!(spotify.save_tracks(&["def"]).is_ok());
+        assert!(spotify.remove_saved_albums(&["xyz"]).is_ok());
+        assert!(spotify.save_tracks(&[]).is_err());
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].method, HttpMethod::GET);
+        assert_eq!(requests[0].url, TEST_API.saved_tracks_contains);
+        assert_eq!(requests[0].query, "ids=abc%2Cdef");
+        assert_eq!(requests[1].method, HttpMethod::PUT);
+        assert_eq!(requests[1].url, TEST_API.saved_tracks);
+        assert_eq!(requests[2].method, HttpMethod::DELETE);
+        assert_eq!(requests[2].url, TEST_API.saved_albums);
+    }
+
+    #[test]
+    fn test_scripted_playlists() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(200, r#"{"href": "x", "limit": 50, "next": null, "offset": 0,
+            "previous": null, "total": 1, "items": [{"id": "pl1", "name": "Mine",
+            "uri": "spotify:playlist:pl1", "collaborative": false, "public": true,
+            "owner": {"id": "me", "display_name": "Me"}, "tracks": {"href": "y", "total": 3}}]}"#);
+        transport.push_response(201, r#"{"snapshot_id": "snap2"}"#);
+        transport.push_response(200, r#"{"snapshot_id": "snap3"}"#);
+
+        let page = spotify.my_playlists(Some(50), None).unwrap();
+        assert_eq!(page.items[0].owner.id, "me");
+        assert_eq!(page.items[0].tracks.total, 3);
+        assert_eq!(spotify.add_to_playlist("pl1", &[uri("spotify:track:6rqhFgbbKwnb9MLmUQDhG6")], Some(0)).unwrap(), "snap2");
+        assert_eq!(spotify.remove_from_playlist("pl1", &[uri("spotify:track:6rqhFgbbKwnb9MLmUQDhG6")], Some("snap2")).unwrap(),
+                   "snap3");
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].url, TEST_API.my_playlists);
+        assert_eq!(requests[1].method, HttpMethod::POST);
+        assert_eq!(requests[1].url, format!("{}/pl1/tracks", TEST_API.playlists));
+        assert_eq!(requests[1].body, r#"{"uris":["spotify:track:6rqhFgbbKwnb9MLmUQDhG6"],"position":0}"#);
+        assert!(requests[1].headers.contains(&("Content-Type".to_string(), "application/json".to_string())));
+        assert_eq!(requests[2].method, HttpMethod::DELETE);
+        assert_eq!(requests[2].body, r#"{"tracks":[{"uri":"spotify:track:6rqhFgbbKwnb9MLmUQDhG6"}],"snapshot_id":"snap2"}"#);
+    }
+
+    #[test]
+    fn test_scripted_playlist_tracks() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(200, r#"{"href": "x", "limit": 100, "next": null, "offset": 0,
+            "previous": null, "total": 4, "items": [
+            {"added_at": "2017-05-01T09:24:03Z", "is_local": false,
+                "track": {"type": "track", "id": "c", "name": "Rebound", "uri": "spotify:track:c",
+                "duration_ms": 1, "album": {"name": "Bakesale", "uri": "spotify:album:b"},
+                "artists": [{"name": "Sebadoh", "uri": "spotify:artist:d"}]}},
+            {"added_at": "2017-05-02T09:24:03Z", "is_local": false,
+                "track": {"type": "episode", "id": "e", "name": "Episode 1", "uri": "spotify:episode:e",
+                "duration_ms": 1, "show": {"name": "Some Podcast", "uri": "spotify:show:s", "publisher": "P"}}},
+            {"added_at": "2017-05-03T09:24:03Z", "is_local": false, "track": {"type": "ad", "name": "Buy stuff"}},
+            {"added_at": null, "is_local": false, "track": null}]}"#);
+        let page = spotify.playlist_tracks("pl1", None, None).unwrap();
+        assert_eq!(page.items.len(), 4);
+        assert_eq!(page.items[0].track.as_ref().unwrap().artist_or_show(), "Sebadoh");
+        assert_eq!(page.items[1].track.as_ref().unwrap().artist_or_show(), "Some Podcast");
+        assert!(page.items[2].track.is_none());
+        assert!(page.items[3].track.is_none());
+        assert_eq!(transport.requests()[0].query, "additional_types=episode");
+    }
+
+    #[test]
+    fn test_scripted_queue() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(204, "");
+        transport.push_response(200, r#"{
+            "currently_playing": {"type": "track", "id": "a", "name": "Skull", "uri": "spotify:track:a",
+                "duration_ms": 1, "album": {"name": "Bakesale", "uri": "spotify:album:b"}, "artists": []},
+            "queue": [
+                {"type": "ad", "name": "Buy stuff"},
+                {"type": "episode", "id": "e", "name": "Some Podcast", "uri": "spotify:episode:e",
+                    "duration_ms": 1, "show": {"name": "Show", "uri": "spotify:show:s", "publisher": "P"}},
+                {"type": "track", "id": "c", "name": "Rebound", "uri": "spotify:track:c", "duration_ms": 1,
+                    "album": {"name": "Bakesale", "uri": "spotify:album:b"},
+                    "artists": [{"name": "Sebadoh", "uri": "spotify:artist:d"}]}]}"#);
+        spotify.set_target_device(Some("abc123".to_string()));
+        assert!(spotify.add_to_queue(&uri("spotify:track:6rqhFgbbKwnb9MLmUQDhG6")).is_ok());
+        let queue = spotify.get_queue().unwrap();
+        assert_eq!(queue.currently_playing.unwrap().name(), "Skull");
+        assert_eq!(queue.queue.len(), 2);
+        assert_eq!(queue.queue[0].artist_or_show(), "Show");
+        assert_eq!(queue.queue[1].artist_or_show(), "Sebadoh");
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, HttpMethod::POST);
+        assert_eq!(requests[0].url, TEST_API.queue);
+        assert_eq!(requests[0].query, "device_id=abc123&uri=spotify%3Atrack%3A6rqhFgbbKwnb9MLmUQDhG6");
+        assert!(!requests[0].form);
+        assert_eq!(requests[1].method, HttpMethod::GET);
+
+        // Only tracks and episodes can be queued.
+        match spotify.add_to_queue(&uri("spotify:album:0ETFjACtuP2ADo6LFhL6HN")) {
+            Err(ConnectrError::InvalidUri(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    fn history_page(names: &[&str], before: Option<&str>) -> String {
+        let next = before.map(|b| format!("\"{}?before={}&limit=2\"", TEST_API.recently_played, b));
+        let items = names.iter().map(|name| format!(r#"{{"played_at": "2017-05-01T09:24:03.123Z",
+            "context": {{"uri": "spotify:album:b"}}, "track": {{"id": "{0}", "name": "{0}",
+            "uri": "spotify:track:{0}", "duration_ms": 1, "artists": [],
+            "album": {{"name": "Bakesale", "uri": "spotify:album:b"}}}}}}"#, name))
+            .collect::<Vec<String>>().join(",");
+        let cursors = match before {
+            Some(b) => format!(r#"{{"after": "1493630643123", "before": "{}"}}"#, b),
+            None => "null".to_string(),
+        };
+        format!(r#"{{"href": "x", "limit": 2, "next": {}, "cursors": {}, "items": [{}]}}"#,
+                next.unwrap_or("null".to_string()), cursors, items)
+    }
+
+    #[test]
+    fn test_scripted_recently_played_iter() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(200, &history_page(&["a", "b"], Some("1493630000000")));
+        transport.push_response(200, &history_page(&["c"], None));
+        let names = spotify.recently_played_iter(Some(2), None)
+            .map(|h| h.unwrap().track.name)
+            .collect::<Vec<String>>();
+        assert_eq!(names, vec!["a", "b", "c"]);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].query, "limit=2");
+        assert_eq!(requests[1].url, format!("{}?before=1493630000000&limit=2", TEST_API.recently_played));
+        assert_eq!(requests[1].query, "");
+    }
+
+    #[test]
+    fn test_scripted_recently_played_after() {
+        let (mut spotify, transport) = scripted_spotify();
+        // The first page links to older tracks, which must not be followed.
+        transport.push_response(200, &history_page(&["a", "b"], Some("1493630000000")));
+        transport.push_response(200, &history_page(&["older"], None));
+        let names = spotify.recently_played_iter(Some(2), Some(HistoryCursor::After(1493620000000)))
+            .map(|h| h.unwrap().track.name)
+            .collect::<Vec<String>>();
+        assert_eq!(names, vec!["a", "b"]);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].query, "after=1493620000000&limit=2");
+    }
+
+    #[test]
+    fn test_scripted_top_items() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(200, r#"{"href": "x", "limit": 1, "next": null, "offset": 0,
+            "previous": null, "total": 1, "items": [{"id": "d", "name": "Sebadoh",
+            "uri": "spotify:artist:d", "genres": ["lo-fi"], "popularity": 40}]}"#);
+        match spotify.top_items(TopItemType::Artists, TimeRange::Long, Some(1), None) {
+            Ok(TopItems::Artists(page)) => assert_eq!(page.items[0].genres, vec!["lo-fi"]),
+            _ => assert!(false),
         }
+        let requests = transport.requests();
+        assert_eq!(requests[0].url, format!("{}/artists", TEST_API.top));
+        assert_eq!(requests[0].query, "limit=1&time_range=long_term");
+
+        let next = format!("{}/tracks?offset=1&limit=1", TEST_API.top);
+        transport.push_response(200, &format!(r#"{{"href": "x", "limit": 1, "next": "{}", "offset": 0,
+            "previous": null, "total": 2, "items": [{{"id": "a", "name": "Skull", "uri": "spotify:track:a",
+            "duration_ms": 1, "artists": [], "album": {{"name": "Bakesale", "uri": "spotify:album:b"}}}}]}}"#, next));
+        transport.push_response(200, r#"{"href": "x", "limit": 1, "next": null, "offset": 1,
+            "previous": null, "total": 2, "items": [{"id": "c", "name": "Rebound", "uri": "spotify:track:c",
+            "duration_ms": 1, "artists": [], "album": {"name": "Bakesale", "uri": "spotify:album:b"}}]}"#);
+        let names = spotify.top_tracks_iter(TimeRange::Short, Some(1))
+            .map(|t| t.unwrap().name)
+            .collect::<Vec<String>>();
+        assert_eq!(names, vec!["Skull", "Rebound"]);
+        let requests = transport.requests();
+        assert_eq!(requests[1].query, "limit=1&time_range=short_term");
+        assert_eq!(requests[2].url, next);
+    }
+
+    #[test]
+    fn test_scripted_player_state_episode() {
+        let (mut spotify, transport) = scripted_spotify();
+        transport.push_response(200, r#"{"timestamp": 1, "progress_ms": 1000, "is_playing": true,
+            "shuffle_state": false, "repeat_state": "off", "context": null,
+            "currently_playing_type": "episode",
+            "actions": {"disallows": {"skipping_prev": true, "toggling_shuffle": false}},
+            "device": {"id": "abc123", "is_active": true, "is_restricted": false, "name": "Kitchen",
+                "type": "Speaker", "volume_percent": 40},
+            "item": {"type": "episode", "id": "e", "name": "Episode 1", "uri": "spotify:episode:e",
+                "duration_ms": 60000, "description": "First",
+                "show": {"name": "Some Podcast", "uri": "spotify:show:s", "publisher": "Someone"}}}"#);
+        transport.push_response(200, r#"{"timestamp": 1, "progress_ms": null, "is_playing": true,
+            "shuffle_state": false, "repeat_state": "off", "context": null, "item": null,
+            "currently_playing_type": "ad",
+            "device": {"id": "abc123", "is_active": true, "is_restricted": false, "name": "Kitchen",
+                "type": "Speaker", "volume_percent": 40}}"#);
+        transport.push_response(200, r#"{"timestamp": 1, "progress_ms": 5000, "is_playing": true,
+            "shuffle_state": false, "repeat_state": "off", "context": null,
+            "currently_playing_type": "ad", "item": {"type": "ad", "name": "Buy stuff"},
+            "device": {"id": "abc123", "is_active": true, "is_restricted": false, "name": "Kitchen",
+                "type": "Speaker", "volume_percent": 40}}"#);
+        transport.push_response(204, "");
+
+        let state = spotify.request_player_state().unwrap().unwrap();
+        assert_eq!(state.currently_playing_type, "episode");
+        assert!(state.is_disallowed("skipping_prev"));
+        assert!(!state.is_disallowed("toggling_shuffle"));
+        assert!(!state.is_disallowed("pausing"));
+        let item = state.item.unwrap();
+        assert_eq!(item.name(), "Episode 1");
+        assert_eq!(item.artist_or_show(), "Some Podcast");
+        assert_eq!(item.album_or_publisher(), "Someone");
+        assert_eq!(item.duration_ms(), 60000);
+
+        let state = spotify.request_player_state().unwrap().unwrap();
+        assert!(state.item.is_none());
+        // Items of unknown types don't spoil the rest of the state.
+        let state = spotify.request_player_state().unwrap().unwrap();
+        assert!(state.item.is_none());
+        assert_eq!(state.progress_ms, Some(5000));
+        assert!(spotify.request_player_state().unwrap().is_none());
+        assert_eq!(transport.requests()[0].query, "additional_types=episode");
     }
 
+    #[test]
+    fn test_player_state_display() {
+        let state: PlayerState = serde_json::from_str(PLAYER_STATE).unwrap();
+        assert!(state.to_string().ends_with("1s / 60s (1.7%)\n"));
+        let state: PlayerState = serde_json::from_str(&PLAYER_STATE.replace("60000", "0")).unwrap();
+        assert!(state.to_string().ends_with("1s / 0s (0.0%)\n"));
+    }
+
+    #[test]
+    fn test_device_lookup() {
+        let list: ConnectDeviceList = serde_json::from_str(r#"{"devices": [
+            {"id": "a1", "is_active": false, "is_restricted": false, "name": "Kitchen",
+             "type": "Speaker", "volume_percent": 40},
+            {"id": "b2", "is_active": true, "is_restricted": false, "name": "Living Room TV",
+             "type": "TV", "volume_percent": null},
+            {"id": "c3", "is_active": false, "is_restricted": false, "name": "Living Room",
+             "type": "Speaker", "volume_percent": 10}]}"#).unwrap();
+        assert_eq!(list.find("b2").unwrap().name, "Living Room TV");
+        assert_eq!(list.find("kitchen").unwrap().id, "a1");
+        assert_eq!(list.find("KIT").unwrap().id, "a1");
+        // Exact names win over abbreviations.
+        assert_eq!(list.find("living room").unwrap().id, "c3");
+        assert_eq!(list.find("tv").unwrap().id, "b2");
+        // Ambiguous or unknown.
+        assert!(list.find("room").is_none());
+        assert!(list.find("Bathroom").is_none());
+        assert_eq!(list.active().unwrap().id, "b2");
+    }
+
+    #[test]
+    fn test_full_track_model() {
+        let json = r#"{"type": "track", "id": "t1", "name": "Skull", "uri": "spotify:track:t1",
+            "duration_ms": 173000, "explicit": true, "popularity": 42, "disc_number": 1,
+            "track_number": 3, "is_local": false, "preview_url": null,
+            "available_markets": ["US", "SE"],
+            "external_urls": {"spotify": "https://open.spotify.com/track/t1"},
+            "external_ids": {"isrc": "USSUB0000001"},
+            "artists": [{"id": "a1", "name": "Sebadoh", "uri": "spotify:artist:a1"}],
+            "album": {"id": "70XjdLKH7HHsFVWoQipP0T", "name": "Bakesale", "uri": "spotify:album:70XjdLKH7HHsFVWoQipP0T",
+                "album_type": "album", "release_date": "1994-08", "release_date_precision": "month",
+                "images": [{"url": "big", "height": 640, "width": 640},
+                           {"url": "medium", "height": 300, "width": 300},
+                           {"url": "small", "height": 64, "width": 64}]}}"#;
+        let item: PlaybackItem = serde_json::from_str(json).unwrap();
+        let track = match item {
+            PlaybackItem::Track(ref t) => t,
+            _ => panic!("not a track"),
+        };
+        assert!(track.explicit);
+        assert_eq!(track.track_number, Some(3));
+        assert_eq!(track.available_markets, vec!["US", "SE"]);
+        assert_eq!(track.external_ids.get("isrc").unwrap(), "USSUB0000001");
+        assert_eq!(track.album.release_date_precision, Some("month".to_string()));
+        assert!(track.album.genres.is_empty());
+        assert_eq!(item.image(100).unwrap().url, "medium");
+        assert_eq!(item.image(1000).unwrap().url, "big");
+
+        // Serializes back to the same shape Spotify sends.
+        let reparsed: PlaybackItem = serde_json::from_str(&serde_json::to_string(&item).unwrap()).unwrap();
+        assert_eq!(reparsed.uri(), "spotify:track:t1");
+        assert_eq!(reparsed.image(64).unwrap().url, "small");
+    }
+
+    #[test]
+    fn test_scripted_fetch_art() {
+        let dir = std::env::temp_dir().join(format!("connectr-art-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut cache = ArtCache::new(&dir, 10);
+        let (spotify, transport) = scripted_spotify();
+        transport.push_bytes(200, "image/jpeg", &[0xff, 0xd8, 0xff, 0x00, 0x01, 0x02]);
+        transport.push_bytes(200, "image/png", &[0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a]);
+
+        let item: PlaybackItem = serde_json::from_str(r#"{"type": "track", "name": "Skull",
+            "uri": "spotify:track:t1", "duration_ms": 1, "artists": [],
+            "album": {"name": "Bakesale", "uri": "spotify:album:a",
+                "images": [{"url": "https://i.scdn.co/image/big", "height": 640, "width": 640},
+                           {"url": "https://i.scdn.co/image/small", "height": 64, "width": 64}]}}"#).unwrap();
+        let path = spotify.fetch_art(&mut cache, &item, 64).unwrap().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 6);
+        assert!(path.to_str().unwrap().ends_with(".jpg"));
+        // Cached, so no second request.
+        assert_eq!(spotify.fetch_art(&mut cache, &item, 64).unwrap().unwrap(), path);
+        assert_eq!(transport.requests().len(), 1);
+        assert!(transport.requests()[0].authorization.is_none());
+        assert_eq!(transport.requests()[0].url, "https://i.scdn.co/image/small");
+
+        // Over the 10 byte limit, so the older image is evicted.
+        let big = spotify.fetch_art(&mut cache, &item, 600).unwrap().unwrap();
+        assert!(big.to_str().unwrap().ends_with(".png"));
+        assert!(!path.exists());
+        assert_eq!(cache.len(), 1);
+
+        // The index survives a restart.
+        drop(cache);
+        let index = || std::fs::read_to_string(dir.join("index.json")).unwrap();
+        let saved = index();
+        let mut cache = ArtCache::new(&dir, 10);
+        assert_eq!(cache.get("https://i.scdn.co/image/big"), Some(big));
+        assert_eq!(cache.get("https://i.scdn.co/image/small"), None);
+        // Hits aren't written out until the cache is dropped.
+        assert_eq!(index(), saved);
+        drop(cache);
+        assert!(index() != saved);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_spotify_uri() {
+        let track = "spotify:track:6rqhFgbbKwnb9MLmUQDhG6";
+        assert_eq!(uri(track).kind(), UriKind::Track);
+        assert_eq!(uri(track).id(), "6rqhFgbbKwnb9MLmUQDhG6");
+        assert_eq!(uri("https://open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6?si=abc").to_string(), track);
+        assert_eq!(uri("open.spotify.com/intl-de/track/6rqhFgbbKwnb9MLmUQDhG6").to_string(), track);
+        assert_eq!(uri(track).url(), "https://open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6");
+
+        let playlist = uri("spotify:user:spotify:playlist:37i9dQZEVXcOmDhsenkuCu");
+        assert_eq!(playlist.to_string(), "spotify:playlist:37i9dQZEVXcOmDhsenkuCu");
+        assert!(playlist.is_context());
+        assert!(!uri(track).is_context());
+        assert!(uri(track).is_playable());
+        assert!(!uri("spotify:user:sebadoh").is_playable());
+        assert_eq!(uri("spotify:user:mrmekon").kind(), UriKind::User);
+
+        assert_eq!(SpotifyUri::parse_as(UriKind::Album, "70XjdLKH7HHsFVWoQipP0T").unwrap().to_string(),
+                   "spotify:album:70XjdLKH7HHsFVWoQipP0T");
+        assert!(SpotifyUri::parse_as(UriKind::Album, track).is_err());
+        assert!("spotify:track:6rqhFgbbKwnb9MLmUQDhG".parse::<SpotifyUri>().is_err());
+        assert!("spotify:trak:6rqhFgbbKwnb9MLmUQDhG6".parse::<SpotifyUri>().is_err());
+        assert!("https://example.com/track/6rqhFgbbKwnb9MLmUQDhG6".parse::<SpotifyUri>().is_err());
+        assert!("6rqhFgbbKwnb9MLmUQDhG6".parse::<SpotifyUri>().is_err());
+    }
+
+    fn saved_page(names: &[&str], offset: u32, next: Option<&str>) -> String {
+        let items = names.iter().map(|name| format!(r#"{{"added_at": "2017-05-01T09:24:03Z",
+            "track": {{"id": "{0}", "name": "{0}", "uri": "spotify:track:{0}", "duration_ms": 1,
+            "artists": [], "album": {{"name": "Bakesale", "uri": "spotify:album:b"}}}}}}"#, name))
+            .collect::<Vec<String>>().join(",");
+        let next = next.map(|n| format!("\"{}\"", n)).unwrap_or("null".to_string());
+        format!(r#"{{"href": "x", "limit": 2, "next": {}, "offset": {}, "previous": null,
+            "total": 3, "items": [{}]}}"#, next, offset, items)
+    }
+
+    #[test]
+    fn test_scripted_page_iter() {
+        let (mut spotify, transport) = scripted_spotify();
+        let next = format!("{}?offset=2&limit=2", TEST_API.saved_tracks);
+        transport.push_response(200, &saved_page(&["a", "b"], 0, Some(&next)));
+        transport.push_response(200, &saved_page(&["c"], 2, None));
+        let names = spotify.saved_tracks_iter(Some(2))
+            .map(|t| t.unwrap().track.name)
+            .collect::<Vec<String>>();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].url, TEST_API.saved_tracks);
+        assert_eq!(requests[0].query, "limit=2");
+        assert_eq!(requests[1].url, next);
+        assert_eq!(requests[1].query, "");
+
+        // Pages past what's taken are never fetched.
+        transport.push_response(200, &saved_page(&["a", "b"], 0, Some(&next)));
+        assert_eq!(spotify.saved_tracks_iter(Some(2)).take(2).count(), 2);
+        assert_eq!(transport.requests().len(), 3);
+
+        // Errors end the iteration.
+        transport.push_response(200, &saved_page(&["a"], 0, Some(&next)));
+        transport.push_response(404, "");
+        let results = spotify.saved_tracks_iter(Some(2)).collect::<Vec<_>>();
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+    }
 }
@@ -0,0 +1,161 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::super::error::ConnectrError;
+
+/// What a Spotify URI refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum UriKind {
+    Track,
+    Album,
+    Artist,
+    Playlist,
+    Show,
+    Episode,
+    User,
+}
+
+impl UriKind {
+    fn from_name(name: &str) -> Option<UriKind> {
+        match name {
+            "track" => Some(UriKind::Track),
+            "album" => Some(UriKind::Album),
+            "artist" => Some(UriKind::Artist),
+            "playlist" => Some(UriKind::Playlist),
+            "show" => Some(UriKind::Show),
+            "episode" => Some(UriKind::Episode),
+            "user" => Some(UriKind::User),
+            _ => None,
+        }
+    }
+    /// Name as used in URIs and links, e.g. `track`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            UriKind::Track => "track",
+            UriKind::Album => "album",
+            UriKind::Artist => "artist",
+            UriKind::Playlist => "playlist",
+            UriKind::Show => "show",
+            UriKind::Episode => "episode",
+            UriKind::User => "user",
+        }
+    }
+}
+
+impl fmt::Display for UriKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A validated Spotify URI.
+///
+/// Parses `spotify:track:<id>`, the older `spotify:user:<user>:playlist:<id>`
+/// and `https://open.spotify.com/track/<id>` links.  Bare IDs are accepted
+/// by `parse_as()`, which needs to know what kind of item they refer to.
+///
+/// Displays as the canonical `spotify:<kind>:<id>` form.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SpotifyUri {
+    kind: UriKind,
+    id: String,
+}
+
+fn invalid(input: &str, reason: &str) -> ConnectrError {
+    ConnectrError::InvalidUri(format!("'{}': {}", input, reason))
+}
+
+/// Catalog IDs are 22 base-62 characters.  User IDs are just usernames.
+fn valid_id(kind: UriKind, id: &str) -> bool {
+    match kind {
+        UriKind::User => id.len() > 0 && !id.contains(|c: char| {
+            c.is_whitespace() || c == ':' || c == '/' || c == '?' || c == '#'
+        }),
+        _ => id.len() == 22 && id.chars().all(|c| c.is_ascii() && c.is_alphanumeric()),
+    }
+}
+
+impl SpotifyUri {
+    pub fn new(kind: UriKind, id: &str) -> Result<SpotifyUri, ConnectrError> {
+        match valid_id(kind, id) {
+            true => Ok(SpotifyUri { kind: kind, id: id.to_string() }),
+            false => Err(invalid(id, &format!("not a valid {} ID", kind))),
+        }
+    }
+    /// Parse a URI, link or bare ID, which must refer to a `kind`.
+    pub fn parse_as(kind: UriKind, input: &str) -> Result<SpotifyUri, ConnectrError> {
+        let input = input.trim();
+        if !input.contains(':') && !input.contains('/') {
+            return SpotifyUri::new(kind, input);
+        }
+        let uri: SpotifyUri = input.parse()?;
+        match uri.kind == kind {
+            true => Ok(uri),
+            false => Err(invalid(input, &format!("expected a {}, not a {}", kind, uri.kind))),
+        }
+    }
+    pub fn kind(&self) -> UriKind {
+        self.kind
+    }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    /// Link to the item on open.spotify.com.
+    pub fn url(&self) -> String {
+        format!("https://open.spotify.com/{}/{}", self.kind, self.id)
+    }
+    /// Whether this can be played as a context (`PlayContext::context_uri`),
+    /// rather than as a list of tracks.
+    pub fn is_context(&self) -> bool {
+        match self.kind {
+            UriKind::Album | UriKind::Artist | UriKind::Playlist | UriKind::Show => true,
+            _ => false,
+        }
+    }
+    /// Whether this can be played at all.  Users' profiles can't.
+    pub fn is_playable(&self) -> bool {
+        self.kind != UriKind::User
+    }
+    /// Interpret the parts of a URI or the path of a link, without the
+    /// `spotify:` prefix or host.
+    fn from_parts(input: &str, parts: &[&str]) -> Result<SpotifyUri, ConnectrError> {
+        let (kind, id) = match parts.len() {
+            2 => (parts[0], parts[1]),
+            4 if parts[0] == "user" && parts[2] == "playlist" => (parts[2], parts[3]),
+            _ => return Err(invalid(input, "not a track, album, artist, playlist, show, episode or user")),
+        };
+        match UriKind::from_name(kind) {
+            Some(kind) => SpotifyUri::new(kind, id).map_err(|_| invalid(input, &format!("not a valid {} ID", kind))),
+            None => Err(invalid(input, &format!("unknown kind '{}'", kind))),
+        }
+    }
+}
+
+impl FromStr for SpotifyUri {
+    type Err = ConnectrError;
+    fn from_str(input: &str) -> Result<SpotifyUri, ConnectrError> {
+        let input = input.trim();
+        if input.starts_with("spotify:") {
+            let parts: Vec<&str> = input["spotify:".len()..].split(':').collect();
+            return SpotifyUri::from_parts(input, &parts);
+        }
+        let link = input.trim_left_matches("https://").trim_left_matches("http://");
+        let path = match link.find('/') {
+            Some(idx) if &link[..idx] == "open.spotify.com" || &link[..idx] == "play.spotify.com" => &link[idx..],
+            _ => return Err(invalid(input, "not a spotify: URI or open.spotify.com link")),
+        };
+        let path = path.split(|c| c == '?' || c == '#').next().unwrap_or("");
+        let mut parts: Vec<&str> = path.split('/').filter(|p| p.len() > 0).collect();
+        // Localized links look like open.spotify.com/intl-de/track/<id>
+        if parts.first().map(|p| p.starts_with("intl-") || *p == "embed").unwrap_or(false) {
+            parts.remove(0);
+        }
+        SpotifyUri::from_parts(input, &parts)
+    }
+}
+
+impl fmt::Display for SpotifyUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "spotify:{}:{}", self.kind, self.id)
+    }
+}
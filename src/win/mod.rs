@@ -17,6 +17,8 @@ pub struct WindowsStatusBar {
     idx: Cell<u32>,
     tx: Sender<String>,
     items: BTreeMap<u64, u32>,
+    // systray has no submenus, so they're drawn as an indented section.
+    in_submenu: bool,
 }
 
 impl TStatusBar for WindowsStatusBar {
@@ -27,6 +29,7 @@ impl TStatusBar for WindowsStatusBar {
             idx: Cell::new(0),
             tx: tx,
             items: BTreeMap::<u64, u32>::new(),
+            in_submenu: false,
         };
         {
             let ref mut win = &mut bar.app.window;
@@ -42,6 +45,7 @@ impl TStatusBar for WindowsStatusBar {
         let ref mut win = &mut self.app.window;
         let _ = win.clear_menu();
         self.items.clear();
+        self.in_submenu = false;
     }
     fn set_tooltip(&mut self, text: &str) {
         let ref mut win = &mut self.app.window;
@@ -53,8 +57,9 @@ impl TStatusBar for WindowsStatusBar {
         let _ = win.set_tooltip(&tooltip);
     }
     fn add_label(&mut self, label: &str) {
+        let label = self.indent(label);
         let ref mut win = &mut self.app.window;
-        let idx = win.add_menu_item(&label.to_string(), false, |_| {});
+        let idx = win.add_menu_item(&label, false, |_| {});
         let _ = win.enable_menu_item(idx.unwrap(), MenuEnableFlag::Disabled);
     }
     fn add_quit(&mut self, label: &str) {
@@ -68,16 +73,25 @@ impl TStatusBar for WindowsStatusBar {
         let _ = win.add_menu_separator();
     }
     fn add_item(&mut self, item: &str, callback: NSCallback, selected: bool) -> *mut Object {
+        let item = self.indent(item);
         let ref mut win = &mut self.app.window;
         let idx = self.idx.get();
         self.idx.set(idx+1);
         let tx = self.tx.clone();
-        let item = win.add_menu_item(&item.to_string(), selected, move |_| {
+        let item = win.add_menu_item(&item, selected, move |_| {
             callback(idx as u64, &tx);
         }).unwrap();
         self.items.insert(idx as u64, item);
         idx as *mut Object
     }
+    fn add_submenu(&mut self, label: &str) {
+        self.in_submenu = false;
+        self.add_label(label);
+        self.in_submenu = true;
+    }
+    fn end_submenu(&mut self) {
+        self.in_submenu = false;
+    }
     fn update_item(&mut self, _item: *mut Object, _label: &str) {
     }
     fn sel_item(&mut self, sender: u64) {
@@ -95,3 +109,12 @@ impl TStatusBar for WindowsStatusBar {
         win.wait_for_message(block);
     }
 }
+
+impl WindowsStatusBar {
+    fn indent(&self, label: &str) -> String {
+        match self.in_submenu {
+            true => format!("    {}", label),
+            false => label.to_string(),
+        }
+    }
+}